@@ -1,25 +1,29 @@
 use std::fs::File;
 use std::io::{Read, Seek};
 
-use crate::disc::{self, IsoKind};
-use crate::{JukeboxError::*, Result};
+use crate::{DiscError::*, Result};
+use crate::{IsoKind, get_iso_kind};
 
 const CISO_HEADER_SIZE: usize = 0x8000;
 const CISO_BLOCK_MAP_SIZE: usize = CISO_HEADER_SIZE - 0x8;
 
 // (Block Size, Block Map)
-type CisoHeader = (u32, [u8; CISO_BLOCK_MAP_SIZE]);
+pub(crate) type CisoHeader = (u32, [u8; CISO_BLOCK_MAP_SIZE]);
 
 /// Get the header of a ciso disc image. If the provided file is not a ciso,
-/// `None` will be returned
+/// `None` will be returned. Block sizes that aren't a power of two (including
+/// zero) are `UnsupportedIso`, as every offset is divided by them.
 pub(crate) fn get_ciso_header(iso: &mut File) -> Result<Option<CisoHeader>> {
-    match disc::get_iso_kind(iso)? {
+    match get_iso_kind(iso)? {
         IsoKind::Ciso => {
             // Get the block size
             let mut block_size = [0; 0x4];
             iso.seek(std::io::SeekFrom::Start(0x4)).map_err(IsoSeek)?;
             iso.read_exact(&mut block_size).map_err(IsoRead)?;
             let block_size = u32::from_le_bytes(block_size);
+            if !block_size.is_power_of_two() {
+                return Err(UnsupportedIso);
+            }
 
             // Get the block map
             let mut block_map = [0; CISO_BLOCK_MAP_SIZE];
@@ -32,6 +36,26 @@ pub(crate) fn get_ciso_header(iso: &mut File) -> Result<Option<CisoHeader>> {
     }
 }
 
+/// Get the size of the blocks in a ciso image
+pub(crate) fn get_ciso_block_size(header: &CisoHeader) -> u32 {
+    header.0
+}
+
+/// Get the size of the disc stored in a ciso image. The format doesn't record
+/// it, so this is where the last stored block ends, unless that block is also
+/// the last block of a disc of `standard_size` (in which case the disc is
+/// assumed to be exactly that size).
+pub(crate) fn get_ciso_disc_size(header: &CisoHeader, standard_size: u64) -> u64 {
+    let (block_size, block_map) = header;
+    let block_size = *block_size as u64;
+    let block_count = block_map.iter().rposition(|&b| b != 0).map_or(0, |pos| pos + 1) as u64;
+
+    match block_count == standard_size.div_ceil(block_size) {
+        true => standard_size,
+        false => block_count * block_size,
+    }
+}
+
 // Given an offset for an standard disc image, return the offset for a ciso
 // image
 pub(crate) fn get_ciso_offset(header: &CisoHeader, offset: u64) -> Option<u64> {
@@ -64,6 +88,21 @@ mod tests {
         assert!(header.is_none());
     }
 
+    #[test]
+    fn rejects_block_sizes_that_arent_a_power_of_two() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut ciso = std::fs::read("test-data/tiny-disc.ciso").unwrap();
+
+        for block_size in [0u32, 0x3000] {
+            ciso[0x4..0x8].copy_from_slice(&block_size.to_le_bytes());
+            let path = folder.path().join("edited.ciso");
+            std::fs::write(&path, &ciso).unwrap();
+
+            let mut file = File::open(path).unwrap();
+            assert!(matches!(get_ciso_header(&mut file), Err(UnsupportedIso)), "{block_size:#x}");
+        }
+    }
+
     #[test]
     fn reads_ciso_header_block_size_correctly() {
         let mut file = File::open("test-data/ciso-header-1.bin").unwrap();
@@ -71,6 +110,17 @@ mod tests {
         assert_eq!(block_size, 0x200000);
     }
 
+    #[test]
+    fn computes_disc_size_from_the_block_map() {
+        let mut file = File::open("test-data/ciso-header-1.bin").unwrap();
+        let header = get_ciso_header(&mut file).unwrap().unwrap();
+        assert_eq!(get_ciso_disc_size(&header, 0x57058000), 0x57058000);
+
+        let mut file = File::open("test-data/tiny-disc.ciso").unwrap();
+        let header = get_ciso_header(&mut file).unwrap().unwrap();
+        assert_eq!(get_ciso_disc_size(&header, 0x57058000), 0x20000);
+    }
+
     #[test]
     fn converts_offsets_to_ciso_offsets_correctly() {
         let mut file = File::open("test-data/ciso-header-1.bin").unwrap();
@@ -0,0 +1,79 @@
+use std::io::{Read, Seek};
+
+use crate::{DiscFile, Result, check_bounds, copy_bytes};
+
+/// Location of the main executable's offset in the disc header
+const DOL_OFFSET_LOCATION: u64 = 0x420;
+
+/// Size of a DOL header, which lists where each section of code and data is
+const DOL_HEADER_SIZE: usize = 0x100;
+
+/// A DOL has 7 text (code) sections followed by 11 data sections
+const DOL_SECTION_COUNT: usize = 18;
+
+/// Where the file offsets of each section are in the DOL header
+const DOL_SECTION_OFFSETS: usize = 0x0;
+
+/// Where the sizes of each section are in the DOL header
+const DOL_SECTION_SIZES: usize = 0x90;
+
+/// Finds the game's main executable, `Start.dol`. It isn't listed in the FST,
+/// so its offset comes from the disc header and its length from the sections
+/// listed in its own header. Fails if any of it would be past the end of the
+/// disc.
+pub fn find_main_dol<R: Read + Seek>(disc: &mut R) -> Result<DiscFile> {
+    let location = copy_bytes(disc, DOL_OFFSET_LOCATION, 4)?;
+    let offset = u32::from_be_bytes(location.try_into().unwrap()) as u64;
+
+    let header = copy_bytes(disc, offset, DOL_HEADER_SIZE)?;
+    let word = |at: usize| u32::from_be_bytes(header[at..at + 4].try_into().unwrap()) as u64;
+
+    // The DOL ends wherever its last section does
+    let length = (0..DOL_SECTION_COUNT)
+        .map(|section| (word(DOL_SECTION_OFFSETS + section * 4), word(DOL_SECTION_SIZES + section * 4)))
+        .filter(|&(_, size)| size > 0)
+        .map(|(section_offset, size)| section_offset + size)
+        .fold(DOL_HEADER_SIZE as u64, u64::max);
+    let length = usize::try_from(length).unwrap_or(usize::MAX);
+
+    check_bounds(disc, offset, length)?;
+    Ok(DiscFile { offset, length })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DiscReader;
+
+    #[test]
+    fn finds_the_main_dol() {
+        for image in [
+            "test-data/tiny-disc.iso",
+            "test-data/tiny-disc.ciso",
+            "test-data/tiny-disc.rvz",
+        ] {
+            let dol = find_main_dol(&mut DiscReader::open(image).unwrap()).unwrap();
+            assert_eq!(
+                dol,
+                DiscFile {
+                    offset: 0x4000,
+                    length: 0x1000
+                },
+                "{image}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_dols_that_run_past_the_end_of_the_disc() {
+        let mut disc = std::fs::read("test-data/tiny-disc.iso").unwrap();
+
+        // Give the first data section a size that's far larger than the disc
+        let size_location = 0x4000 + DOL_SECTION_SIZES + 7 * 4;
+        disc[size_location..size_location + 4].copy_from_slice(&0xFFFF_0000u32.to_be_bytes());
+        assert!(matches!(
+            find_main_dol(&mut std::io::Cursor::new(disc)),
+            Err(crate::DiscError::OutOfBounds(0x4000, _))
+        ));
+    }
+}
@@ -0,0 +1,28 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DiscError {
+    #[error("{0}")]
+    GenericIO(#[from] std::io::Error),
+
+    #[error("Failed to seek the ISO: {0}")]
+    IsoSeek(std::io::Error),
+
+    #[error("Failed to read the ISO: {0}")]
+    IsoRead(std::io::Error),
+
+    #[error("Failed to decompress block {0} of the ISO: {1}")]
+    IsoDecompress(u64, std::io::Error),
+
+    #[error("Block {0} of the ISO is corrupt")]
+    IsoBlockChecksum(u64),
+
+    #[error("0x{1:x} bytes at 0x{0:x} extend past the end of the ISO")]
+    OutOfBounds(u64, usize),
+
+    #[error("The ISO's file system table is malformed")]
+    MalformedFst,
+
+    #[error("The provided game file is not supported")]
+    UnsupportedIso,
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+
+use crate::{DiscError::*, Result, copy_bytes};
+
+/// Location of the FST's offset and size in the disc header
+const FST_OFFSET_LOCATION: u64 = 0x424;
+
+/// Size of a single entry in the FST
+const FST_ENTRY_SIZE: usize = 0xC;
+
+/// Where a file is stored on the disc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscFile {
+    pub offset: u64,
+    pub length: usize,
+}
+
+/// The file system table of a GameCube disc, which maps the path of every file
+/// on the disc (e.g. `audio/izumi.hps`) to where it is stored.
+#[derive(Debug, Default)]
+pub struct FileSystemTable {
+    files: HashMap<String, DiscFile>,
+}
+
+impl FileSystemTable {
+    /// Reads the FST of the provided disc
+    pub fn read<R: Read + Seek>(disc: &mut R) -> Result<Self> {
+        // The FST offset (u32) is immediately followed by its size (u32)
+        let mut location = [0; 8];
+        disc.seek(std::io::SeekFrom::Start(FST_OFFSET_LOCATION)).map_err(IsoSeek)?;
+        disc.read_exact(&mut location).map_err(IsoRead)?;
+        let fst_offset = u32::from_be_bytes(location[..4].try_into().unwrap()) as u64;
+        let fst_size = u32::from_be_bytes(location[4..].try_into().unwrap()) as usize;
+
+        let fst = copy_bytes(disc, fst_offset, fst_size)?;
+
+        Self::parse(&fst)
+    }
+
+    /// Parses a raw FST. Entries are either files or directories, and the
+    /// first entry is always the root directory. Each entry is laid out as:
+    ///
+    /// - Flags (u8): 0 for files and 1 for directories
+    /// - Name offset (u24): Offset of the entry's name in the string table
+    /// - File offset or parent directory index (u32)
+    /// - File length or index of the first entry after the directory (u32)
+    ///
+    /// The string table of null terminated names comes right after the last
+    /// entry.
+    fn parse(fst: &[u8]) -> Result<Self> {
+        let entry = |index: usize| -> Result<(bool, usize, u32, u32)> {
+            let bytes = fst
+                .get(index * FST_ENTRY_SIZE..(index + 1) * FST_ENTRY_SIZE)
+                .ok_or(MalformedFst)?;
+            let name_offset = u32::from_be_bytes([0, bytes[1], bytes[2], bytes[3]]) as usize;
+            let a = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+            let b = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+            Ok((bytes[0] != 0, name_offset, a, b))
+        };
+
+        // The root's "next entry index" is the total number of entries
+        let (_, _, _, entry_count) = entry(0)?;
+        let entry_count = entry_count as usize;
+        let strings = fst.get(entry_count * FST_ENTRY_SIZE..).ok_or(MalformedFst)?;
+
+        let name = |offset: usize| -> Result<String> {
+            let bytes = strings.get(offset..).ok_or(MalformedFst)?;
+            let end = bytes.iter().position(|&b| b == 0).ok_or(MalformedFst)?;
+            Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+        };
+
+        // Directories we are inside of, as (index of the first entry after the
+        // directory, path prefix of its contents)
+        let mut directories: Vec<(usize, String)> = vec![(entry_count, String::new())];
+        let mut files = HashMap::new();
+
+        for index in 1..entry_count {
+            while directories.last().is_some_and(|(end, _)| index >= *end) {
+                directories.pop();
+            }
+            let prefix = directories.last().map(|(_, prefix)| prefix.as_str()).unwrap_or_default();
+
+            let (is_directory, name_offset, a, b) = entry(index)?;
+            let path = format!("{prefix}{}", name(name_offset)?);
+
+            match is_directory {
+                true => directories.push((b as usize, format!("{path}/"))),
+                false => {
+                    files.insert(
+                        path,
+                        DiscFile {
+                            offset: a as u64,
+                            length: b as usize,
+                        },
+                    );
+                },
+            }
+        }
+
+        Ok(Self { files })
+    }
+
+    /// Finds a file by its full path on the disc (`audio/izumi.hps`), or by
+    /// just its file name (`izumi.hps`). Names are not case sensitive.
+    ///
+    /// A full path always wins over a file name. If a file name is shared by
+    /// files in more than one directory, it's ambiguous and nothing is found.
+    pub fn get(&self, name: &str) -> Option<DiscFile> {
+        let by_path = self.files.iter().find(|(path, _)| path.eq_ignore_ascii_case(name));
+        if let Some((_, file)) = by_path {
+            return Some(*file);
+        }
+
+        let mut by_name = self.files.iter().filter(|(path, _)| {
+            path.rsplit('/')
+                .next()
+                .is_some_and(|file_name| file_name.eq_ignore_ascii_case(name))
+        });
+
+        match (by_name.next(), by_name.next()) {
+            (Some((_, file)), None) => Some(*file),
+            _ => None,
+        }
+    }
+
+    /// Every file on the disc, by path, in no particular order
+    pub fn files(&self) -> impl Iterator<Item = (&str, DiscFile)> {
+        self.files.iter().map(|(path, file)| (path.as_str(), *file))
+    }
+
+    /// Finds the path of the file stored at `offset` on the disc
+    pub fn path_at(&self, offset: u64) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|(_, file)| file.offset == offset)
+            .map(|(path, _)| path.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DiscReader;
+
+    #[test]
+    fn reads_files_in_the_root_and_in_directories() {
+        let mut disc = DiscReader::open("test-data/tiny-disc.iso").unwrap();
+        let fst = FileSystemTable::read(&mut disc).unwrap();
+        assert_eq!(fst.files.len(), 6);
+
+        let expected = [
+            ("MnSlChr.usd", 0x18000, 0x600),
+            ("PlCo.dat", 0x17000, 0x900),
+            ("opening.bnr", 0x19000, 0x1960),
+            ("audio/menu01.hps", 0x13F00, 0x500),
+            ("audio/izumi.hps", 0x15000, 0x320),
+            ("audio/broken.hps", 0x16000, 0x100),
+        ];
+        for (path, offset, length) in expected {
+            assert_eq!(fst.get(path), Some(DiscFile { offset, length }), "{path}");
+        }
+    }
+
+    #[test]
+    fn finds_files_by_name() {
+        let mut disc = DiscReader::open("test-data/tiny-disc.rvz").unwrap();
+        let fst = FileSystemTable::read(&mut disc).unwrap();
+
+        assert_eq!(fst.get("izumi.hps").map(|file| file.offset), Some(0x15000));
+        assert_eq!(fst.get("MENU01.HPS").map(|file| file.offset), Some(0x13F00));
+        assert_eq!(fst.get("audio"), None);
+        assert_eq!(fst.get("mutecity.hps"), None);
+
+        assert_eq!(fst.path_at(0x15000), Some("audio/izumi.hps"));
+        assert_eq!(fst.path_at(0x15001), None);
+    }
+
+    #[test]
+    fn prefers_full_paths_and_ignores_ambiguous_names() {
+        // (is directory, name, file offset or parent, file length or next entry)
+        let entries = [
+            (true, "", 0, 8),
+            (true, "a", 0, 4),
+            (false, "song.hps", 0x100, 0x10),
+            (false, "intro.hps", 0x110, 0x10),
+            (true, "b", 0, 7),
+            (false, "song.hps", 0x200, 0x10),
+            (false, "intro.hps", 0x210, 0x10),
+            (false, "song.hps", 0x300, 0x10),
+        ];
+
+        let mut fst = Vec::new();
+        let mut strings = Vec::new();
+        for (is_directory, name, a, b) in entries {
+            fst.push(is_directory as u8);
+            fst.extend_from_slice(&(strings.len() as u32).to_be_bytes()[1..]);
+            fst.extend_from_slice(&(a as u32).to_be_bytes());
+            fst.extend_from_slice(&(b as u32).to_be_bytes());
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+        fst.extend(strings);
+
+        let fst = FileSystemTable::parse(&fst).unwrap();
+        assert_eq!(fst.get("song.hps").map(|file| file.offset), Some(0x300));
+        assert_eq!(fst.get("a/song.hps").map(|file| file.offset), Some(0x100));
+        assert_eq!(fst.get("B/INTRO.HPS").map(|file| file.offset), Some(0x210));
+        assert_eq!(fst.get("intro.hps"), None);
+    }
+
+    #[test]
+    fn rejects_truncated_tables() {
+        let mut disc = DiscReader::open("test-data/tiny-disc.iso").unwrap();
+        let mut fst = vec![0; 0x60];
+        disc.seek(std::io::SeekFrom::Start(0x3000)).unwrap();
+        disc.read_exact(&mut fst).unwrap();
+
+        assert!(matches!(FileSystemTable::parse(&fst[..0x20]), Err(MalformedFst)));
+        assert!(matches!(FileSystemTable::parse(&fst), Err(MalformedFst)));
+    }
+}
@@ -0,0 +1,266 @@
+use std::fs::File;
+use std::io::{Read, Seek};
+
+use flate2::read::ZlibDecoder;
+
+use crate::{DiscError::*, Result};
+use crate::{IsoKind, get_iso_kind};
+
+const GCZ_HEADER_SIZE: u64 = 0x20;
+
+/// The top bit of a block pointer is set when that block is stored as-is
+/// instead of being zlib compressed.
+const GCZ_UNCOMPRESSED_FLAG: u64 = 1 << 63;
+
+/// Largest prime smaller than 2^16, used by the adler32 checksum.
+const ADLER_MOD: u32 = 65521;
+
+/// Everything we need from a gcz header in order to locate, verify and inflate
+/// the blocks of the disc it contains.
+#[derive(Debug)]
+pub(crate) struct GczHeader {
+    compressed_data_size: u64,
+    data_size: u64,
+    block_size: u32,
+    block_pointers: Vec<u64>,
+    block_hashes: Vec<u32>,
+}
+
+impl GczHeader {
+    /// Size of the uncompressed disc stored in the gcz
+    pub(crate) fn data_size(&self) -> u64 {
+        self.data_size
+    }
+
+    /// Offset in the gcz file where the (compressed) block data begins. This
+    /// comes right after the header, the block pointers and the block hashes.
+    fn data_offset(&self) -> u64 {
+        GCZ_HEADER_SIZE + self.block_pointers.len() as u64 * 12
+    }
+}
+
+/// Get the header of a gcz disc image. If the provided file is not a gcz,
+/// `None` will be returned. Headers whose block size is zero, or whose tables
+/// and block data don't fit in the file, are `UnsupportedIso`.
+pub(crate) fn get_gcz_header(iso: &mut File) -> Result<Option<GczHeader>> {
+    match get_iso_kind(iso)? {
+        IsoKind::Gcz => {
+            // Magic word (u32), sub type (u32), compressed data size (u64),
+            // data size (u64), block size (u32) and block count (u32)
+            let mut header = [0; GCZ_HEADER_SIZE as usize];
+            iso.rewind().map_err(IsoSeek)?;
+            iso.read_exact(&mut header).map_err(IsoRead)?;
+
+            let compressed_data_size = u64::from_le_bytes(header[0x8..0x10].try_into().unwrap());
+            let data_size = u64::from_le_bytes(header[0x10..0x18].try_into().unwrap());
+            let block_size = u32::from_le_bytes(header[0x18..0x1c].try_into().unwrap());
+            let block_count = u32::from_le_bytes(header[0x1c..0x20].try_into().unwrap()) as usize;
+
+            // Check the sizes against the file before allocating anything for
+            // them, as a corrupt header can have any values here
+            let file_size = iso.metadata()?.len();
+            let tables_size = block_count.checked_mul(12).ok_or(UnsupportedIso)?;
+            let fits = GCZ_HEADER_SIZE
+                .checked_add(tables_size as u64)
+                .and_then(|data_offset| data_offset.checked_add(compressed_data_size))
+                .is_some_and(|end| end <= file_size);
+            if block_size == 0 || !fits {
+                return Err(UnsupportedIso);
+            }
+
+            // The block pointer table is immediately followed by the table of
+            // adler32 hashes for each (stored) block
+            let mut tables = vec![0; tables_size];
+            iso.read_exact(&mut tables).map_err(IsoRead)?;
+            let (pointer_bytes, hash_bytes) = tables.split_at(block_count * 8);
+
+            let block_pointers = pointer_bytes
+                .chunks_exact(8)
+                .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                .collect();
+            let block_hashes = hash_bytes
+                .chunks_exact(4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect();
+
+            Ok(Some(GczHeader {
+                compressed_data_size,
+                data_size,
+                block_size,
+                block_pointers,
+                block_hashes,
+            }))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Read the block containing the disc `offset`. Returns the offset on the disc
+/// where the block starts along with its (inflated) contents.
+pub(crate) fn read_block_at(iso: &mut File, header: &GczHeader, offset: u64) -> Result<(u64, Vec<u8>)> {
+    let block_size = header.block_size as u64;
+    let index = offset / block_size;
+    Ok((index * block_size, read_block(iso, header, index)?))
+}
+
+/// Read, verify and (if needed) inflate the block at `index`. Every block but
+/// the last must be exactly `block_size` long once inflated, and the last one
+/// can't be any longer.
+fn read_block(iso: &mut File, header: &GczHeader, index: u64) -> Result<Vec<u8>> {
+    let i = index as usize;
+    let Some(&pointer) = header.block_pointers.get(i) else {
+        return Err(IsoRead(std::io::ErrorKind::UnexpectedEof.into()));
+    };
+
+    // The stored size of a block is the distance to the next block, or to the
+    // end of the compressed data for the last one
+    let start = pointer & !GCZ_UNCOMPRESSED_FLAG;
+    let end = match header.block_pointers.get(i + 1) {
+        Some(next) => next & !GCZ_UNCOMPRESSED_FLAG,
+        None => header.compressed_data_size,
+    };
+    let stored_size = match end.checked_sub(start) {
+        Some(size) if end <= header.compressed_data_size => size,
+        _ => return Err(IsoBlockChecksum(index)),
+    };
+
+    let mut stored = vec![0; stored_size as usize];
+    iso.seek(std::io::SeekFrom::Start(header.data_offset() + start))
+        .map_err(IsoSeek)?;
+    iso.read_exact(&mut stored).map_err(IsoRead)?;
+
+    if adler32(&stored) != header.block_hashes[i] {
+        return Err(IsoBlockChecksum(index));
+    }
+
+    let block_size = header.block_size as usize;
+    let block = match pointer & GCZ_UNCOMPRESSED_FLAG != 0 {
+        true => stored,
+        false => {
+            // Inflating one byte more than a block is enough to tell that the
+            // block is too long
+            let mut block = Vec::with_capacity(block_size);
+            ZlibDecoder::new(stored.as_slice())
+                .take(block_size as u64 + 1)
+                .read_to_end(&mut block)
+                .map_err(|e| IsoDecompress(index, e))?;
+            block
+        },
+    };
+
+    let is_last = i + 1 == header.block_pointers.len();
+    match block.len() == block_size || (is_last && block.len() < block_size) {
+        true => Ok(block),
+        false => Err(IsoBlockChecksum(index)),
+    }
+}
+
+/// Computes the adler32 checksum that gcz stores for every block
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    // 5552 is the largest number of bytes that can be summed before `b` could
+    // overflow a u32, so only reduce once per chunk
+    for chunk in bytes.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_MOD;
+        b %= ADLER_MOD;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doesnt_try_to_read_headers_from_non_gcz_files() {
+        let mut file = File::open("test-data/misow.bin").unwrap();
+        let header = get_gcz_header(&mut file).unwrap();
+        assert!(header.is_none());
+
+        let mut file = File::open("test-data/ciso-header-1.bin").unwrap();
+        let header = get_gcz_header(&mut file).unwrap();
+        assert!(header.is_none());
+    }
+
+    #[test]
+    fn reads_gcz_header_correctly() {
+        let mut file = File::open("test-data/tiny-disc.gcz").unwrap();
+        let header = get_gcz_header(&mut file).unwrap().unwrap();
+        assert_eq!(header.block_size, 0x4000);
+        assert_eq!(header.data_size(), 0x20000);
+        assert_eq!(header.block_pointers.len(), 8);
+        assert_eq!(header.block_hashes.len(), 8);
+    }
+
+    #[test]
+    fn inflates_the_same_bytes_as_the_plain_disc() {
+        let iso = std::fs::read("test-data/tiny-disc.iso").unwrap();
+        let mut file = File::open("test-data/tiny-disc.gcz").unwrap();
+        let header = get_gcz_header(&mut file).unwrap().unwrap();
+
+        // Every block, including the uncompressed (junk) ones at the end
+        for offset in (0..0x20000).step_by(0x4000) {
+            let (start, block) = read_block_at(&mut file, &header, offset + 0x123).unwrap();
+            assert_eq!(start, offset);
+            assert_eq!(block, &iso[offset as usize..offset as usize + 0x4000]);
+        }
+    }
+
+    #[test]
+    fn fails_to_read_past_the_end_of_the_disc() {
+        let mut file = File::open("test-data/tiny-disc.gcz").unwrap();
+        let header = get_gcz_header(&mut file).unwrap().unwrap();
+        assert!(read_block_at(&mut file, &header, 0x20000).is_err());
+    }
+
+    /// Writes a copy of the tiny test gcz with `edit` applied to it, and opens it
+    fn edited_gcz(folder: &tempfile::TempDir, edit: impl FnOnce(&mut Vec<u8>)) -> File {
+        let mut gcz = std::fs::read("test-data/tiny-disc.gcz").unwrap();
+        edit(&mut gcz);
+
+        let path = folder.path().join("edited.gcz");
+        std::fs::write(&path, gcz).unwrap();
+        File::open(path).unwrap()
+    }
+
+    #[test]
+    fn rejects_headers_that_dont_fit_the_file() {
+        let folder = tempfile::tempdir().unwrap();
+
+        let edits: [(usize, &[u8]); 3] = [
+            // Block size of zero
+            (0x18, &0u32.to_le_bytes()),
+            // More blocks than the file has room for
+            (0x1c, &u32::MAX.to_le_bytes()),
+            // More compressed data than the file has
+            (0x8, &u64::MAX.to_le_bytes()),
+        ];
+        for (at, bytes) in edits {
+            let mut file = edited_gcz(&folder, |gcz| gcz[at..at + bytes.len()].copy_from_slice(bytes));
+            assert!(matches!(get_gcz_header(&mut file), Err(UnsupportedIso)), "{at:#x}");
+        }
+    }
+
+    #[test]
+    fn rejects_blocks_of_the_wrong_size() {
+        let folder = tempfile::tempdir().unwrap();
+
+        // Every block inflates to twice the block size that the header claims
+        let mut file = edited_gcz(&folder, |gcz| gcz[0x18..0x1c].copy_from_slice(&0x2000u32.to_le_bytes()));
+        let header = get_gcz_header(&mut file).unwrap().unwrap();
+        assert!(matches!(read_block_at(&mut file, &header, 0), Err(IsoBlockChecksum(0))));
+    }
+
+    #[test]
+    fn computes_adler32_checksums() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        assert_eq!(adler32(&[0xFF; 0x4000]), 0xB0D9C3B2);
+    }
+}
@@ -0,0 +1,88 @@
+use std::io::{Read, Seek};
+
+use crate::{Result, copy_bytes};
+
+/// Size of the part of the disc header that `DiscHeader` reads, which ends
+/// with the game's internal name
+const DISC_HEADER_SIZE: usize = 0x400;
+
+/// Where the internal name starts in the disc header
+const INTERNAL_NAME_OFFSET: usize = 0x20;
+
+/// Identifies the game on a disc, as stored at the start of every GameCube disc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscHeader {
+    /// Four characters identifying the game and its region, e.g. `GALE`
+    pub game_id: String,
+
+    /// Two characters identifying the publisher, e.g. `01`
+    pub maker_code: String,
+
+    /// Which disc this is, for games that come on more than one. The first
+    /// disc is 0.
+    pub disc_number: u8,
+
+    /// 0 for the game's first release, 1 for the first update, and so on. NTSC
+    /// Melee 1.02 is revision 2.
+    pub revision: u8,
+
+    /// Name of the game, e.g. `Super Smash Bros Melee`
+    pub internal_name: String,
+}
+
+impl DiscHeader {
+    /// Reads the header of the provided disc
+    pub fn read<R: Read + Seek>(disc: &mut R) -> Result<Self> {
+        let bytes = copy_bytes(disc, 0, DISC_HEADER_SIZE)?;
+        Ok(Self::parse(&bytes))
+    }
+
+    fn parse(bytes: &[u8]) -> Self {
+        let text = |bytes: &[u8]| {
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+        };
+
+        Self {
+            game_id: text(&bytes[0x0..0x4]),
+            maker_code: text(&bytes[0x4..0x6]),
+            disc_number: bytes[0x6],
+            revision: bytes[0x7],
+            internal_name: text(&bytes[INTERNAL_NAME_OFFSET..]),
+        }
+    }
+
+    /// Game ID followed by maker code, e.g. `GALE01`
+    pub fn id(&self) -> String {
+        format!("{}{}", self.game_id, self.maker_code)
+    }
+}
+
+impl std::fmt::Display for DiscHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} revision {}", self.id(), self.revision)?;
+        if !self.internal_name.is_empty() {
+            write!(f, " ({})", self.internal_name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DiscReader;
+
+    #[test]
+    fn reads_the_disc_header() {
+        for image in ["test-data/tiny-disc.iso", "test-data/tiny-disc.rvz"] {
+            let header = DiscHeader::read(&mut DiscReader::open(image).unwrap()).unwrap();
+            assert_eq!(header.game_id, "GALE");
+            assert_eq!(header.maker_code, "01");
+            assert_eq!(header.disc_number, 0);
+            assert_eq!(header.revision, 2);
+            assert_eq!(header.internal_name, "Super Smash Bros Melee");
+            assert_eq!(header.to_string(), "GALE01 revision 2 (Super Smash Bros Melee)");
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! This module reads GameCube disc images and the files on them. Jukebox and the game
+//! reporter both look inside the player's ISO, and do so through here so that every image
+//! format is supported in one place.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+mod ciso;
+
+mod dol;
+pub use dol::find_main_dol;
+
+mod errors;
+use DiscError::*;
+pub use errors::DiscError;
+
+mod fst;
+pub use fst::{DiscFile, FileSystemTable};
+
+mod gcz;
+
+mod header;
+pub use header::DiscHeader;
+
+mod wia;
+
+pub type Result<T> = std::result::Result<T, DiscError>;
+
+/// Size of a standard GameCube disc. Ciso images don't store how large the
+/// disc they contain is, so this is used when their block map covers a whole
+/// standard disc.
+const GAMECUBE_DISC_SIZE: u64 = 0x57058000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsoKind {
+    Standard,
+    Ciso,
+    Gcz,
+    Wia,
+    Rvz,
+    Unknown,
+}
+
+/// Given an iso file, determine what kind it is
+pub fn get_iso_kind(iso: &mut File) -> Result<IsoKind> {
+    // Get the first four bytes
+    iso.rewind().map_err(IsoSeek)?;
+    let mut initial_bytes = [0; 4];
+    iso.read_exact(&mut initial_bytes).map_err(IsoRead)?;
+
+    // Get the four bytes at 0x1c
+    iso.seek(std::io::SeekFrom::Start(0x1c)).map_err(IsoSeek)?;
+    let mut dvd_magic_bytes = [0; 4];
+    iso.read_exact(&mut dvd_magic_bytes).map_err(IsoRead)?;
+
+    match (initial_bytes, dvd_magic_bytes) {
+        // DVD Magic Word
+        (_, [0xc2, 0x33, 0x9F, 0x3D]) => Ok(IsoKind::Standard),
+        // CISO header
+        ([0x43, 0x49, 0x53, 0x4F], _) => Ok(IsoKind::Ciso),
+        // GCZ magic word (0xB10BC001, little endian)
+        ([0x01, 0xC0, 0x0B, 0xB1], _) => Ok(IsoKind::Gcz),
+        // "WIA\x01" and "RVZ\x01"
+        ([0x57, 0x49, 0x41, 0x01], _) => Ok(IsoKind::Wia),
+        ([0x52, 0x56, 0x5A, 0x01], _) => Ok(IsoKind::Rvz),
+        _ => Ok(IsoKind::Unknown),
+    }
+}
+
+/// Get a copy of the `size` bytes in `reader` at `offset`. Fails without
+/// allocating anything if they aren't all in `reader`.
+pub fn copy_bytes<R: Read + Seek>(reader: &mut R, offset: u64, size: usize) -> Result<Vec<u8>> {
+    check_bounds(reader, offset, size)?;
+    reader.seek(SeekFrom::Start(offset)).map_err(IsoSeek)?;
+    let mut bytes = vec![0; size];
+    reader.read_exact(&mut bytes).map_err(IsoRead)?;
+    Ok(bytes)
+}
+
+/// Makes sure that the `size` bytes at `offset` are all in `reader`. Offsets
+/// and lengths read from the disc itself can be anything on a corrupt or
+/// modified image, so they should be checked before anything is allocated for
+/// them.
+pub fn check_bounds<R: Seek>(reader: &mut R, offset: u64, size: usize) -> Result<()> {
+    let end = reader.seek(SeekFrom::End(0)).map_err(IsoSeek)?;
+    match offset.checked_add(size as u64) {
+        Some(last) if last <= end => Ok(()),
+        _ => Err(OutOfBounds(offset, size)),
+    }
+}
+
+/// The kind of image a `DiscReader` is reading from, along with whatever header
+/// is needed to find the disc's data within it.
+enum Image {
+    Standard,
+    Ciso(Box<ciso::CisoHeader>),
+    Gcz(gcz::GczHeader),
+    Wia(wia::WiaHeader),
+}
+
+/// Reads the _logical_ GameCube disc contained in a disc image, regardless of
+/// how the image stores it. Offsets are always offsets on a standard disc, so
+/// anything that knows where a file lives on the disc can seek there and read
+/// it, even when the file is split across blocks that are stored out of order,
+/// compressed, or not stored at all.
+///
+/// Example Usage:
+/// ```ignore
+/// let mut disc = DiscReader::open("/foo/bar.rvz")?;
+/// disc.seek(SeekFrom::Start(0x424))?;
+/// let mut fst_offset = [0; 4];
+/// disc.read_exact(&mut fst_offset)?;
+/// ```
+pub struct DiscReader {
+    iso: File,
+    kind: IsoKind,
+    image: Image,
+    size: u64,
+    position: u64,
+    /// The most recently decompressed block and its offset on the disc.
+    /// Music files are read in small pieces, so this saves decompressing the
+    /// same block over and over.
+    cached_block: Option<(u64, Vec<u8>)>,
+}
+
+impl DiscReader {
+    /// Opens the disc image at `iso_path`. Fails with `UnsupportedIso` if the
+    /// file isn't a disc image that we know how to read.
+    pub fn open(iso_path: &str) -> Result<Self> {
+        Self::new(File::open(iso_path)?)
+    }
+
+    /// Creates a reader for the disc image in `iso`
+    pub fn new(mut iso: File) -> Result<Self> {
+        let kind = get_iso_kind(&mut iso)?;
+        let (image, size) = match kind {
+            IsoKind::Standard => (Image::Standard, iso.metadata()?.len()),
+            IsoKind::Ciso => {
+                let header = ciso::get_ciso_header(&mut iso)?.ok_or(UnsupportedIso)?;
+                let size = ciso::get_ciso_disc_size(&header, GAMECUBE_DISC_SIZE);
+                (Image::Ciso(Box::new(header)), size)
+            },
+            IsoKind::Gcz => {
+                let header = gcz::get_gcz_header(&mut iso)?.ok_or(UnsupportedIso)?;
+                let size = header.data_size();
+                (Image::Gcz(header), size)
+            },
+            IsoKind::Wia | IsoKind::Rvz => {
+                let header = wia::get_wia_header(&mut iso)?.ok_or(UnsupportedIso)?;
+                let size = header.data_size();
+                (Image::Wia(header), size)
+            },
+            IsoKind::Unknown => return Err(UnsupportedIso),
+        };
+
+        Ok(Self {
+            iso,
+            kind,
+            image,
+            size,
+            position: 0,
+            cached_block: None,
+        })
+    }
+
+    /// Which kind of image the disc is stored in
+    pub fn kind(&self) -> IsoKind {
+        self.kind
+    }
+
+    /// Size of the logical disc
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Reads as much of `buf` as can be read from the image in one go. Reads
+    /// never go past the end of the block containing the current position.
+    fn read_from_image(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let position = self.position;
+
+        match &self.image {
+            Image::Standard => {
+                self.iso.seek(SeekFrom::Start(position)).map_err(IsoSeek)?;
+                self.iso.read(buf).map_err(IsoRead)
+            },
+            Image::Ciso(header) => {
+                let block_size = ciso::get_ciso_block_size(header) as u64;
+                let len = buf.len().min((block_size - position % block_size) as usize);
+
+                // Blocks that aren't in the image are all zeroes
+                match ciso::get_ciso_offset(header, position) {
+                    Some(offset) => {
+                        self.iso.seek(SeekFrom::Start(offset)).map_err(IsoSeek)?;
+                        self.iso.read(&mut buf[..len]).map_err(IsoRead)
+                    },
+                    None => {
+                        buf[..len].fill(0);
+                        Ok(len)
+                    },
+                }
+            },
+            Image::Gcz(_) | Image::Wia(_) => {
+                let is_cached = matches!(
+                    &self.cached_block,
+                    Some((start, block)) if (*start..*start + block.len() as u64).contains(&position)
+                );
+
+                if !is_cached {
+                    self.cached_block = Some(match &self.image {
+                        Image::Gcz(header) => gcz::read_block_at(&mut self.iso, header, position)?,
+                        Image::Wia(header) => wia::read_group_at(&mut self.iso, header, position)?,
+                        _ => unreachable!(),
+                    });
+                }
+
+                // A corrupt image can give back a block that doesn't contain
+                // the position at all
+                let (start, block) = self.cached_block.as_ref().unwrap();
+                let block = position
+                    .checked_sub(*start)
+                    .and_then(|skip| block.get(skip as usize..))
+                    .filter(|block| !block.is_empty())
+                    .ok_or_else(|| IsoRead(std::io::ErrorKind::UnexpectedEof.into()))?;
+                let len = buf.len().min(block.len());
+                buf[..len].copy_from_slice(&block[..len]);
+                Ok(len)
+            },
+        }
+    }
+}
+
+impl Read for DiscReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Images may contain data past the end of the disc (or be truncated
+        // before it), so never read outside of the logical disc
+        let remaining = self.size.saturating_sub(self.position);
+        let len = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let read = self.read_from_image(&mut buf[..len]).map_err(std::io::Error::other)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for DiscReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGES: [&str; 8] = [
+        "test-data/tiny-disc.iso",
+        "test-data/tiny-disc.ciso",
+        "test-data/tiny-disc.gcz",
+        "test-data/tiny-disc.rvz",
+        "test-data/tiny-disc-none.wia",
+        "test-data/tiny-disc-bzip2.wia",
+        "test-data/tiny-disc-lzma.wia",
+        "test-data/tiny-disc-lzma2.wia",
+    ];
+
+    #[test]
+    fn every_image_contains_the_same_logical_disc() {
+        let iso = std::fs::read("test-data/tiny-disc.iso").unwrap();
+
+        for image in IMAGES {
+            let mut disc = DiscReader::open(image).unwrap();
+            assert_eq!(disc.size(), iso.len() as u64, "{image}");
+
+            let mut bytes = Vec::new();
+            disc.read_to_end(&mut bytes).unwrap();
+            assert!(bytes == iso, "{image}");
+        }
+    }
+
+    #[test]
+    fn reads_across_block_boundaries() {
+        let iso = std::fs::read("test-data/tiny-disc.iso").unwrap();
+
+        // The `menu01.hps` file, a range spanning the unstored (zeroed) block
+        // of the ciso, and a range of junk crossing into the last block
+        let ranges = [(0x13F00, 0x500), (0x7FF0, 0x8020), (0x17FFF, 0x4002)];
+        for image in IMAGES {
+            let mut disc = DiscReader::open(image).unwrap();
+
+            for (offset, size) in ranges {
+                let mut bytes = vec![0; size];
+                disc.seek(SeekFrom::Start(offset)).unwrap();
+                disc.read_exact(&mut bytes).unwrap();
+                assert!(bytes == iso[offset as usize..offset as usize + size], "{image} @ {offset:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn stops_reading_at_the_end_of_the_disc() {
+        for image in IMAGES {
+            let mut disc = DiscReader::open(image).unwrap();
+
+            assert_eq!(disc.seek(SeekFrom::End(-0x100)).unwrap(), 0x1FF00);
+            let mut bytes = vec![0; 0x200];
+            assert!(disc.read_exact(&mut bytes).is_err(), "{image}");
+
+            assert_eq!(disc.seek(SeekFrom::Current(0x1000)).unwrap(), 0x21000);
+            assert_eq!(disc.read(&mut bytes).unwrap(), 0, "{image}");
+        }
+    }
+
+    #[test]
+    fn refuses_to_copy_past_the_end_of_the_disc() {
+        let mut disc = DiscReader::open("test-data/tiny-disc.rvz").unwrap();
+        assert_eq!(copy_bytes(&mut disc, 0x1FF00, 0x100).unwrap().len(), 0x100);
+
+        // None of these are allocated
+        for (offset, size) in [(0x1FF00, 0x200), (0, usize::MAX), (u64::MAX, 1)] {
+            assert!(
+                matches!(copy_bytes(&mut disc, offset, size), Err(OutOfBounds(..))),
+                "{size:#x} @ {offset:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn doesnt_open_unknown_files() {
+        assert!(matches!(DiscReader::open("test-data/misow.bin"), Err(UnsupportedIso)));
+    }
+}
@@ -0,0 +1,128 @@
+use std::io::Read;
+
+use bzip2::read::BzDecoder;
+use lzma_rs::decompress::{Options, UnpackedSize};
+use ruzstd::decoding::StreamingDecoder;
+
+/// Length of the SHA-1 hash that ends every purge compressed chunk
+const PURGE_HASH_SIZE: usize = 20;
+
+/// The compression methods that wia and rvz files may use for their tables and
+/// group data. Zstd is only used by rvz.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Compression {
+    None,
+    Purge,
+    Bzip2,
+    /// Carries the lzma properties byte followed by the little endian dictionary size
+    Lzma([u8; 5]),
+    /// The lzma2 dictionary size is also stored in the compressor data, but
+    /// lzma-rs doesn't need to be told about it up front
+    Lzma2,
+    Zstd,
+}
+
+impl Compression {
+    /// Parses the compression type and the compressor data stored in a wia
+    /// disc struct. Returns `None` for unknown or malformed values.
+    pub(crate) fn from_disc_struct(kind: u32, compressor_data: &[u8]) -> Option<Self> {
+        match kind {
+            0 => Some(Self::None),
+            1 => Some(Self::Purge),
+            2 => Some(Self::Bzip2),
+            3 => Some(Self::Lzma(compressor_data.get(..5)?.try_into().ok()?)),
+            4 => Some(Self::Lzma2),
+            5 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Decompresses `data`, which is expected to expand to `size` bytes
+    pub(crate) fn decompress(&self, data: &[u8], size: usize) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(size);
+
+        match self {
+            Self::None => out.extend_from_slice(data),
+            Self::Purge => purge_decompress(data, size, &mut out)?,
+            Self::Bzip2 => {
+                BzDecoder::new(data).read_to_end(&mut out)?;
+            },
+            Self::Lzma(properties) => {
+                let options = Options {
+                    unpacked_size: UnpackedSize::UseProvided(Some(size as u64)),
+                    ..Default::default()
+                };
+
+                // lzma-rs expects the properties in front of the raw stream,
+                // the same way an .lzma file header stores them
+                let mut input = properties.chain(data);
+                lzma_rs::lzma_decompress_with_options(&mut std::io::BufReader::new(&mut input), &mut out, &options)
+                    .map_err(to_io_error)?;
+            },
+            Self::Lzma2 => {
+                lzma_rs::lzma2_decompress(&mut std::io::BufReader::new(data), &mut out).map_err(to_io_error)?;
+            },
+            Self::Zstd => {
+                StreamingDecoder::new(data).map_err(to_io_error)?.read_to_end(&mut out)?;
+            },
+        };
+
+        if out.len() != size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Expected {size} decompressed bytes, got {}", out.len()),
+            ));
+        }
+
+        Ok(out)
+    }
+}
+
+/// Purge "compression" only strips runs of zeroes. The data is a list of
+/// `(offset, size, bytes)` segments followed by a SHA-1 hash, and everything not
+/// covered by a segment is zero.
+fn purge_decompress(data: &[u8], size: usize, out: &mut Vec<u8>) -> std::io::Result<()> {
+    let malformed = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed purge segment");
+
+    out.resize(size, 0);
+
+    let segments = data.len().checked_sub(PURGE_HASH_SIZE).ok_or_else(malformed)?;
+    let mut data = &data[..segments];
+    while !data.is_empty() {
+        let header = data.get(..8).ok_or_else(malformed)?;
+        let offset = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let length = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+
+        let bytes = data.get(8..8 + length).ok_or_else(malformed)?;
+        out.get_mut(offset..offset + length)
+            .ok_or_else(malformed)?
+            .copy_from_slice(bytes);
+
+        data = &data[8 + length..];
+    }
+
+    Ok(())
+}
+
+fn to_io_error<E: std::fmt::Debug>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{error:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_gaps_between_purge_segments_with_zeroes() {
+        let mut data = vec![0, 0, 0, 2, 0, 0, 0, 3, 0xA, 0xB, 0xC];
+        data.extend_from_slice(&[0; PURGE_HASH_SIZE]);
+
+        let out = Compression::Purge.decompress(&data, 8).unwrap();
+        assert_eq!(out, [0, 0, 0xA, 0xB, 0xC, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_data_that_doesnt_expand_to_the_expected_size() {
+        assert!(Compression::None.decompress(&[1, 2, 3], 4).is_err());
+    }
+}
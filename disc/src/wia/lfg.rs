@@ -0,0 +1,106 @@
+/// Size of the generator's state, in u32 words
+const LFG_K: usize = 521;
+
+/// Lag used when advancing the generator
+const LFG_J: usize = 32;
+
+/// Number of u32 words in a seed
+const SEED_SIZE: usize = 17;
+
+/// Number of bytes in a seed, as stored in rvz packed data
+pub(crate) const SEED_BYTES: usize = SEED_SIZE * 4;
+
+/// Lagged Fibonacci generator used by Nintendo's mastering tools to fill unused
+/// space on discs with "junk" data. Rvz doesn't store this junk at all; it only
+/// stores the seed, so the junk has to be regenerated to get back the original
+/// disc.
+///
+/// This mirrors Dolphin's `LaggedFibonacciGenerator`, which is the reference
+/// implementation for rvz.
+pub(crate) struct LaggedFibonacciGenerator {
+    buffer: [u32; LFG_K],
+    position: usize,
+}
+
+impl LaggedFibonacciGenerator {
+    /// Creates a generator from a big endian seed of `SEED_BYTES` bytes
+    pub(crate) fn new(seed: &[u8; SEED_BYTES]) -> Self {
+        let mut buffer = [0; LFG_K];
+
+        for (word, bytes) in buffer.iter_mut().zip(seed.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+
+        for i in SEED_SIZE..LFG_K {
+            buffer[i] = (buffer[i - 17] << 23) ^ (buffer[i - 16] >> 9) ^ buffer[i - 1];
+        }
+
+        // The output is shifted by 18 bits instead of 16 for the third byte of
+        // every word. Doing it once up front keeps the output path simple.
+        for word in buffer.iter_mut() {
+            *word = (*word & 0xFF00FFFF) | ((*word >> 2) & 0x00FF0000);
+        }
+
+        let mut generator = Self { buffer, position: 0 };
+        for _ in 0..4 {
+            generator.forward();
+        }
+
+        generator
+    }
+
+    /// Discards the next `count` bytes of output
+    pub(crate) fn skip(&mut self, count: usize) {
+        self.position += count;
+        while self.position >= LFG_K * 4 {
+            self.forward();
+            self.position -= LFG_K * 4;
+        }
+    }
+
+    /// Appends the next `count` bytes of output to `out`
+    pub(crate) fn take(&mut self, count: usize, out: &mut Vec<u8>) {
+        out.reserve(count);
+
+        for _ in 0..count {
+            out.push(self.buffer[self.position / 4].to_be_bytes()[self.position % 4]);
+
+            self.position += 1;
+            if self.position == LFG_K * 4 {
+                self.forward();
+                self.position = 0;
+            }
+        }
+    }
+
+    /// Advances the whole state buffer by one generation
+    fn forward(&mut self) {
+        for i in 0..LFG_J {
+            self.buffer[i] ^= self.buffer[i + LFG_K - LFG_J];
+        }
+
+        for i in LFG_J..LFG_K {
+            self.buffer[i] ^= self.buffer[i - LFG_J];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipping_matches_taking() {
+        let seed = std::array::from_fn(|i| i as u8 * 3);
+
+        let mut taken = Vec::new();
+        LaggedFibonacciGenerator::new(&seed).take(LFG_K * 4 * 2 + 100, &mut taken);
+
+        let mut skipped = Vec::new();
+        let mut generator = LaggedFibonacciGenerator::new(&seed);
+        generator.skip(LFG_K * 4 + 50);
+        generator.take(LFG_K * 4 + 50, &mut skipped);
+
+        assert_eq!(skipped, &taken[LFG_K * 4 + 50..]);
+    }
+}
@@ -0,0 +1,444 @@
+use std::fs::File;
+use std::io::{Read, Seek};
+
+use crate::{DiscError::*, Result};
+use crate::{IsoKind, get_iso_kind};
+
+mod compression;
+mod lfg;
+
+use compression::Compression;
+use lfg::{LaggedFibonacciGenerator, SEED_BYTES};
+
+const WIA_FILE_HEAD_SIZE: usize = 0x48;
+
+/// Smallest disc struct we know how to read. Newer versions of the format are
+/// allowed to make it larger.
+const WIA_DISC_STRUCT_SIZE: usize = 0xDC;
+
+/// The oldest versions of each format that we are able to read, and the newest
+/// version that files may require a reader to understand
+const WIA_VERSION_READ_COMPATIBLE: u32 = 0x00080000;
+const RVZ_VERSION_READ_COMPATIBLE: u32 = 0x00030000;
+const WIA_VERSION: u32 = 0x01000000;
+
+/// `disc_type` value of a GameCube disc (Wii discs are 2)
+const WIA_DISC_TYPE_GAMECUBE: u32 = 1;
+
+/// Raw data regions always start on a boundary of this size, even when the
+/// stored offset isn't aligned
+const WIA_SECTOR_SIZE: u64 = 0x8000;
+
+/// In rvz group entries, the top bit of the size says whether the group is
+/// compressed. The remaining bits are the stored size.
+const RVZ_COMPRESSED_FLAG: u32 = 1 << 31;
+
+/// In rvz packed data, the top bit of a run's size says whether the run is junk
+/// data that has to be regenerated from a seed.
+const RVZ_JUNK_FLAG: u32 = 1 << 31;
+
+/// A region of the disc that is stored as a sequence of groups. GameCube discs
+/// have no partitions, so the whole disc is described by these.
+#[derive(Debug)]
+struct RawDataEntry {
+    data_offset: u64,
+    data_size: u64,
+    group_index: usize,
+    group_count: usize,
+}
+
+/// Where a group (chunk) of disc data is stored in the file and how.
+#[derive(Debug)]
+struct GroupEntry {
+    data_offset: u64,
+    data_size: u32,
+    compressed: bool,
+    packed_size: u32,
+}
+
+/// Everything we need from the headers of a wia or rvz file in order to locate
+/// and decompress the groups of the disc it contains.
+#[derive(Debug)]
+pub(crate) struct WiaHeader {
+    is_rvz: bool,
+    /// Size of the wia/rvz file itself
+    file_size: u64,
+    iso_file_size: u64,
+    compression: Compression,
+    chunk_size: u64,
+    raw_data_entries: Vec<RawDataEntry>,
+    group_entries: Vec<GroupEntry>,
+}
+
+impl WiaHeader {
+    /// Size of the uncompressed disc stored in the wia/rvz
+    pub(crate) fn data_size(&self) -> u64 {
+        self.iso_file_size
+    }
+}
+
+/// Get the headers of a wia or rvz disc image. If the provided file is neither,
+/// `None` will be returned. Headers with sizes or ranges that don't fit in the
+/// file are `UnsupportedIso`.
+pub(crate) fn get_wia_header(iso: &mut File) -> Result<Option<WiaHeader>> {
+    let is_rvz = match get_iso_kind(iso)? {
+        IsoKind::Wia => false,
+        IsoKind::Rvz => true,
+        _ => return Ok(None),
+    };
+
+    let mut file_head = [0; WIA_FILE_HEAD_SIZE];
+    iso.rewind().map_err(IsoSeek)?;
+    iso.read_exact(&mut file_head).map_err(IsoRead)?;
+
+    let file_size = iso.metadata()?.len();
+    let version = read_u32(&file_head, 0x4);
+    let version_compatible = read_u32(&file_head, 0x8);
+    let disc_struct_size = read_u32(&file_head, 0xC) as usize;
+    let iso_file_size = read_u64(&file_head, 0x24);
+
+    let read_compatible = if is_rvz {
+        RVZ_VERSION_READ_COMPATIBLE
+    } else {
+        WIA_VERSION_READ_COMPATIBLE
+    };
+    if version < read_compatible
+        || version_compatible > WIA_VERSION
+        || disc_struct_size < WIA_DISC_STRUCT_SIZE
+        || WIA_FILE_HEAD_SIZE as u64 + disc_struct_size as u64 > file_size
+    {
+        return Err(UnsupportedIso);
+    }
+
+    // The disc struct immediately follows the file head
+    let mut disc_struct = vec![0; disc_struct_size];
+    iso.read_exact(&mut disc_struct).map_err(IsoRead)?;
+
+    // Wii discs are stored as partitions with their own hashing and encryption,
+    // none of which matters for Melee
+    if read_u32(&disc_struct, 0x0) != WIA_DISC_TYPE_GAMECUBE {
+        return Err(UnsupportedIso);
+    }
+
+    let compressor_data_size = (disc_struct[0xD4] as usize).min(7);
+    let compressor_data = &disc_struct[0xD5..0xD5 + compressor_data_size];
+    let compression = match Compression::from_disc_struct(read_u32(&disc_struct, 0x4), compressor_data) {
+        Some(Compression::Zstd) if !is_rvz => return Err(UnsupportedIso),
+        Some(compression) => compression,
+        None => return Err(UnsupportedIso),
+    };
+    let chunk_size = read_u32(&disc_struct, 0xC) as u64;
+    if chunk_size == 0 {
+        return Err(UnsupportedIso);
+    }
+
+    // Both tables are stored compressed with the same method as the disc data
+    let raw_data_count = read_u32(&disc_struct, 0xB4) as usize;
+    let raw_data_table = read_table(
+        iso,
+        file_size,
+        &compression,
+        read_u64(&disc_struct, 0xB8),
+        read_u32(&disc_struct, 0xC0),
+        raw_data_count.checked_mul(24).ok_or(UnsupportedIso)?,
+    )?;
+
+    let raw_data_entries = raw_data_table
+        .chunks_exact(24)
+        .map(|entry| {
+            // The stored offset doesn't have to be aligned, but the groups
+            // always start at the aligned offset before it
+            let data_offset = read_u64(entry, 0x0);
+            let skipped = data_offset % WIA_SECTOR_SIZE;
+            let data_size = read_u64(entry, 0x8)
+                .checked_add(skipped)
+                .filter(|size| data_offset.checked_add(size - skipped).is_some())
+                .ok_or(UnsupportedIso)?;
+
+            Ok(RawDataEntry {
+                data_offset: data_offset - skipped,
+                data_size,
+                group_index: read_u32(entry, 0x10) as usize,
+                group_count: read_u32(entry, 0x14) as usize,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    let group_count = read_u32(&disc_struct, 0xC4) as usize;
+    let group_entry_size = if is_rvz { 12 } else { 8 };
+    let group_table = read_table(
+        iso,
+        file_size,
+        &compression,
+        read_u64(&disc_struct, 0xC8),
+        read_u32(&disc_struct, 0xD0),
+        group_count.checked_mul(group_entry_size).ok_or(UnsupportedIso)?,
+    )?;
+
+    let group_entries = group_table
+        .chunks_exact(group_entry_size)
+        .map(|entry| {
+            // Offsets are stored divided by 4
+            let data_offset = read_u32(entry, 0x0) as u64 * 4;
+            let data_size = read_u32(entry, 0x4);
+
+            match is_rvz {
+                true => GroupEntry {
+                    data_offset,
+                    data_size: data_size & !RVZ_COMPRESSED_FLAG,
+                    compressed: data_size & RVZ_COMPRESSED_FLAG != 0,
+                    packed_size: read_u32(entry, 0x8),
+                },
+                false => GroupEntry {
+                    data_offset,
+                    data_size,
+                    compressed: true,
+                    packed_size: 0,
+                },
+            }
+        })
+        .collect();
+
+    Ok(Some(WiaHeader {
+        is_rvz,
+        file_size,
+        iso_file_size,
+        compression,
+        chunk_size,
+        raw_data_entries,
+        group_entries,
+    }))
+}
+
+/// Read and decompress the group containing the disc `offset`. Returns the
+/// offset on the disc where the group starts along with its contents.
+pub(crate) fn read_group_at(iso: &mut File, header: &WiaHeader, offset: u64) -> Result<(u64, Vec<u8>)> {
+    let eof = || IsoRead(std::io::ErrorKind::UnexpectedEof.into());
+
+    if offset >= header.iso_file_size {
+        return Err(eof());
+    }
+
+    let raw_data = header
+        .raw_data_entries
+        .iter()
+        .find(|entry| (entry.data_offset..entry.data_offset + entry.data_size).contains(&offset))
+        .ok_or_else(eof)?;
+
+    let group_in_entry = (offset - raw_data.data_offset) / header.chunk_size;
+    if group_in_entry as usize >= raw_data.group_count {
+        return Err(eof());
+    }
+
+    let index = raw_data.group_index + group_in_entry as usize;
+    let group = header.group_entries.get(index).ok_or_else(eof)?;
+    let group_offset = raw_data.data_offset + group_in_entry * header.chunk_size;
+    let group_size = header
+        .chunk_size
+        .min(raw_data.data_offset + raw_data.data_size - group_offset) as usize;
+
+    // Groups that are entirely zeroes aren't stored at all
+    if group.data_size == 0 {
+        return Ok((group_offset, vec![0; group_size]));
+    }
+
+    // A corrupt group entry can point anywhere, so make sure the group is in
+    // the file before allocating anything for it
+    if group.data_offset + group.data_size as u64 > header.file_size {
+        return Err(IsoBlockChecksum(index as u64));
+    }
+
+    let mut stored = vec![0; group.data_size as usize];
+    iso.seek(std::io::SeekFrom::Start(group.data_offset)).map_err(IsoSeek)?;
+    iso.read_exact(&mut stored).map_err(IsoRead)?;
+
+    let compression = match group.compressed {
+        true => header.compression,
+        false => Compression::None,
+    };
+
+    let decompress_error = |e| IsoDecompress(index as u64, e);
+    let data = match header.is_rvz && group.packed_size != 0 {
+        true => {
+            let packed = compression
+                .decompress(&stored, group.packed_size as usize)
+                .map_err(decompress_error)?;
+            unpack_rvz_group(&packed, group_offset, group_size).map_err(decompress_error)?
+        },
+        false => compression.decompress(&stored, group_size).map_err(decompress_error)?,
+    };
+
+    Ok((group_offset, data))
+}
+
+/// Rvz groups that contain junk data are "packed": they are a list of runs that
+/// are either stored bytes or a seed for regenerating junk.
+fn unpack_rvz_group(packed: &[u8], offset: u64, size: usize) -> std::io::Result<Vec<u8>> {
+    let malformed = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Malformed rvz packed data");
+
+    let mut data = Vec::with_capacity(size);
+    let mut packed = packed;
+
+    while data.len() < size {
+        let run_header = packed.get(..4).ok_or_else(malformed)?;
+        let run = u32::from_be_bytes(run_header.try_into().unwrap());
+        let length = (run & !RVZ_JUNK_FLAG) as usize;
+        packed = &packed[4..];
+
+        if run & RVZ_JUNK_FLAG != 0 {
+            let seed = packed.get(..SEED_BYTES).ok_or_else(malformed)?;
+            let mut generator = LaggedFibonacciGenerator::new(seed.try_into().unwrap());
+
+            // The junk stream restarts at every sector boundary
+            let run_offset = offset + data.len() as u64;
+            generator.skip((run_offset % WIA_SECTOR_SIZE) as usize);
+            generator.take(length, &mut data);
+            packed = &packed[SEED_BYTES..];
+        } else {
+            data.extend_from_slice(packed.get(..length).ok_or_else(malformed)?);
+            packed = &packed[length..];
+        }
+    }
+
+    if data.len() != size {
+        return Err(malformed());
+    }
+
+    Ok(data)
+}
+
+/// Reads a (compressed) table of `size` bytes that is stored at `offset`, in a
+/// file of `file_size` bytes
+fn read_table(
+    iso: &mut File,
+    file_size: u64,
+    compression: &Compression,
+    offset: u64,
+    stored_size: u32,
+    size: usize,
+) -> Result<Vec<u8>> {
+    if offset.checked_add(stored_size as u64).is_none_or(|end| end > file_size) {
+        return Err(UnsupportedIso);
+    }
+
+    let mut stored = vec![0; stored_size as usize];
+    iso.seek(std::io::SeekFrom::Start(offset)).map_err(IsoSeek)?;
+    iso.read_exact(&mut stored).map_err(IsoRead)?;
+
+    compression.decompress(&stored, size).map_err(IsoRead)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGES: [&str; 5] = [
+        "test-data/tiny-disc.rvz",
+        "test-data/tiny-disc-none.wia",
+        "test-data/tiny-disc-bzip2.wia",
+        "test-data/tiny-disc-lzma.wia",
+        "test-data/tiny-disc-lzma2.wia",
+    ];
+
+    #[test]
+    fn doesnt_try_to_read_headers_from_non_wia_files() {
+        let mut file = File::open("test-data/tiny-disc.gcz").unwrap();
+        assert!(get_wia_header(&mut file).unwrap().is_none());
+
+        let mut file = File::open("test-data/ciso-header-1.bin").unwrap();
+        assert!(get_wia_header(&mut file).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_wia_headers_correctly() {
+        let mut file = File::open("test-data/tiny-disc.rvz").unwrap();
+        let header = get_wia_header(&mut file).unwrap().unwrap();
+        assert!(header.is_rvz);
+        assert!(matches!(header.compression, Compression::Zstd));
+        assert_eq!(header.data_size(), 0x20000);
+        assert_eq!(header.chunk_size, 0x8000);
+        assert_eq!(header.raw_data_entries.len(), 1);
+        assert_eq!(header.group_entries.len(), 4);
+
+        let mut file = File::open("test-data/tiny-disc-lzma.wia").unwrap();
+        let header = get_wia_header(&mut file).unwrap().unwrap();
+        assert!(!header.is_rvz);
+        assert!(matches!(header.compression, Compression::Lzma(_)));
+        assert_eq!(header.chunk_size, 0x200000);
+        assert_eq!(header.group_entries.len(), 1);
+    }
+
+    #[test]
+    fn decompresses_the_same_bytes_as_the_plain_disc() {
+        let iso = std::fs::read("test-data/tiny-disc.iso").unwrap();
+
+        // Every group, including the all-zero one and the rvz's packed junk
+        for image in IMAGES {
+            let mut file = File::open(image).unwrap();
+            let header = get_wia_header(&mut file).unwrap().unwrap();
+            let group_size = header.chunk_size.min(0x20000);
+
+            for offset in (0..0x20000).step_by(group_size as usize) {
+                let (start, group) = read_group_at(&mut file, &header, offset + 0x123).unwrap();
+                assert_eq!(start, offset, "{image}");
+                assert!(
+                    group == iso[offset as usize..(offset + group_size) as usize],
+                    "{image} @ {offset:#x}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fails_to_read_past_the_end_of_the_disc() {
+        for image in IMAGES {
+            let mut file = File::open(image).unwrap();
+            let header = get_wia_header(&mut file).unwrap().unwrap();
+            assert!(read_group_at(&mut file, &header, 0x20000).is_err());
+        }
+    }
+
+    /// Writes a copy of the uncompressed test wia (whose tables are stored as
+    /// is) with `edit` applied to it, and opens it
+    fn edited_wia(folder: &tempfile::TempDir, edit: impl FnOnce(&mut Vec<u8>)) -> File {
+        let mut wia = std::fs::read("test-data/tiny-disc-none.wia").unwrap();
+        edit(&mut wia);
+
+        let path = folder.path().join("edited.wia");
+        std::fs::write(&path, wia).unwrap();
+        File::open(path).unwrap()
+    }
+
+    #[test]
+    fn rejects_sizes_that_dont_fit_the_file() {
+        let folder = tempfile::tempdir().unwrap();
+
+        let edits: [(usize, &[u8]); 4] = [
+            // Disc struct size
+            (0xC, &u32::MAX.to_be_bytes()),
+            // Stored size of the raw data table
+            (0x48 + 0xC0, &u32::MAX.to_be_bytes()),
+            // Offset of the group table
+            (0x48 + 0xC8, &u64::MAX.to_be_bytes()),
+            // Size of the raw data entry, which overflows its offset
+            (0x124 + 0x8, &u64::MAX.to_be_bytes()),
+        ];
+        for (at, bytes) in edits {
+            let mut file = edited_wia(&folder, |wia| wia[at..at + bytes.len()].copy_from_slice(bytes));
+            assert!(matches!(get_wia_header(&mut file), Err(UnsupportedIso)), "{at:#x}");
+        }
+
+        // Size of the only group
+        let mut file = edited_wia(&folder, |wia| wia[0x140..0x144].copy_from_slice(&u32::MAX.to_be_bytes()));
+        let header = get_wia_header(&mut file).unwrap().unwrap();
+        assert!(matches!(read_group_at(&mut file, &header, 0), Err(IsoBlockChecksum(0))));
+    }
+}
@@ -19,9 +19,18 @@ pub struct SCMConfig {
     // pub distributor_str: String,
 }
 
+/// Web services that can be pointed somewhere other than their defaults, e.g. for testing.
+#[derive(Debug, Default)]
+pub struct EndpointsConfig {
+    /// Where an up to date list of ISOs known to cause desyncs is fetched from. If this is
+    /// `None`, only the list bundled with the game reporter is used.
+    pub known_desync_isos_url: Option<String>,
+}
+
 /// Core EXI device parameters that we need provided by the Dolphin side.
 #[derive(Debug)]
 pub struct Config {
     pub paths: FilePathsConfig,
     pub scm: SCMConfig,
+    pub endpoints: EndpointsConfig,
 }
@@ -5,14 +5,16 @@
 //! `SlippiEXIDevice` and forwards calls over the C FFI. This has a fairly clean mapping to "when
 //! Slippi stuff is happening" and enables us to let the Rust side live in its own world.
 
+use std::path::Path;
+
 use dolphin_integrations::Log;
 use slippi_game_reporter::GameReporter;
 use slippi_gg_api::APIClient;
-use slippi_jukebox::Jukebox;
+use slippi_jukebox::{Config as JukeboxConfig, Jukebox, JukeboxError};
 use slippi_user::UserManager;
 
 mod config;
-pub use config::{Config, FilePathsConfig, SCMConfig};
+pub use config::{Config, EndpointsConfig, FilePathsConfig, SCMConfig};
 
 /// An EXI Device subclass specific to managing and interacting with the game itself.
 #[derive(Debug)]
@@ -27,6 +29,8 @@ pub enum JukeboxConfiguration {
     Start {
         initial_dolphin_system_volume: u8,
         initial_dolphin_music_volume: u8,
+        /// Name of a second audio device to also play music on, if any
+        mirror_output_device: Option<String>,
     },
     Stop,
 }
@@ -46,7 +50,13 @@ impl SlippiEXIDevice {
             config.scm.slippi_semver.clone(),
         );
 
-        let game_reporter = GameReporter::new(api_client.clone(), user_manager.clone(), config.paths.iso.clone());
+        let game_reporter = GameReporter::new(
+            api_client.clone(),
+            user_manager.clone(),
+            config.paths.iso.clone(),
+            config.paths.user_config_folder.clone().into(),
+            config.endpoints.known_desync_isos_url.clone(),
+        );
 
         // Playback has no need to deal with this.
         // (We could maybe silo more?)
@@ -68,37 +78,58 @@ impl SlippiEXIDevice {
     pub fn dma_read(&mut self, _address: usize, _size: usize) {}
 
     /// Configures a new Jukebox, or ensures an existing one is dropped if it's being disabled.
-    pub fn configure_jukebox(&mut self, config: JukeboxConfiguration) {
+    /// Returns why Jukebox couldn't be started, if it couldn't.
+    pub fn configure_jukebox(&mut self, config: JukeboxConfiguration) -> Result<(), JukeboxError> {
         if let JukeboxConfiguration::Stop = config {
             self.jukebox = None;
-            return;
+            return Ok(());
         }
 
         if self.jukebox.is_some() {
             tracing::warn!(target: Log::SlippiOnline, "Jukebox is already active");
-            return;
+            return Ok(());
         }
 
         if let JukeboxConfiguration::Start {
             initial_dolphin_system_volume,
             initial_dolphin_music_volume,
+            mirror_output_device,
         } = config
         {
-            match Jukebox::new(
+            // Users can replace songs by putting their own music in here
+            let music_folder = Path::new(&self.config.paths.user_config_folder).join("Music");
+
+            let mut jukebox_config = JukeboxConfig::new(
                 self.config.paths.iso.clone(),
+                Some(music_folder),
                 initial_dolphin_system_volume,
                 initial_dolphin_music_volume,
-            ) {
+            );
+
+            // Song loudness is measured once and remembered between sessions,
+            // as is whether the ISO's music can be played
+            let jukebox_folder = Path::new(&self.config.paths.user_config_folder).join("Jukebox");
+            jukebox_config.loudness_cache_path = Some(jukebox_folder.join("loudness.json"));
+            jukebox_config.scan_cache_path = Some(jukebox_folder.join("disc-scan.json"));
+
+            jukebox_config.mirror_output_device = mirror_output_device;
+
+            match Jukebox::new(jukebox_config) {
                 Ok(jukebox) => {
                     self.jukebox = Some(jukebox);
                 },
 
-                Err(e) => tracing::error!(
-                    target: Log::SlippiOnline,
-                    error = ?e,
-                    "Failed to start Jukebox"
-                ),
+                Err(e) => {
+                    tracing::error!(
+                        target: Log::SlippiOnline,
+                        error = ?e,
+                        "Failed to start Jukebox"
+                    );
+                    return Err(e);
+                },
             }
         }
+
+        Ok(())
     }
 }
@@ -15,6 +15,42 @@ typedef enum DirectCodeKind {
   TeamsCodes = 2,
 } DirectCodeKind;
 
+/**
+ * Mirrors `slippi_jukebox::PlaybackState` for the C++ side. Like `RustJukeboxResult`,
+ * variants are prefixed with the enum's name in the header.
+ *
+ */
+typedef enum RustJukeboxPlaybackState {
+  RustJukeboxPlaybackState_Idle = 0,
+  RustJukeboxPlaybackState_Loading = 1,
+  RustJukeboxPlaybackState_Playing = 2,
+  RustJukeboxPlaybackState_Error = 3,
+} RustJukeboxPlaybackState;
+
+/**
+ * Result codes for Jukebox calls that can fail, mirroring `slippi_jukebox::JukeboxErrorKind`,
+ * plus `InvalidArgument` for calls that were passed a value they don't accept. Values are
+ * stable, so the C++ side can switch on them.
+ *
+ * Variants are prefixed with the enum's name in the header (e.g. `RustJukeboxResult_Ok`),
+ * as plain names like `Ok` and `Error` would collide with names in Dolphin.
+ *
+ */
+typedef enum RustJukeboxResult {
+  RustJukeboxResult_Ok = 0,
+  RustJukeboxResult_UnsupportedIso = 1,
+  RustJukeboxResult_UnsupportedGame = 2,
+  RustJukeboxResult_AudioDevice = 3,
+  RustJukeboxResult_AudioCapture = 4,
+  RustJukeboxResult_DiscRead = 5,
+  RustJukeboxResult_InvalidMusic = 6,
+  RustJukeboxResult_Io = 7,
+  RustJukeboxResult_ThreadSpawn = 8,
+  RustJukeboxResult_ThreadStopped = 9,
+  RustJukeboxResult_Unknown = 10,
+  RustJukeboxResult_InvalidArgument = 11,
+} RustJukeboxResult;
+
 /**
  * This enum is duplicated from `slippi_game_reporter::OnlinePlayMode` in order
  * to appease cbindgen, which cannot see the type from the other module for
@@ -45,6 +81,37 @@ typedef struct SlippiRustEXIConfig {
   void (*osd_add_msg_fn)(const char*, uint32_t, uint32_t);
 } SlippiRustEXIConfig;
 
+/**
+ * An intermediary type for moving the names of audio output devices across the FFI boundary.
+ *
+ * This type is C compatible, and we coerce Rust types into C types for this struct to
+ * ease passing things over. This must be free'd on the Rust side via `slprs_jukebox_free_output_devices`.
+ */
+typedef struct RustJukeboxOutputDevices {
+  char **data;
+  int len;
+} RustJukeboxOutputDevices;
+
+/**
+ * An intermediary type for moving a `JukeboxStatus` across the FFI boundary.
+ *
+ * This type is C compatible, and we coerce Rust types into C types for this struct to
+ * ease passing things over. This must be free'd on the Rust side via `slprs_jukebox_free_status`.
+ *
+ * `hps_offset` is 0 when there is no current song, and `file_name` and `error` are null
+ * when they're not known.
+ */
+typedef struct RustJukeboxStatus {
+  enum RustJukeboxPlaybackState state;
+  uint64_t hps_offset;
+  const char *file_name;
+  uint64_t elapsed_samples;
+  uint32_t sample_rate;
+  float volume;
+  bool paused;
+  const char *error;
+} RustJukeboxStatus;
+
 /**
  * Rank info that we vend back to the Dolphin side of things.
  */
@@ -86,6 +153,13 @@ typedef struct RustChatMessages {
 extern "C" {
 #endif // __cplusplus
 
+/**
+ * Sets where the list of ISOs known to cause desyncs is fetched from, for EXI devices
+ * created from now on. Call this before `slprs_exi_device_create`. A null or empty string
+ * goes back to the default of only using the list bundled with Dolphin.
+ */
+void slprs_exi_device_set_known_desync_isos_url(const char *url);
+
 /**
  * Creates and leaks a shadow EXI device with the provided configuration.
  *
@@ -157,12 +231,28 @@ void slprs_exi_device_reporter_push_replay_data(uintptr_t instance_ptr,
  * Configures the Jukebox process. This needs to be called after the EXI device is created
  * in order for certain pieces of Dolphin to be properly initalized; this may change down
  * the road though and is not set in stone.
+ *
+ * See `slprs_exi_device_try_configure_jukebox` to also play music on a second audio
+ * device, or to find out whether Jukebox started.
  */
 void slprs_exi_device_configure_jukebox(uintptr_t exi_device_instance_ptr,
                                         bool is_enabled,
                                         uint8_t initial_dolphin_system_volume,
                                         uint8_t initial_dolphin_music_volume);
 
+/**
+ * Same as `slprs_exi_device_configure_jukebox`, but returns whether Jukebox started, or
+ * why it didn't. Stopping Jukebox always succeeds.
+ *
+ * `mirror_output_device` is the name of a second audio device to also play music on. Pass
+ * a null or empty string to only play music on the main device.
+ */
+enum RustJukeboxResult slprs_exi_device_try_configure_jukebox(uintptr_t exi_device_instance_ptr,
+                                                              bool is_enabled,
+                                                              uint8_t initial_dolphin_system_volume,
+                                                              uint8_t initial_dolphin_music_volume,
+                                                              const char *mirror_output_device);
+
 /**
  * Creates a new Player Report and leaks it, returning the pointer.
  *
@@ -230,6 +320,101 @@ void slprs_jukebox_set_dolphin_system_volume(uintptr_t exi_device_instance_ptr,
  */
 void slprs_jukebox_set_dolphin_music_volume(uintptr_t exi_device_instance_ptr, uint8_t volume);
 
+/**
+ * Calls through to `Jukebox::set_fade_durations`. Durations are in
+ * milliseconds.
+ */
+void slprs_jukebox_set_fade_durations(uintptr_t exi_device_instance_ptr,
+                                      uint32_t fade_out_ms,
+                                      uint32_t crossfade_ms);
+
+/**
+ * Calls through to `Jukebox::emulation_paused`.
+ */
+void slprs_jukebox_emulation_paused(uintptr_t exi_device_instance_ptr);
+
+/**
+ * Calls through to `Jukebox::emulation_resumed`.
+ */
+void slprs_jukebox_emulation_resumed(uintptr_t exi_device_instance_ptr);
+
+/**
+ * Calls through to `Jukebox::set_emulation_speed`. A `speed` of 1.0 is full speed.
+ */
+void slprs_jukebox_set_emulation_speed(uintptr_t exi_device_instance_ptr, float speed);
+
+/**
+ * Calls through to `Jukebox::is_running`. Returns `false` if Jukebox isn't enabled.
+ */
+bool slprs_jukebox_is_running(uintptr_t exi_device_instance_ptr);
+
+/**
+ * Calls through to `Jukebox::thread_error`, returning why the player thread stopped. Returns
+ * `Ok` while it's running, or if Jukebox isn't enabled.
+ */
+enum RustJukeboxResult slprs_jukebox_get_thread_error(uintptr_t exi_device_instance_ptr);
+
+/**
+ * Calls through to `Jukebox::set_channel_mode` with Melee's Stereo/Mono sound setting, as
+ * read from the game: 0 for stereo and 1 for mono. This should be called whenever the game's
+ * sound setting changes.
+ *
+ * Returns `InvalidArgument` for any other `channel_mode`, and otherwise `Ok` (including when
+ * Jukebox isn't enabled).
+ */
+enum RustJukeboxResult slprs_jukebox_set_channel_mode(uintptr_t exi_device_instance_ptr,
+                                                      int32_t channel_mode);
+
+/**
+ * Calls through to `Jukebox::set_balance`.
+ */
+void slprs_jukebox_set_balance(uintptr_t exi_device_instance_ptr, float balance);
+
+/**
+ * Calls through to `Jukebox::set_loudness_normalization`.
+ */
+void slprs_jukebox_set_loudness_normalization(uintptr_t exi_device_instance_ptr, bool enabled);
+
+/**
+ * Calls through to `Jukebox::set_mirror_volume`.
+ */
+void slprs_jukebox_set_mirror_volume(uintptr_t exi_device_instance_ptr, uint8_t volume);
+
+/**
+ * Calls through to `Jukebox::set_output_device`. Passing a null or empty
+ * `device_name` plays music on the system's default device.
+ */
+void slprs_jukebox_set_output_device(uintptr_t exi_device_instance_ptr, const char *device_name);
+
+/**
+ * Returns a C-compatible struct containing the names of the audio output devices that
+ * Jukebox can play music on. This doesn't need a running Jukebox, so settings UIs can
+ * list devices at any time.
+ *
+ * The return value of this _must_ be passed back to `slprs_jukebox_free_output_devices` to free memory.
+ */
+struct RustJukeboxOutputDevices *slprs_jukebox_get_output_devices(void);
+
+/**
+ * Takes back ownership of a `RustJukeboxOutputDevices` instance and frees the underlying data
+ * by converting it into the proper Rust types.
+ */
+void slprs_jukebox_free_output_devices(struct RustJukeboxOutputDevices *ptr);
+
+/**
+ * Calls through to `Jukebox::status` and wraps it in a C struct to pass back so that
+ * ownership is safely moved. Returns null if Jukebox isn't running.
+ *
+ * The return value of this _must_ be passed back to `slprs_jukebox_free_status` to free memory.
+ */
+struct RustJukeboxStatus *slprs_jukebox_get_status(uintptr_t exi_device_instance_ptr);
+
+/**
+ * Takes back ownership of a `RustJukeboxStatus` instance and frees the underlying data
+ * by converting it into the proper Rust types.
+ */
+void slprs_jukebox_free_status(struct RustJukeboxStatus *ptr);
+
 /**
  * This should be called from the Dolphin LogManager initialization to ensure that
  * all logging needs on the Rust side are configured appropriately.
@@ -1,10 +1,12 @@
 use std::ffi::c_char;
+use std::sync::Mutex;
 
 use dolphin_integrations::Log;
-use slippi_exi_device::{Config, FilePathsConfig, JukeboxConfiguration, SCMConfig, SlippiEXIDevice};
+use slippi_exi_device::{Config, EndpointsConfig, FilePathsConfig, JukeboxConfiguration, SCMConfig, SlippiEXIDevice};
 use slippi_game_reporter::GameReport;
 
 use crate::c_str_to_string;
+use crate::jukebox::RustJukeboxResult;
 
 /// A configuration struct for passing over certain argument types from the C/C++ side.
 ///
@@ -36,6 +38,24 @@ pub struct SlippiRustEXIConfig {
     pub osd_add_msg_fn: unsafe extern "C" fn(*const c_char, u32, u32),
 }
 
+/// Endpoint set by `slprs_exi_device_set_known_desync_isos_url`, for EXI devices that are
+/// created after it's set. This isn't part of `SlippiRustEXIConfig`, as that's passed by
+/// value and adding to it would break older Dolphin builds.
+static KNOWN_DESYNC_ISOS_URL: Mutex<Option<String>> = Mutex::new(None);
+
+/// Sets where the list of ISOs known to cause desyncs is fetched from, for EXI devices
+/// created from now on. Call this before `slprs_exi_device_create`. A null or empty string
+/// goes back to the default of only using the list bundled with Dolphin.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_exi_device_set_known_desync_isos_url(url: *const c_char) {
+    let url = match url.is_null() {
+        true => None,
+        false => Some(c_str_to_string(url, "slprs_exi_device_set_known_desync_isos_url", "url")).filter(|url| !url.is_empty()),
+    };
+
+    *KNOWN_DESYNC_ISOS_URL.lock().unwrap() = url;
+}
+
 /// Creates and leaks a shadow EXI device with the provided configuration.
 ///
 /// The C++ (Dolphin) side of things should call this and pass the appropriate arguments. At
@@ -58,6 +78,10 @@ pub extern "C" fn slprs_exi_device_create(config: SlippiRustEXIConfig) -> usize
         scm: SCMConfig {
             slippi_semver: c_str_to_string(config.scm_slippi_semver_str, fn_name, "slippi_semver"),
         },
+
+        endpoints: EndpointsConfig {
+            known_desync_isos_url: KNOWN_DESYNC_ISOS_URL.lock().unwrap().clone(),
+        },
     }));
 
     let exi_device_instance_ptr = Box::into_raw(exi_device) as usize;
@@ -206,6 +230,9 @@ pub extern "C" fn slprs_exi_device_reporter_push_replay_data(instance_ptr: usize
 /// Configures the Jukebox process. This needs to be called after the EXI device is created
 /// in order for certain pieces of Dolphin to be properly initalized; this may change down
 /// the road though and is not set in stone.
+///
+/// See `slprs_exi_device_try_configure_jukebox` to also play music on a second audio
+/// device, or to find out whether Jukebox started.
 #[unsafe(no_mangle)]
 pub extern "C" fn slprs_exi_device_configure_jukebox(
     exi_device_instance_ptr: usize,
@@ -213,6 +240,38 @@ pub extern "C" fn slprs_exi_device_configure_jukebox(
     initial_dolphin_system_volume: u8,
     initial_dolphin_music_volume: u8,
 ) {
+    let _ = slprs_exi_device_try_configure_jukebox(
+        exi_device_instance_ptr,
+        is_enabled,
+        initial_dolphin_system_volume,
+        initial_dolphin_music_volume,
+        std::ptr::null(),
+    );
+}
+
+/// Same as `slprs_exi_device_configure_jukebox`, but returns whether Jukebox started, or
+/// why it didn't. Stopping Jukebox always succeeds.
+///
+/// `mirror_output_device` is the name of a second audio device to also play music on. Pass
+/// a null or empty string to only play music on the main device.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_exi_device_try_configure_jukebox(
+    exi_device_instance_ptr: usize,
+    is_enabled: bool,
+    initial_dolphin_system_volume: u8,
+    initial_dolphin_music_volume: u8,
+    mirror_output_device: *const c_char,
+) -> RustJukeboxResult {
+    let mirror_output_device = match mirror_output_device.is_null() {
+        true => None,
+        false => Some(c_str_to_string(
+            mirror_output_device,
+            "slprs_exi_device_try_configure_jukebox",
+            "mirror_output_device",
+        ))
+        .filter(|name| !name.is_empty()),
+    };
+
     // Coerce the instance from the pointer. This is theoretically safe since we control
     // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
     // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
@@ -222,11 +281,17 @@ pub extern "C" fn slprs_exi_device_configure_jukebox(
         true => JukeboxConfiguration::Start {
             initial_dolphin_system_volume,
             initial_dolphin_music_volume,
+            mirror_output_device,
         },
         false => JukeboxConfiguration::Stop,
     };
-    device.configure_jukebox(jukebox_config);
+    let result = match device.configure_jukebox(jukebox_config) {
+        Ok(()) => RustJukeboxResult::Ok,
+        Err(e) => e.kind().into(),
+    };
 
     // Fall back into a raw pointer so Rust doesn't obliterate the object.
     let _leak = Box::into_raw(device);
+
+    result
 }
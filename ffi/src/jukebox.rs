@@ -1,5 +1,12 @@
+use std::ffi::{CString, c_char, c_int};
+use std::ptr;
+use std::time::Duration;
+
+use dolphin_integrations::Log;
 use slippi_exi_device::SlippiEXIDevice;
-use slippi_jukebox::VolumeControl;
+use slippi_jukebox::{ChannelMode, Jukebox, JukeboxErrorKind, PlaybackState, VolumeControl};
+
+use crate::c_str_to_string;
 
 /// Calls through to `Jukebox::start_song`.
 #[unsafe(no_mangle)]
@@ -80,3 +87,407 @@ pub extern "C" fn slprs_jukebox_set_dolphin_music_volume(exi_device_instance_ptr
     // Fall back into a raw pointer so Rust doesn't obliterate the object.
     let _leak = Box::into_raw(device);
 }
+
+/// Calls through to `Jukebox::set_fade_durations`. Durations are in
+/// milliseconds.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_set_fade_durations(exi_device_instance_ptr: usize, fade_out_ms: u32, crossfade_ms: u32) {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let mut device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    if let Some(jukebox) = device.jukebox.as_mut() {
+        jukebox.set_fade_durations(
+            Duration::from_millis(fade_out_ms as u64),
+            Duration::from_millis(crossfade_ms as u64),
+        );
+    }
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+}
+
+/// Calls through to `Jukebox::emulation_paused`.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_emulation_paused(exi_device_instance_ptr: usize) {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let mut device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    if let Some(jukebox) = device.jukebox.as_mut() {
+        jukebox.emulation_paused();
+    }
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+}
+
+/// Calls through to `Jukebox::emulation_resumed`.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_emulation_resumed(exi_device_instance_ptr: usize) {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let mut device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    if let Some(jukebox) = device.jukebox.as_mut() {
+        jukebox.emulation_resumed();
+    }
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+}
+
+/// Calls through to `Jukebox::set_emulation_speed`. A `speed` of 1.0 is full speed.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_set_emulation_speed(exi_device_instance_ptr: usize, speed: f32) {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let mut device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    if let Some(jukebox) = device.jukebox.as_mut() {
+        jukebox.set_emulation_speed(speed);
+    }
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+}
+
+/// Result codes for Jukebox calls that can fail, mirroring `slippi_jukebox::JukeboxErrorKind`,
+/// plus `InvalidArgument` for calls that were passed a value they don't accept. Values are
+/// stable, so the C++ side can switch on them.
+///
+/// Variants are prefixed with the enum's name in the header (e.g. `RustJukeboxResult_Ok`),
+/// as plain names like `Ok` and `Error` would collide with names in Dolphin.
+///
+/// cbindgen:prefix-with-name
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RustJukeboxResult {
+    Ok = 0,
+    UnsupportedIso = 1,
+    UnsupportedGame = 2,
+    AudioDevice = 3,
+    AudioCapture = 4,
+    DiscRead = 5,
+    InvalidMusic = 6,
+    Io = 7,
+    ThreadSpawn = 8,
+    ThreadStopped = 9,
+    Unknown = 10,
+    InvalidArgument = 11,
+}
+
+impl From<JukeboxErrorKind> for RustJukeboxResult {
+    fn from(kind: JukeboxErrorKind) -> Self {
+        match kind {
+            JukeboxErrorKind::UnsupportedIso => Self::UnsupportedIso,
+            JukeboxErrorKind::UnsupportedGame => Self::UnsupportedGame,
+            JukeboxErrorKind::AudioDevice => Self::AudioDevice,
+            JukeboxErrorKind::AudioCapture => Self::AudioCapture,
+            JukeboxErrorKind::DiscRead => Self::DiscRead,
+            JukeboxErrorKind::InvalidMusic => Self::InvalidMusic,
+            JukeboxErrorKind::Io => Self::Io,
+            JukeboxErrorKind::ThreadSpawn => Self::ThreadSpawn,
+            JukeboxErrorKind::ThreadStopped => Self::ThreadStopped,
+            JukeboxErrorKind::Unknown => Self::Unknown,
+        }
+    }
+}
+
+/// Calls through to `Jukebox::is_running`. Returns `false` if Jukebox isn't enabled.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_is_running(exi_device_instance_ptr: usize) -> bool {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    let is_running = device.jukebox.as_ref().is_some_and(Jukebox::is_running);
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+
+    is_running
+}
+
+/// Calls through to `Jukebox::thread_error`, returning why the player thread stopped. Returns
+/// `Ok` while it's running, or if Jukebox isn't enabled.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_get_thread_error(exi_device_instance_ptr: usize) -> RustJukeboxResult {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    let result = device
+        .jukebox
+        .as_ref()
+        .and_then(Jukebox::thread_error)
+        .map_or(RustJukeboxResult::Ok, RustJukeboxResult::from);
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+
+    result
+}
+
+/// Calls through to `Jukebox::set_channel_mode` with Melee's Stereo/Mono sound setting, as
+/// read from the game: 0 for stereo and 1 for mono. This should be called whenever the game's
+/// sound setting changes.
+///
+/// Returns `InvalidArgument` for any other `channel_mode`, and otherwise `Ok` (including when
+/// Jukebox isn't enabled).
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_set_channel_mode(exi_device_instance_ptr: usize, channel_mode: i32) -> RustJukeboxResult {
+    let channel_mode = match channel_mode {
+        0 => ChannelMode::Stereo,
+        1 => ChannelMode::Mono,
+        _ => {
+            tracing::error!(
+                target: Log::Jukebox,
+                "Invalid channel mode passed to slprs_jukebox_set_channel_mode: {channel_mode}"
+            );
+            return RustJukeboxResult::InvalidArgument;
+        },
+    };
+
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let mut device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    if let Some(jukebox) = device.jukebox.as_mut() {
+        jukebox.set_channel_mode(channel_mode);
+    }
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+
+    RustJukeboxResult::Ok
+}
+
+/// Calls through to `Jukebox::set_balance`.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_set_balance(exi_device_instance_ptr: usize, balance: f32) {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let mut device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    if let Some(jukebox) = device.jukebox.as_mut() {
+        jukebox.set_balance(balance);
+    }
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+}
+
+/// Calls through to `Jukebox::set_loudness_normalization`.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_set_loudness_normalization(exi_device_instance_ptr: usize, enabled: bool) {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let mut device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    if let Some(jukebox) = device.jukebox.as_mut() {
+        jukebox.set_loudness_normalization(enabled);
+    }
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+}
+
+/// Calls through to `Jukebox::set_mirror_volume`.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_set_mirror_volume(exi_device_instance_ptr: usize, volume: u8) {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let mut device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    if let Some(jukebox) = device.jukebox.as_mut() {
+        jukebox.set_mirror_volume(volume);
+    }
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+}
+
+/// Calls through to `Jukebox::set_output_device`. Passing a null or empty
+/// `device_name` plays music on the system's default device.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_set_output_device(exi_device_instance_ptr: usize, device_name: *const c_char) {
+    let device_name = match device_name.is_null() {
+        true => None,
+        false => {
+            Some(c_str_to_string(device_name, "slprs_jukebox_set_output_device", "device_name")).filter(|name| !name.is_empty())
+        },
+    };
+
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let mut device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    if let Some(jukebox) = device.jukebox.as_mut() {
+        jukebox.set_output_device(device_name.as_deref());
+    }
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+}
+
+/// An intermediary type for moving the names of audio output devices across the FFI boundary.
+///
+/// This type is C compatible, and we coerce Rust types into C types for this struct to
+/// ease passing things over. This must be free'd on the Rust side via `slprs_jukebox_free_output_devices`.
+#[repr(C)]
+pub struct RustJukeboxOutputDevices {
+    pub data: *mut *mut c_char,
+    pub len: c_int,
+}
+
+/// Returns a C-compatible struct containing the names of the audio output devices that
+/// Jukebox can play music on. This doesn't need a running Jukebox, so settings UIs can
+/// list devices at any time.
+///
+/// The return value of this _must_ be passed back to `slprs_jukebox_free_output_devices` to free memory.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_get_output_devices() -> *mut RustJukeboxOutputDevices {
+    // Same layout as chat messages: a shrunk Vec of CString pointers that the free
+    // method below rebuilds and cleans up.
+    let mut devices: Vec<*mut _> = Jukebox::output_devices()
+        .into_iter()
+        .filter_map(|name| CString::new(name).ok())
+        .map(CString::into_raw)
+        .collect();
+
+    devices.shrink_to_fit();
+
+    let len = devices.len() as c_int;
+    let data = devices.as_mut_ptr();
+    std::mem::forget(devices);
+
+    Box::into_raw(Box::new(RustJukeboxOutputDevices { data, len }))
+}
+
+/// Takes back ownership of a `RustJukeboxOutputDevices` instance and frees the underlying data
+/// by converting it into the proper Rust types.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_free_output_devices(ptr: *mut RustJukeboxOutputDevices) {
+    if ptr.is_null() {
+        return;
+    }
+
+    unsafe {
+        let devices = Box::from_raw(ptr);
+
+        // Rebuild the Vec~
+        let len = devices.len as usize;
+        let devices = Vec::from_raw_parts(devices.data, len, len);
+
+        // Consume, walk, and free the inner items
+        for device in devices.into_iter() {
+            let _device = CString::from_raw(device);
+        }
+    }
+}
+
+/// Mirrors `slippi_jukebox::PlaybackState` for the C++ side. Like `RustJukeboxResult`,
+/// variants are prefixed with the enum's name in the header.
+///
+/// cbindgen:prefix-with-name
+#[repr(C)]
+pub enum RustJukeboxPlaybackState {
+    Idle = 0,
+    Loading = 1,
+    Playing = 2,
+    Error = 3,
+}
+
+/// An intermediary type for moving a `JukeboxStatus` across the FFI boundary.
+///
+/// This type is C compatible, and we coerce Rust types into C types for this struct to
+/// ease passing things over. This must be free'd on the Rust side via `slprs_jukebox_free_status`.
+///
+/// `hps_offset` is 0 when there is no current song, and `file_name` and `error` are null
+/// when they're not known.
+#[repr(C)]
+pub struct RustJukeboxStatus {
+    pub state: RustJukeboxPlaybackState,
+    pub hps_offset: u64,
+    pub file_name: *const c_char,
+    pub elapsed_samples: u64,
+    pub sample_rate: u32,
+    pub volume: f32,
+    pub paused: bool,
+    pub error: *const c_char,
+}
+
+/// Calls through to `Jukebox::status` and wraps it in a C struct to pass back so that
+/// ownership is safely moved. Returns null if Jukebox isn't running.
+///
+/// The return value of this _must_ be passed back to `slprs_jukebox_free_status` to free memory.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_get_status(exi_device_instance_ptr: usize) -> *mut RustJukeboxStatus {
+    // Coerce the instance from the pointer. This is theoretically safe since we control
+    // the C++ side and can guarantee that the `exi_device_instance_ptr` is only owned
+    // by the C++ EXI device, and is created/destroyed with the corresponding lifetimes.
+    let device = unsafe { Box::from_raw(exi_device_instance_ptr as *mut SlippiEXIDevice) };
+
+    let status = device.jukebox.as_ref().map(|jukebox| {
+        let status = jukebox.status();
+
+        let to_c_string = |string: Option<String>| match string.and_then(|string| CString::new(string).ok()) {
+            Some(string) => string.into_raw() as *const c_char,
+            None => ptr::null(),
+        };
+
+        Box::new(RustJukeboxStatus {
+            state: match status.state {
+                PlaybackState::Idle => RustJukeboxPlaybackState::Idle,
+                PlaybackState::Loading => RustJukeboxPlaybackState::Loading,
+                PlaybackState::Playing => RustJukeboxPlaybackState::Playing,
+                PlaybackState::Error => RustJukeboxPlaybackState::Error,
+            },
+            hps_offset: status.hps_offset.unwrap_or(0),
+            file_name: to_c_string(status.file_name),
+            elapsed_samples: status.elapsed_samples,
+            sample_rate: status.sample_rate,
+            volume: status.volume,
+            paused: status.paused,
+            error: to_c_string(status.error),
+        })
+    });
+
+    // Fall back into a raw pointer so Rust doesn't obliterate the object.
+    let _leak = Box::into_raw(device);
+
+    status.map_or(ptr::null_mut(), Box::into_raw)
+}
+
+/// Takes back ownership of a `RustJukeboxStatus` instance and frees the underlying data
+/// by converting it into the proper Rust types.
+#[unsafe(no_mangle)]
+pub extern "C" fn slprs_jukebox_free_status(ptr: *mut RustJukeboxStatus) {
+    if ptr.is_null() {
+        return;
+    }
+
+    unsafe {
+        let status = Box::from_raw(ptr);
+
+        if !status.file_name.is_null() {
+            let _file_name = CString::from_raw(status.file_name as *mut _);
+        }
+
+        if !status.error.is_null() {
+            let _error = CString::from_raw(status.error as *mut _);
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Identifies which build of Melee the player is running (vanilla 1.02, or a known mod)
+//! from the hashes of a few files that mods tend to change. Unlike the whole-ISO hash,
+//! this still works for builds that only differ in unrelated files.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Seek, SeekFrom};
+
+use serde::Deserialize;
+
+use dolphin_integrations::Log;
+use slippi_disc::{DiscError, DiscReader, FileSystemTable, check_bounds, find_main_dol};
+
+use crate::iso_md5_hasher::md5;
+
+/// Files that are hashed to identify a build: the game's code, common fighter data,
+/// and the character select screen.
+const FINGERPRINT_FILES: [&str; 3] = ["Start.dol", "PlCo.dat", "MnSlChr.usd"];
+
+/// The table of builds that ships with Dolphin. Builds should only be added once their
+/// hashes have been verified against a known good copy.
+const BUNDLED_KNOWN_BUILDS: &str = include_str!("known_builds.json");
+
+/// A build of Melee that can be identified by its fingerprint.
+#[derive(Clone, Debug, Deserialize)]
+pub struct KnownBuild {
+    /// Short identifier that's sent along with game reports, e.g. `vanilla-1.02`
+    pub id: String,
+
+    /// Name that's logged, e.g. `Vanilla 1.02`
+    pub name: String,
+
+    /// MD5 hashes of the fingerprint files that identify this build, keyed by file
+    /// name. Files that aren't listed can be anything.
+    pub files: HashMap<String, String>,
+}
+
+/// MD5 hashes of the fingerprint files on a disc, keyed by file name. Files that
+/// aren't on the disc are left out.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Fingerprint(BTreeMap<String, String>);
+
+impl Fingerprint {
+    /// Hashes the fingerprint files on the disc at `iso_path`. Files are hashed a chunk at
+    /// a time, and a file that the FST places past the end of the disc fails the whole
+    /// fingerprint rather than being hashed short.
+    pub fn read(iso_path: &str) -> Result<Self, DiscError> {
+        let mut disc = DiscReader::open(iso_path)?;
+        let fst = FileSystemTable::read(&mut disc)?;
+
+        let mut hashes = BTreeMap::new();
+        for name in FINGERPRINT_FILES {
+            let file = match name {
+                "Start.dol" => Some(find_main_dol(&mut disc)?),
+                _ => fst.get(name),
+            };
+
+            if let Some(file) = file {
+                check_bounds(&mut disc, file.offset, file.length)?;
+                disc.seek(SeekFrom::Start(file.offset)).map_err(DiscError::IsoSeek)?;
+                let hash = md5(disc.by_ref().take(file.length as u64)).map_err(DiscError::IsoRead)?;
+                hashes.insert(name.to_string(), hash);
+            }
+        }
+
+        Ok(Self(hashes))
+    }
+
+    /// Finds the build that this fingerprint matches, if any. A build matches when all
+    /// of its files do. If several builds match, the one with the most files wins, as
+    /// it's the most specific.
+    pub fn identify<'a>(&self, builds: &'a [KnownBuild]) -> Option<&'a KnownBuild> {
+        builds
+            .iter()
+            .filter(|build| !build.files.is_empty())
+            .filter(|build| build.files.iter().all(|(name, hash)| self.0.get(name) == Some(hash)))
+            .max_by_key(|build| build.files.len())
+    }
+}
+
+/// The table of builds that ships with Dolphin.
+pub fn known_builds() -> Vec<KnownBuild> {
+    serde_json::from_str(BUNDLED_KNOWN_BUILDS).unwrap_or_else(|error| {
+        // This should never happen, and is covered by tests.
+        tracing::error!(target: Log::SlippiOnline, ?error, "Unable to parse bundled known builds");
+        Vec::new()
+    })
+}
+
+/// Fingerprints the ISO at `iso_path`, logs which build it is, and returns the id of the
+/// build. Unknown builds log their hashes instead, so that they can be added to the table.
+///
+/// The id isn't sent with game reports until the bundled table has verified builds in it,
+/// as until then every ISO is unknown.
+///
+/// Like the ISO hasher, this logs errors rather than letting anything panic.
+pub fn run(iso_path: &str) -> Option<String> {
+    run_with_builds(iso_path, &known_builds())
+}
+
+/// Same as `run`, but identifies the ISO from `known_builds` instead of the bundled table.
+fn run_with_builds(iso_path: &str, known_builds: &[KnownBuild]) -> Option<String> {
+    let fingerprint = match Fingerprint::read(iso_path) {
+        Ok(fingerprint) => fingerprint,
+
+        Err(error) => {
+            tracing::warn!(target: Log::SlippiOnline, ?error, "Unable to fingerprint ISO");
+
+            return None;
+        },
+    };
+
+    let Some(build) = fingerprint.identify(known_builds) else {
+        // Log the hashes, so that the build can be added to the table if need be.
+        tracing::info!(target: Log::SlippiOnline, fingerprint = ?fingerprint.0, "ISO is not a known build");
+
+        return None;
+    };
+
+    tracing::info!(target: Log::SlippiOnline, mod_id = build.id, "ISO is {}", build.name);
+
+    Some(build.id.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    /// Writes a copy of the tiny test disc to `folder` as `name`, with `edit` applied to it
+    fn edited_disc(folder: &Path, name: &str, edit: impl FnOnce(&mut Vec<u8>)) -> String {
+        let mut disc = std::fs::read("../disc/test-data/tiny-disc.iso").unwrap();
+        edit(&mut disc);
+
+        let path = folder.join(name);
+        std::fs::write(&path, disc).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Sets the length of the file at `offset` in the disc's FST
+    fn set_fst_length(disc: &mut [u8], offset: u64, length: u32) {
+        let fst_offset = u32::from_be_bytes(disc[0x424..0x428].try_into().unwrap()) as usize;
+        let entry = (fst_offset..disc.len())
+            .step_by(0xC)
+            .find(|&entry| disc[entry] == 0 && disc[entry + 4..entry + 8] == (offset as u32).to_be_bytes())
+            .unwrap();
+        disc[entry + 8..entry + 12].copy_from_slice(&length.to_be_bytes());
+    }
+
+    fn build(id: &str, files: &[(&str, &str)]) -> KnownBuild {
+        KnownBuild {
+            id: id.to_string(),
+            name: id.to_string(),
+            files: files
+                .iter()
+                .map(|(name, hash)| (name.to_string(), hash.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn bundles_a_valid_table() {
+        let known_builds: Vec<KnownBuild> = serde_json::from_str(BUNDLED_KNOWN_BUILDS).unwrap();
+        assert!(known_builds.iter().all(|build| !build.files.is_empty()));
+    }
+
+    #[test]
+    fn hashes_the_same_files_in_every_image_format() {
+        let fingerprint = Fingerprint::read("../disc/test-data/tiny-disc.iso").unwrap();
+        let names: Vec<&str> = fingerprint.0.keys().map(String::as_str).collect();
+        assert_eq!(names, ["MnSlChr.usd", "PlCo.dat", "Start.dol"]);
+
+        for image in ["../disc/test-data/tiny-disc.ciso", "../disc/test-data/tiny-disc.rvz"] {
+            assert_eq!(Fingerprint::read(image).unwrap(), fingerprint, "{image}");
+        }
+
+        assert!(Fingerprint::read("../disc/test-data/misow.bin").is_err());
+    }
+
+    #[test]
+    fn rejects_files_past_the_end_of_the_disc() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut disc = DiscReader::open("../disc/test-data/tiny-disc.iso").unwrap();
+        let plco = FileSystemTable::read(&mut disc).unwrap().get("PlCo.dat").unwrap();
+
+        let iso_path = edited_disc(folder.path(), "huge-plco.iso", |disc| {
+            set_fst_length(disc, plco.offset, 0xFFFF_FFFF)
+        });
+        assert!(matches!(
+            Fingerprint::read(&iso_path),
+            Err(DiscError::OutOfBounds(offset, 0xFFFF_FFFF)) if offset == plco.offset
+        ));
+    }
+
+    #[test]
+    fn identifies_the_most_specific_matching_build() {
+        let fingerprint = Fingerprint::read("../disc/test-data/tiny-disc.iso").unwrap();
+        let dol = fingerprint.0["Start.dol"].as_str();
+        let plco = fingerprint.0["PlCo.dat"].as_str();
+        let css = fingerprint.0["MnSlChr.usd"].as_str();
+
+        let builds = [
+            build("same-code", &[("Start.dol", dol)]),
+            build("everything", &[("Start.dol", dol), ("PlCo.dat", plco), ("MnSlChr.usd", css)]),
+            build(
+                "other-css",
+                &[("Start.dol", dol), ("MnSlChr.usd", "00000000000000000000000000000000")],
+            ),
+            build("nothing", &[]),
+        ];
+        assert_eq!(
+            fingerprint.identify(&builds).map(|build| build.id.as_str()),
+            Some("everything")
+        );
+        assert_eq!(
+            fingerprint.identify(&builds[..1]).map(|build| build.id.as_str()),
+            Some("same-code")
+        );
+        assert!(fingerprint.identify(&builds[2..]).is_none());
+    }
+
+    #[test]
+    fn identifies_each_build() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut disc = DiscReader::open("../disc/test-data/tiny-disc.iso").unwrap();
+        let fst = FileSystemTable::read(&mut disc).unwrap();
+        let files = [
+            ("Start.dol", find_main_dol(&mut disc).unwrap()),
+            ("PlCo.dat", fst.get("PlCo.dat").unwrap()),
+            ("MnSlChr.usd", fst.get("MnSlChr.usd").unwrap()),
+        ];
+
+        // Stand-ins for each build, which change a byte in the middle of some of the
+        // fingerprint files. Training Mode has the same code as UnclePunch, but its own CSS.
+        let builds = [
+            ("vanilla-1.02", &[][..]),
+            ("unclepunch", &["Start.dol"][..]),
+            ("20xx", &["PlCo.dat", "MnSlChr.usd"][..]),
+            ("training-mode", &["Start.dol", "MnSlChr.usd"][..]),
+        ];
+        let discs: Vec<(&str, String)> = builds
+            .iter()
+            .map(|&(id, changed)| {
+                let iso_path = edited_disc(folder.path(), &format!("{id}.iso"), |disc| {
+                    for (_, file) in files.iter().filter(|(name, _)| changed.contains(name)) {
+                        disc[file.offset as usize + file.length / 2] ^= 0xFF;
+                    }
+                });
+                (id, iso_path)
+            })
+            .collect();
+
+        // Each build is identified by the files that it changes, and vanilla by all of them.
+        // Training Mode also matches UnclePunch's code, but matches more files.
+        let known_builds: Vec<KnownBuild> = builds
+            .iter()
+            .zip(&discs)
+            .map(|(&(id, changed), (_, iso_path))| {
+                let disc = std::fs::read(iso_path).unwrap();
+                let hashes: Vec<(&str, String)> = files
+                    .iter()
+                    .filter(|(name, _)| id == "vanilla-1.02" || changed.contains(name))
+                    .map(|(name, file)| {
+                        let bytes = &disc[file.offset as usize..file.offset as usize + file.length];
+                        (*name, md5(bytes).unwrap())
+                    })
+                    .collect();
+                let hashes: Vec<(&str, &str)> = hashes.iter().map(|(name, hash)| (*name, hash.as_str())).collect();
+                build(id, &hashes)
+            })
+            .collect();
+
+        for (id, iso_path) in &discs {
+            assert_eq!(run_with_builds(iso_path, &known_builds).as_deref(), Some(*id), "{iso_path}");
+        }
+
+        // A mod that isn't in the table isn't mistaken for vanilla, or for a build that
+        // changes some of the same files
+        let unknown = edited_disc(folder.path(), "unknown.iso", |disc| {
+            let (_, plco) = files[1];
+            disc[plco.offset as usize] ^= 0xFF;
+        });
+        assert_eq!(run_with_builds(&unknown, &known_builds), None);
+    }
+
+    #[test]
+    fn doesnt_identify_unknown_builds() {
+        assert_eq!(run("../disc/test-data/tiny-disc.iso"), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Remembers the MD5 hashes of ISOs that have already been hashed, so that the (1GB+)
+//! ISO doesn't need to be read in full every time Dolphin starts.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use dolphin_integrations::Log;
+
+/// Identifies one particular version of a file. If any of these change, the file may
+/// have been replaced or modified and needs to be hashed again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FileIdentity {
+    size: u64,
+
+    /// Last modification time, in nanoseconds since the Unix epoch
+    modified: u64,
+
+    /// Always 0 on platforms without inodes
+    inode: u64,
+}
+
+impl FileIdentity {
+    /// Reads the identity of the file at `path`, along with its canonical path
+    fn read(path: &Path) -> std::io::Result<(String, Self)> {
+        let path = fs::canonicalize(path)?;
+        let metadata = fs::metadata(&path)?;
+
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as u64);
+
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        let identity = Self {
+            size: metadata.len(),
+            modified,
+            inode,
+        };
+
+        Ok((path.to_string_lossy().into_owned(), identity))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    #[serde(flatten)]
+    identity: FileIdentity,
+    hash: String,
+}
+
+/// ISO hashes keyed by the ISO's canonical path, stored as a json file in the user
+/// config folder. A hash is only returned while the ISO's size, modification time
+/// and inode are the same as when it was hashed.
+#[derive(Debug)]
+pub struct IsoHashCache {
+    path: PathBuf,
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl IsoHashCache {
+    /// Loads the cache stored at `path`. A missing or unreadable cache file is
+    /// treated as an empty cache.
+    pub fn load(path: PathBuf) -> Self {
+        let entries = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|error| {
+                tracing::warn!(target: Log::SlippiOnline, ?error, "Ignoring malformed ISO hash cache");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self { path, entries }
+    }
+
+    /// Returns the hash of the ISO at `iso_path`, if it hasn't changed since it was
+    /// last hashed.
+    pub fn get(&self, iso_path: &str) -> Option<String> {
+        let (key, identity) = FileIdentity::read(Path::new(iso_path)).ok()?;
+
+        self.entries
+            .get(&key)
+            .filter(|entry| entry.identity == identity)
+            .map(|entry| entry.hash.clone())
+    }
+
+    /// Remembers `hash` as the hash of the ISO at `iso_path` as it is right now, and
+    /// saves the cache. Failing to save just means the ISO is hashed again next time,
+    /// so errors are only logged.
+    pub fn insert(&mut self, iso_path: &str, hash: String) {
+        let (key, identity) = match FileIdentity::read(Path::new(iso_path)) {
+            Ok(identified) => identified,
+
+            Err(error) => {
+                tracing::warn!(target: Log::SlippiOnline, ?error, "Unable to read ISO metadata for the hash cache");
+
+                return;
+            },
+        };
+
+        self.entries.insert(key, CacheEntry { identity, hash });
+
+        let saved = serde_json::to_vec(&self.entries)
+            .map_err(std::io::Error::from)
+            .and_then(|json| fs::write(&self.path, json));
+
+        if let Err(error) = saved {
+            tracing::warn!(target: Log::SlippiOnline, ?error, "Unable to save the ISO hash cache");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    #[test]
+    fn remembers_hashes_across_launches() {
+        let folder = tempfile::tempdir().unwrap();
+        let iso = folder.path().join("melee.iso");
+        fs::write(&iso, b"GALE01").unwrap();
+        let iso = iso.to_str().unwrap();
+
+        let cache_path = folder.path().join("iso-hash-cache.json");
+        let mut cache = IsoHashCache::load(cache_path.clone());
+        assert_eq!(cache.get(iso), None);
+
+        cache.insert(iso, "abc123".to_string());
+        assert_eq!(cache.get(iso).as_deref(), Some("abc123"));
+
+        // The same file through a different path is the same ISO
+        let indirect = folder.path().join(".").join("melee.iso");
+        assert_eq!(cache.get(indirect.to_str().unwrap()).as_deref(), Some("abc123"));
+
+        let cache = IsoHashCache::load(cache_path);
+        assert_eq!(cache.get(iso).as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn forgets_hashes_of_isos_that_changed() {
+        let folder = tempfile::tempdir().unwrap();
+        let iso = folder.path().join("melee.iso");
+        fs::write(&iso, b"GALE01").unwrap();
+
+        let mut cache = IsoHashCache::load(folder.path().join("iso-hash-cache.json"));
+        cache.insert(iso.to_str().unwrap(), "abc123".to_string());
+
+        // Same size, different time
+        let file = File::options().write(true).open(&iso).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        assert_eq!(cache.get(iso.to_str().unwrap()), None);
+
+        // Different size
+        cache.insert(iso.to_str().unwrap(), "abc123".to_string());
+        fs::write(&iso, b"GALE01 but modded").unwrap();
+        assert_eq!(cache.get(iso.to_str().unwrap()), None);
+
+        assert_eq!(cache.get(folder.path().join("missing.iso").to_str().unwrap()), None);
+    }
+
+    #[test]
+    fn ignores_malformed_cache_files() {
+        let folder = tempfile::tempdir().unwrap();
+        let cache_path = folder.path().join("iso-hash-cache.json");
+        fs::write(&cache_path, b"{ not json").unwrap();
+
+        let iso = folder.path().join("melee.iso");
+        fs::write(&iso, b"GALE01").unwrap();
+
+        let mut cache = IsoHashCache::load(cache_path.clone());
+        assert_eq!(cache.get(iso.to_str().unwrap()), None);
+
+        cache.insert(iso.to_str().unwrap(), "abc123".to_string());
+        assert_eq!(
+            IsoHashCache::load(cache_path).get(iso.to_str().unwrap()).as_deref(),
+            Some("abc123")
+        );
+    }
+}
@@ -2,33 +2,40 @@
 //! be called from a background thread due to processing time.
 
 use std::fs::File;
+use std::io::{Read, Seek};
 use std::sync::{Arc, Mutex};
 
-use chksum::chksum;
 use chksum::hash::MD5;
 
 use dolphin_integrations::{Color, Dolphin, Duration, Log};
+use slippi_disc::{DiscError, DiscHeader, DiscReader};
 
-/// ISO hashes that are known to cause problems. We alert the player
-/// if we detect that they're running one.
-const KNOWN_DESYNC_ISOS: [&'static str; 4] = [
-    "23d6baef06bd65989585096915da20f2",
-    "27a5668769a54cd3515af47b8d9982f3",
-    "5805fa9f1407aedc8804d0472346fc5f",
-    "9bb3e275e77bb1a160276f2330f93931",
-];
+use crate::iso_hash_cache::IsoHashCache;
+use crate::known_desync_isos::{KnownDesyncIsos, KnownDesyncIsosSource};
+
+/// Size of the pieces that the ISO is read and hashed in
+const HASH_CHUNK_SIZE: usize = 1024 * 1024;
 
 /// Computes an MD5 hash of the ISO at `iso_path` and writes it back to the value
-/// behind `iso_hash`.
+/// behind `iso_hash`. ISOs that haven't changed since they were last hashed aren't
+/// hashed again; their hash comes from `cache` instead.
+///
+/// The hash is then checked against the ISOs that are known to cause problems (see
+/// `KnownDesyncIsos::load`), and we alert the player if they're running one.
 ///
 /// This function is currently more defensive than it probably needs to be, but while
 /// we move things into Rust I'd like to reduce the chances of anything panic'ing back
 /// into C++ since that can produce undefined behavior. This just handles every possible
 /// failure gracefully - however seemingly rare - and simply logs the error.
-pub fn run(iso_hash: Arc<Mutex<String>>, iso_path: String) {
-    let digest = match File::open(&iso_path) {
-        Ok(file) => match chksum::<MD5, _>(file) {
-            Ok(digest) => digest,
+pub fn run(iso_hash: Arc<Mutex<String>>, iso_path: String, mut cache: IsoHashCache, known_desync_isos: KnownDesyncIsosSource) {
+    let hash = match cache.get(&iso_path) {
+        Some(hash) => hash,
+
+        None => match hash_iso(&iso_path) {
+            Ok(hash) => {
+                cache.insert(&iso_path, hash.clone());
+                hash
+            },
 
             Err(error) => {
                 tracing::error!(target: Log::SlippiOnline, ?error, "Unable to produce ISO MD5 Hash");
@@ -36,24 +43,20 @@ pub fn run(iso_hash: Arc<Mutex<String>>, iso_path: String) {
                 return;
             },
         },
-
-        Err(error) => {
-            tracing::error!(target: Log::SlippiOnline, ?error, "Unable to open ISO for MD5 hashing");
-
-            return;
-        },
     };
 
-    let hash = format!("{:x}", digest);
+    // Fill in the hash before checking it, since the list may need to be fetched.
+    set_iso_hash(&iso_hash, hash.clone());
 
-    if !KNOWN_DESYNC_ISOS.contains(&hash.as_str()) {
-        tracing::info!(target: Log::SlippiOnline, iso_md5_hash = ?hash);
-    } else {
+    let known_desync_isos = KnownDesyncIsos::load(&known_desync_isos);
+
+    if let Some(reason) = known_desync_isos.reason(&hash) {
         // Dump it into the logs as well in case we're ever looking at a user's
         // logs - may end up being faster than trying to debug with them.
         tracing::warn!(
             target: Log::SlippiOnline,
             iso_md5_hash = ?hash,
+            reason,
             "Potential desync ISO detected"
         );
 
@@ -64,10 +67,15 @@ pub fn run(iso_hash: Arc<Mutex<String>>, iso_path: String) {
         Dolphin::add_osd_message(
             Color::Red,
             Duration::Custom(20000),
-            "\n\nCAUTION: You are using an ISO that is known to cause desyncs",
+            format!("\n\nCAUTION: You are using an ISO that is known to cause desyncs\n{reason}"),
         );
+    } else {
+        tracing::info!(target: Log::SlippiOnline, iso_md5_hash = ?hash);
     }
+}
 
+/// Writes `hash` back to the value behind `iso_hash`.
+pub fn set_iso_hash(iso_hash: &Mutex<String>, hash: String) {
     match iso_hash.lock() {
         Ok(mut iso_hash) => {
             *iso_hash = hash;
@@ -78,3 +86,115 @@ pub fn run(iso_hash: Arc<Mutex<String>>, iso_path: String) {
         },
     };
 }
+
+/// Computes the MD5 hash of the _logical_ disc in the image at `iso_path`, rather than of
+/// the file itself. This way a CISO (or otherwise compressed) copy of an ISO hashes the
+/// same as the plain ISO does, and known desync ISOs are caught in any format.
+///
+/// Files that aren't disc images we can read (e.g. a `.dol`) are hashed as is.
+fn hash_iso(iso_path: &str) -> Result<String, DiscError> {
+    let mut disc = match DiscReader::open(iso_path) {
+        Ok(disc) => disc,
+        Err(DiscError::UnsupportedIso) => return Ok(md5(File::open(iso_path)?)?),
+        Err(error) => return Err(error),
+    };
+
+    // Log which game (and which kind of image) the player is running, since that's
+    // handy when looking at a user's logs.
+    match DiscHeader::read(&mut disc) {
+        Ok(header) => tracing::info!(target: Log::SlippiOnline, kind = ?disc.kind(), "ISO is {header}"),
+        Err(error) => tracing::warn!(target: Log::SlippiOnline, ?error, "Unable to read the ISO's disc header"),
+    }
+
+    disc.rewind()?;
+    Ok(md5(disc)?)
+}
+
+/// Computes the MD5 hash of everything that can be read from `reader`
+pub(crate) fn md5<R: Read>(mut reader: R) -> std::io::Result<String> {
+    let mut hash = MD5::default();
+    let mut buf = vec![0; HASH_CHUNK_SIZE];
+
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            read => hash = hash.update(&buf[..read]),
+        }
+    }
+
+    Ok(format!("{:x}", hash.digest()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use slippi_gg_api::APIClient;
+
+    use super::*;
+
+    /// Only uses the bundled list of known desync ISOs
+    fn bundled_known_desync_isos(folder: &Path) -> KnownDesyncIsosSource {
+        KnownDesyncIsosSource {
+            api_client: APIClient::new("test"),
+            endpoint: None,
+            cache_path: folder.join("known-desync-isos.json"),
+        }
+    }
+
+    #[test]
+    fn every_image_of_a_disc_hashes_like_the_plain_iso() {
+        let iso_hash = md5(File::open("../disc/test-data/tiny-disc.iso").unwrap()).unwrap();
+
+        // The expanded ISO and its CISO are different files...
+        let ciso_file_hash = md5(File::open("../disc/test-data/tiny-disc.ciso").unwrap()).unwrap();
+        assert_ne!(ciso_file_hash, iso_hash);
+
+        // ...holding the same disc
+        for image in [
+            "../disc/test-data/tiny-disc.iso",
+            "../disc/test-data/tiny-disc.ciso",
+            "../disc/test-data/tiny-disc.gcz",
+            "../disc/test-data/tiny-disc.rvz",
+            "../disc/test-data/tiny-disc-lzma.wia",
+        ] {
+            assert_eq!(hash_iso(image).unwrap(), iso_hash, "{image}");
+        }
+
+        let folder = tempfile::tempdir().unwrap();
+        let iso_hash_setter = Arc::new(Mutex::new(String::new()));
+        let cache = IsoHashCache::load(folder.path().join("iso-hash-cache.json"));
+        let known_desync_isos = bundled_known_desync_isos(folder.path());
+        run(
+            iso_hash_setter.clone(),
+            "../disc/test-data/tiny-disc.ciso".to_string(),
+            cache,
+            known_desync_isos,
+        );
+        assert_eq!(*iso_hash_setter.lock().unwrap(), iso_hash);
+    }
+
+    #[test]
+    fn caches_hashes_between_runs() {
+        let folder = tempfile::tempdir().unwrap();
+        let cache_path = folder.path().join("iso-hash-cache.json");
+        let iso_path = "../disc/test-data/tiny-disc.gcz".to_string();
+
+        let iso_hash_setter = Arc::new(Mutex::new(String::new()));
+        run(
+            iso_hash_setter.clone(),
+            iso_path.clone(),
+            IsoHashCache::load(cache_path.clone()),
+            bundled_known_desync_isos(folder.path()),
+        );
+
+        let iso_hash = iso_hash_setter.lock().unwrap().clone();
+        assert_eq!(IsoHashCache::load(cache_path).get(&iso_path), Some(iso_hash));
+    }
+
+    #[test]
+    fn hashes_files_that_arent_disc_images_as_is() {
+        let path = "../disc/test-data/misow.bin";
+        assert_eq!(hash_iso(path).unwrap(), md5(File::open(path).unwrap()).unwrap());
+    }
+}
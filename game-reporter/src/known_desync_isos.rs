@@ -0,0 +1,275 @@
+//! The list of ISOs that are known to cause desyncs. A list ships with Dolphin, and can be
+//! added to without a new build by pointing the game reporter at an up to date list online.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use dolphin_integrations::Log;
+use slippi_gg_api::APIClient;
+
+/// The list that ships with Dolphin.
+const BUNDLED_KNOWN_DESYNC_ISOS: &str = include_str!("known_desync_isos.json");
+
+/// How long a fetched list is used for before it's fetched again.
+const FETCHED_LIST_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// An ISO that is known to cause desyncs.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownDesyncIso {
+    /// MD5 hash of the ISO, as computed by `iso_md5_hasher`
+    pub md5: String,
+
+    /// Why the ISO causes problems, shown to players running it
+    pub reason: String,
+}
+
+/// A fetched list, as cached in the user config folder.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedList {
+    /// When the list was fetched, in seconds since the Unix epoch
+    fetched_at: u64,
+    isos: Vec<KnownDesyncIso>,
+}
+
+impl CachedList {
+    fn is_fresh(&self) -> bool {
+        let fetched_at = UNIX_EPOCH + Duration::from_secs(self.fetched_at);
+
+        SystemTime::now()
+            .duration_since(fetched_at)
+            .is_ok_and(|age| age < FETCHED_LIST_MAX_AGE)
+    }
+}
+
+/// Where an up to date list of known desync ISOs comes from.
+#[derive(Clone, Debug)]
+pub struct KnownDesyncIsosSource {
+    pub api_client: APIClient,
+
+    /// URL of a json list of `KnownDesyncIso`s to merge with the bundled list. If
+    /// this is `None`, only the bundled list (and a previously fetched one) is used.
+    pub endpoint: Option<String>,
+
+    /// Where the fetched list is cached between launches.
+    pub cache_path: PathBuf,
+}
+
+/// Known desync ISOs, keyed by their MD5 hash.
+#[derive(Debug, Default)]
+pub struct KnownDesyncIsos {
+    isos: HashMap<String, String>,
+}
+
+impl KnownDesyncIsos {
+    /// Loads the bundled list, merged with the fetched one. The fetched list is cached,
+    /// and only fetched again once the cached one is a day old. If fetching fails, an
+    /// older cached list is used instead.
+    ///
+    /// This may make a network request, so should be called from a background thread.
+    pub fn load(source: &KnownDesyncIsosSource) -> Self {
+        let mut known_desync_isos = Self::bundled();
+
+        let cached = fs::read(&source.cache_path)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<CachedList>(&json).ok());
+
+        let fetched = match (cached, &source.endpoint) {
+            (Some(cached), _) if cached.is_fresh() => Some(cached.isos),
+
+            (cached, Some(endpoint)) => match fetch(&source.api_client, endpoint) {
+                Ok(isos) => {
+                    save(source, &isos);
+                    Some(isos)
+                },
+
+                Err(error) => {
+                    tracing::warn!(target: Log::SlippiOnline, ?error, "Unable to fetch known desync ISOs");
+                    cached.map(|cached| cached.isos)
+                },
+            },
+
+            (cached, None) => cached.map(|cached| cached.isos),
+        };
+
+        known_desync_isos.extend(fetched.unwrap_or_default());
+        known_desync_isos
+    }
+
+    /// The list that ships with Dolphin.
+    pub fn bundled() -> Self {
+        let mut known_desync_isos = Self::default();
+
+        match serde_json::from_str(BUNDLED_KNOWN_DESYNC_ISOS) {
+            Ok(isos) => known_desync_isos.extend(isos),
+
+            Err(error) => {
+                // This should never happen, and is covered by tests.
+                tracing::error!(target: Log::SlippiOnline, ?error, "Unable to parse bundled known desync ISOs");
+            },
+        }
+
+        known_desync_isos
+    }
+
+    /// Returns why the ISO with the provided MD5 hash causes desyncs, if it's known to.
+    pub fn reason(&self, md5: &str) -> Option<&str> {
+        self.isos.get(md5).map(String::as_str)
+    }
+
+    fn extend(&mut self, isos: Vec<KnownDesyncIso>) {
+        self.isos.extend(isos.into_iter().map(|iso| (iso.md5, iso.reason)));
+    }
+}
+
+/// Fetches the list at `endpoint`.
+fn fetch(api_client: &APIClient, endpoint: &str) -> Result<Vec<KnownDesyncIso>, Box<slippi_gg_api::Error>> {
+    let response = api_client.get(endpoint).call().map_err(Box::new)?;
+    response.into_json().map_err(|error| Box::new(error.into()))
+}
+
+/// Caches a freshly fetched list. Failing to do so just means it's fetched again next
+/// time, so errors are only logged.
+fn save(source: &KnownDesyncIsosSource, isos: &[KnownDesyncIso]) {
+    let fetched_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+
+    let cached = CachedList {
+        fetched_at,
+        isos: isos.to_vec(),
+    };
+
+    let saved = serde_json::to_vec(&cached)
+        .map_err(std::io::Error::from)
+        .and_then(|json| fs::write(&source.cache_path, json));
+
+    if let Err(error) = saved {
+        tracing::warn!(target: Log::SlippiOnline, ?error, "Unable to cache known desync ISOs");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    const BUNDLED_MD5: &str = "23d6baef06bd65989585096915da20f2";
+
+    /// Serves `body` as json to `requests` requests on a local port, returning the URL
+    /// to request and a handle that yields how many requests were served.
+    fn serve(body: &'static str, requests: usize) -> (String, JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/known-desync-isos.json", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let mut served = 0;
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+
+                // Read up to the blank line that ends the request headers
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+                served += 1;
+            }
+            served
+        });
+
+        (url, server)
+    }
+
+    fn source(endpoint: Option<String>, cache_path: PathBuf) -> KnownDesyncIsosSource {
+        KnownDesyncIsosSource {
+            api_client: APIClient::new("test"),
+            endpoint,
+            cache_path,
+        }
+    }
+
+    #[test]
+    fn bundles_a_list_with_reasons() {
+        let known_desync_isos = KnownDesyncIsos::bundled();
+        assert_eq!(known_desync_isos.isos.len(), 4);
+        assert!(known_desync_isos.reason(BUNDLED_MD5).is_some_and(|reason| !reason.is_empty()));
+        assert_eq!(known_desync_isos.reason("00000000000000000000000000000000"), None);
+    }
+
+    #[test]
+    fn merges_and_caches_the_fetched_list() {
+        let folder = tempfile::tempdir().unwrap();
+        let cache_path = folder.path().join("known-desync-isos.json");
+        let (url, server) = serve(r#"[{ "md5": "0123456789abcdef0123456789abcdef", "reason": "Test ISO" }]"#, 1);
+
+        let known_desync_isos = KnownDesyncIsos::load(&source(Some(url.clone()), cache_path.clone()));
+        assert_eq!(known_desync_isos.reason("0123456789abcdef0123456789abcdef"), Some("Test ISO"));
+        assert!(known_desync_isos.reason(BUNDLED_MD5).is_some());
+        assert_eq!(server.join().unwrap(), 1);
+
+        // The cached list is used without fetching it again, even though the server is gone
+        let known_desync_isos = KnownDesyncIsos::load(&source(Some(url), cache_path));
+        assert_eq!(known_desync_isos.reason("0123456789abcdef0123456789abcdef"), Some("Test ISO"));
+    }
+
+    #[test]
+    fn refreshes_expired_lists() {
+        let folder = tempfile::tempdir().unwrap();
+        let cache_path = folder.path().join("known-desync-isos.json");
+        fs::write(
+            &cache_path,
+            r#"{ "fetched_at": 0, "isos": [{ "md5": "0123456789abcdef0123456789abcdef", "reason": "Old reason" }] }"#,
+        )
+        .unwrap();
+
+        let (url, server) = serve(
+            r#"[{ "md5": "0123456789abcdef0123456789abcdef", "reason": "New reason" }]"#,
+            1,
+        );
+        let known_desync_isos = KnownDesyncIsos::load(&source(Some(url), cache_path.clone()));
+        assert_eq!(
+            known_desync_isos.reason("0123456789abcdef0123456789abcdef"),
+            Some("New reason")
+        );
+        assert_eq!(server.join().unwrap(), 1);
+
+        let cached: CachedList = serde_json::from_slice(&fs::read(&cache_path).unwrap()).unwrap();
+        assert!(cached.is_fresh());
+    }
+
+    #[test]
+    fn falls_back_to_older_lists_when_fetching_fails() {
+        let folder = tempfile::tempdir().unwrap();
+        let cache_path = folder.path().join("known-desync-isos.json");
+        fs::write(
+            &cache_path,
+            r#"{ "fetched_at": 0, "isos": [{ "md5": "0123456789abcdef0123456789abcdef", "reason": "Old reason" }] }"#,
+        )
+        .unwrap();
+
+        let (url, server) = serve("not json", 1);
+        let known_desync_isos = KnownDesyncIsos::load(&source(Some(url), cache_path));
+        assert_eq!(
+            known_desync_isos.reason("0123456789abcdef0123456789abcdef"),
+            Some("Old reason")
+        );
+        assert!(known_desync_isos.reason(BUNDLED_MD5).is_some());
+        assert_eq!(server.join().unwrap(), 1);
+
+        let known_desync_isos = KnownDesyncIsos::load(&source(None, folder.path().join("missing.json")));
+        assert_eq!(known_desync_isos.isos.len(), 4);
+    }
+}
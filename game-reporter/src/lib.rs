@@ -2,6 +2,7 @@
 //! not to rewrite the universe.
 
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::mpsc::{self, Sender};
@@ -11,14 +12,29 @@ use dolphin_integrations::Log;
 use slippi_gg_api::APIClient;
 use slippi_user::UserManager;
 
+mod iso_fingerprint;
+
+mod iso_hash_cache;
+use iso_hash_cache::IsoHashCache;
+
 mod iso_md5_hasher;
 
+mod known_desync_isos;
+use known_desync_isos::KnownDesyncIsosSource;
+
 mod queue;
 use queue::GameReporterQueue;
 
 mod types;
 pub use types::{GameReport, OnlinePlayMode, PlayerReport};
 
+/// Name of the file in the user config folder that ISO hashes are cached in.
+const ISO_HASH_CACHE_FILE: &str = "iso-hash-cache.json";
+
+/// Name of the file in the user config folder that the fetched list of known desync
+/// ISOs is cached in.
+const KNOWN_DESYNC_ISOS_CACHE_FILE: &str = "known-desync-isos.json";
+
 /// Events that we dispatch into the processing thread.
 #[derive(Copy, Clone, Debug)]
 pub(crate) enum ProcessingEvent {
@@ -67,17 +83,44 @@ impl GameReporter {
     ///
     /// Currently, failure to spawn any thread should result in a crash - i.e, if we can't
     /// spawn an OS thread, then there are probably far bigger issues at work here.
-    pub fn new(api_client: APIClient, user_manager: UserManager, iso_path: String) -> Self {
+    ///
+    /// ISO hashes are cached in `user_config_folder`, so that the ISO only needs to be
+    /// hashed again when it changes. If `known_desync_isos_url` is set, the list of ISOs
+    /// known to cause desyncs is fetched from it (and cached in the same folder) in
+    /// addition to the list bundled with Dolphin.
+    pub fn new(
+        api_client: APIClient,
+        user_manager: UserManager,
+        iso_path: String,
+        user_config_folder: PathBuf,
+        known_desync_isos_url: Option<String>,
+    ) -> Self {
         let queue = GameReporterQueue::new(api_client.clone());
 
         // This is a thread-safe "one time" setter that the MD5 hasher thread
         // will set when it's done computing.
         let iso_hash_setter = queue.iso_hash.clone();
 
+        // Hashing the ISO takes a while, so if it's been hashed before, fill in its hash
+        // right away. Reports sent before the hasher thread finishes then still have it.
+        let iso_hash_cache = IsoHashCache::load(user_config_folder.join(ISO_HASH_CACHE_FILE));
+        if let Some(hash) = iso_hash_cache.get(&iso_path) {
+            iso_md5_hasher::set_iso_hash(&queue.iso_hash, hash);
+        }
+
+        let known_desync_isos = KnownDesyncIsosSource {
+            api_client: api_client.clone(),
+            endpoint: known_desync_isos_url,
+            cache_path: user_config_folder.join(KNOWN_DESYNC_ISOS_CACHE_FILE),
+        };
+
         let iso_md5_hasher_thread = thread::Builder::new()
             .name("GameReporterISOHasherThread".into())
             .spawn(move || {
-                iso_md5_hasher::run(iso_hash_setter, iso_path);
+                // The hash (and the desync warning that comes with it) matters more than
+                // which build the ISO is, so fingerprinting waits for it.
+                iso_md5_hasher::run(iso_hash_setter, iso_path.clone(), iso_hash_cache, known_desync_isos);
+                iso_fingerprint::run(&iso_path);
             })
             .expect("Failed to spawn GameReporterISOHasherThread.");
 
@@ -0,0 +1,196 @@
+use std::sync::mpsc::{Receiver, TryRecvError, sync_channel};
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::{JukeboxError::*, Result};
+
+/// Number of sample frames that the decoding thread hands over at a time
+const CHUNK_FRAMES: usize = 2048;
+
+/// Number of decoded chunks that can be waiting to play. For songs on the disc
+/// (32kHz) this is about a second of audio.
+const BUFFERED_CHUNKS: usize = 16;
+
+/// Plays a song that is decoded on its own thread. The thread stays up to
+/// `BUFFERED_CHUNKS` chunks ahead of playback, so reading the disc (which can
+/// mean waiting on the disc lock, or decompressing a whole group of a
+/// compressed image) and decoding never happen on the audio device's thread,
+/// which only takes chunks that are ready. If decoding falls behind, silence
+/// plays until it catches up.
+///
+/// The thread stops once the song ends, or once this source is dropped.
+pub(crate) struct BufferedSource {
+    rx: Receiver<Vec<i16>>,
+    channels: u16,
+    sample_rate: u32,
+
+    /// Interleaved samples of the chunk that is playing
+    chunk: Vec<i16>,
+    position: usize,
+
+    /// Samples of silence left to play, so that a whole frame of silence is
+    /// played when decoding falls behind
+    silence: usize,
+}
+
+impl BufferedSource {
+    /// Starts decoding `song` on its own thread. This waits for the first chunk,
+    /// so that the song starts with audio rather than silence.
+    pub(crate) fn new<S>(song: S) -> Result<Self>
+    where
+        S: Source<Item = i16> + Send + 'static,
+    {
+        let channels = song.channels().max(1);
+        let sample_rate = song.sample_rate();
+        let (tx, rx) = sync_channel(BUFFERED_CHUNKS);
+
+        std::thread::Builder::new()
+            .name("SlippiJukeboxDecode".to_string())
+            .spawn(move || {
+                let mut song = song;
+                loop {
+                    let chunk: Vec<i16> = song.by_ref().take(CHUNK_FRAMES * channels as usize).collect();
+
+                    // Sending fails once the source has been dropped
+                    if chunk.is_empty() || tx.send(chunk).is_err() {
+                        break;
+                    }
+                }
+            })
+            .map_err(ThreadSpawn)?;
+
+        // An empty song has no chunks at all
+        let chunk = rx.recv().unwrap_or_default();
+
+        Ok(Self {
+            rx,
+            channels,
+            sample_rate,
+            chunk,
+            position: 0,
+            silence: 0,
+        })
+    }
+}
+
+impl Iterator for BufferedSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.silence > 0 {
+            self.silence -= 1;
+            return Some(0);
+        }
+
+        // Chunks are always whole frames, so switching chunks (or playing
+        // silence) never happens in the middle of a frame
+        if self.position >= self.chunk.len() {
+            match self.rx.try_recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                },
+                Err(TryRecvError::Empty) => {
+                    self.silence = self.channels as usize - 1;
+                    return Some(0);
+                },
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+
+        let sample = self.chunk[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for BufferedSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{Sender, channel};
+
+    use super::*;
+
+    /// A stereo song that only has the samples that the test has sent it so
+    /// far, and ends once the sender is dropped
+    struct GatedSong(Receiver<i16>);
+
+    impl Iterator for GatedSong {
+        type Item = i16;
+
+        fn next(&mut self) -> Option<i16> {
+            self.0.recv().ok()
+        }
+    }
+
+    impl Source for GatedSong {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            2
+        }
+
+        fn sample_rate(&self) -> u32 {
+            32000
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    const CHUNK: i16 = (CHUNK_FRAMES * 2) as i16;
+
+    fn send_chunk(tx: &Sender<i16>) {
+        (1..=CHUNK).for_each(|sample| tx.send(sample).unwrap());
+    }
+
+    #[test]
+    fn plays_silence_while_decoding_catches_up() {
+        let (tx, rx) = channel();
+        send_chunk(&tx);
+        let mut source = BufferedSource::new(GatedSong(rx)).unwrap();
+        assert_eq!((source.channels(), source.sample_rate()), (2, 32000));
+        assert!(source.by_ref().take(CHUNK as usize).eq(1..=CHUNK));
+
+        // Nothing else has been decoded yet, so a frame of silence plays
+        assert_eq!(source.by_ref().take(2).collect::<Vec<_>>(), [0, 0]);
+
+        // Then the next chunk plays once it's ready, after whole frames of
+        // silence, and the song ends after it
+        send_chunk(&tx);
+        drop(tx);
+        let rest: Vec<i16> = source.collect();
+        let silence = rest.iter().take_while(|&&sample| sample == 0).count();
+        assert_eq!(silence % 2, 0);
+        assert!(rest[silence..silence + CHUNK as usize].iter().copied().eq(1..=CHUNK));
+        assert!(rest[silence + CHUNK as usize..].iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn ends_empty_songs() {
+        let (_, rx) = channel();
+        let mut source = BufferedSource::new(GatedSong(rx)).unwrap();
+        assert_eq!(source.next(), None);
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Default amount of memory that decoded songs may use, in bytes. Songs on the
+/// disc are 32kHz stereo, so a three minute song takes up about 23MB and this
+/// keeps the last couple of songs around.
+pub const DEFAULT_SONG_CACHE_SIZE: usize = 48 * 1024 * 1024;
+
+/// Songs that are worth decoding in the background when jukebox starts, for
+/// machines with memory to spare: the main menu, and the music of the stages
+/// that are played the most. Nothing is pre-decoded unless
+/// `Config::predecode_songs` is set, e.g. to this list.
+pub const SUGGESTED_PREDECODE_SONGS: [&str; 7] = [
+    "menu01.hps",
+    "izumi.hps",
+    "pstadium.hps",
+    "old_ys.hps",
+    "old_kb.hps",
+    "sp_zako.hps",
+    "sp_end.hps",
+];
+
+/// Default time it takes for music to fade out when it's stopped
+pub const DEFAULT_FADE_OUT: Duration = Duration::from_millis(500);
+
+/// Default time that two songs overlap for when switching between them. Songs
+/// are switched instantly by default.
+pub const DEFAULT_CROSSFADE: Duration = Duration::ZERO;
+
+/// Default loudness that songs are brought to when loudness normalization is
+/// on, in LUFS. This is the same reference level that ReplayGain 2.0 uses.
+pub const DEFAULT_TARGET_LOUDNESS: f64 = -18.0;
+
+/// Where jukebox sends the music it plays
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AudioBackend {
+    /// An audio device on this machine (see `Config::output_device`)
+    #[default]
+    Device,
+
+    /// Plays music in real time without any sound coming out, for machines that
+    /// don't have an audio device
+    Null,
+
+    /// Like `Null`, but writes everything that plays to a 16 bit stereo WAV
+    /// file at this path. The file is complete once jukebox is dropped.
+    Capture(PathBuf),
+}
+
+/// Everything needed to start Slippi Jukebox.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Path to the game's disc image
+    pub iso_path: String,
+
+    /// Audio files in here that are named after a song on the disc (e.g.
+    /// `izumi.ogg` for `audio/izumi.hps`) are played in place of that song.
+    /// Only the disc's music is played if this is `None`.
+    pub music_folder: Option<PathBuf>,
+
+    pub initial_dolphin_system_volume: u8,
+    pub initial_dolphin_music_volume: u8,
+
+    /// Maximum amount of memory that decoded songs are allowed to use, in
+    /// bytes. Songs that were played recently are kept in memory so that they
+    /// can be played again without decoding them again.
+    pub song_cache_size: usize,
+
+    /// File names of songs to decode in the background once jukebox starts, so
+    /// that they can be played right away. This is empty (and off) by default,
+    /// as the decoded songs stay in memory. See `SUGGESTED_PREDECODE_SONGS`.
+    pub predecode_songs: Vec<String>,
+
+    /// How long music takes to fade out when it's stopped. Zero stops it
+    /// instantly.
+    pub fade_out: Duration,
+
+    /// How long the previous song fades out for while the next one fades in.
+    /// Zero switches songs instantly.
+    pub crossfade: Duration,
+
+    /// Name of the audio device to play music on. When this is `None` (or the
+    /// device can't be found), music plays on the system's default device and
+    /// follows it when it changes.
+    pub output_device: Option<String>,
+
+    /// Where music is played
+    pub audio_backend: AudioBackend,
+
+    /// Name of a second audio device that music is also played on, e.g. a
+    /// virtual cable that a stream picks up. It gets the same music at the
+    /// same volume as the main output, with `initial_mirror_volume` applied on
+    /// top.
+    pub mirror_output_device: Option<String>,
+    pub initial_mirror_volume: u8,
+
+    /// Whether music speeds up and slows down (changing pitch) along with the
+    /// emulator, so that it stays in step with the game. Music always pauses
+    /// when the emulator does.
+    pub follow_emulation_speed: bool,
+
+    /// Shifts music toward the left (-1.0) or right (1.0) speaker. At 0.0,
+    /// the default, both speakers play at full volume.
+    pub balance: f32,
+
+    /// Whether each song's volume is adjusted so that it plays at
+    /// `target_loudness`. Songs are measured the first time they play, so the
+    /// adjustment starts from the second time a song plays.
+    pub normalize_loudness: bool,
+
+    /// Loudness that songs are brought to, in LUFS
+    pub target_loudness: f64,
+
+    /// File that song loudness measurements are saved to, so that songs only
+    /// need to be measured once. Measurements are only kept in memory if this
+    /// is `None`.
+    pub loudness_cache_path: Option<PathBuf>,
+
+    /// File that the result of checking the ISO's music is saved to, so that
+    /// the ISO is only scanned again once it changes. The ISO is scanned every
+    /// time jukebox starts if this is `None`.
+    pub scan_cache_path: Option<PathBuf>,
+}
+
+impl Config {
+    /// Creates a config with the default song cache, fade, output and loudness
+    /// settings, and without pre-decoding
+    pub fn new(
+        iso_path: String,
+        music_folder: Option<PathBuf>,
+        initial_dolphin_system_volume: u8,
+        initial_dolphin_music_volume: u8,
+    ) -> Self {
+        Self {
+            iso_path,
+            music_folder,
+            initial_dolphin_system_volume,
+            initial_dolphin_music_volume,
+            song_cache_size: DEFAULT_SONG_CACHE_SIZE,
+            predecode_songs: Vec::new(),
+            fade_out: DEFAULT_FADE_OUT,
+            crossfade: DEFAULT_CROSSFADE,
+            output_device: None,
+            audio_backend: AudioBackend::Device,
+            mirror_output_device: None,
+            initial_mirror_volume: 100,
+            follow_emulation_speed: true,
+            balance: 0.0,
+            normalize_loudness: false,
+            target_loudness: DEFAULT_TARGET_LOUDNESS,
+            loudness_cache_path: None,
+            scan_cache_path: None,
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use std::path::Path;
+
+use dolphin_integrations::{Color, Dolphin, Duration as OSDDuration, Log};
+use hps_decode::Hps;
+use slippi_disc::{DiscHeader, DiscReader, FileSystemTable, copy_bytes};
+
+use crate::iso_identity::IsoIdentity;
+use crate::looping_source::LoopPoints;
+use crate::song_cache::{decode_hps, hps_frame_count, hps_loop_start};
+use crate::{JukeboxError, JukeboxError::*, Result};
+
+/// An hps file on a disc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscTrack {
+    /// Path of the file on the disc, e.g. `audio/menu01.hps`
+    pub path: String,
+    pub offset: u64,
+    pub length: usize,
+}
+
+/// The format of a track's audio, read from its hps file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+
+    /// Length of one pass through the track, in sample frames
+    pub frames: u64,
+
+    /// Sample frame that the track loops back to once it ends, if it loops
+    pub loop_start: Option<u64>,
+}
+
+/// Number of coefficient pairs that a DSP-ADPCM frame header can pick from
+const COEFFICIENT_PAIRS_PER_CHANNEL: u8 = 8;
+
+/// What `DiscMusic::scan` found
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// Number of tracks that were checked
+    pub tracks: usize,
+
+    /// Tracks that can't be played, and why
+    pub problems: Vec<(DiscTrack, JukeboxError)>,
+}
+
+/// The music on a disc image, for inspecting and exporting it outside of the
+/// emulator
+pub struct DiscMusic {
+    disc: DiscReader,
+    fst: FileSystemTable,
+}
+
+impl DiscMusic {
+    /// Opens the disc image at `iso_path`, in any format that jukebox can play
+    /// music from
+    pub fn open(iso_path: &str) -> Result<Self> {
+        let mut disc = DiscReader::open(iso_path)?;
+        let fst = FileSystemTable::read(&mut disc)?;
+        Ok(Self { disc, fst })
+    }
+
+    /// Reads which game (and which revision of it) is on the disc
+    pub fn header(&mut self) -> Result<DiscHeader> {
+        Ok(DiscHeader::read(&mut self.disc)?)
+    }
+
+    /// Every hps file on the disc, sorted by path
+    pub fn tracks(&self) -> Vec<DiscTrack> {
+        let mut tracks: Vec<DiscTrack> = self
+            .fst
+            .files()
+            .filter(|(path, _)| path.to_ascii_lowercase().ends_with(".hps"))
+            .map(|(path, file)| DiscTrack {
+                path: path.to_string(),
+                offset: file.offset,
+                length: file.length,
+            })
+            .collect();
+
+        tracks.sort_by(|a, b| a.path.cmp(&b.path));
+        tracks
+    }
+
+    /// Finds a track by its full path on the disc (`audio/izumi.hps`), or by
+    /// just its file name (`izumi.hps`)
+    pub fn find(&self, name: &str) -> Option<DiscTrack> {
+        let file = self.fst.get(name)?;
+        let path = self.fst.path_at(file.offset)?.to_string();
+        Some(DiscTrack {
+            path,
+            offset: file.offset,
+            length: file.length,
+        })
+    }
+
+    /// Reads the format of `track` from its hps file, without decoding it
+    pub fn info(&mut self, track: &DiscTrack) -> Result<TrackInfo> {
+        let hps = self.read_hps(track)?;
+        Ok(TrackInfo {
+            sample_rate: hps.sample_rate,
+            channels: hps.channel_count as u16,
+            frames: hps_frame_count(&hps),
+            loop_start: hps_loop_start(&hps),
+        })
+    }
+
+    /// Decodes one pass through `track` and writes it to a 16 bit WAV file at
+    /// `wav_path`. Tracks that loop also get a json file next to it with their
+    /// loop point, so that the WAV can be used as a music override as is.
+    pub fn export_wav(&mut self, track: &DiscTrack, wav_path: &Path) -> Result<()> {
+        let song = decode_hps(&mut self.disc, track.offset, track.length)?;
+
+        let spec = hound::WavSpec {
+            channels: song.channels(),
+            sample_rate: song.sample_rate(),
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(wav_path, spec)?;
+        for &sample in song.samples() {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()?;
+
+        if let Some(start) = song.loop_start() {
+            let loop_points = LoopPoints { start, length: None };
+            let json = serde_json::to_string(&loop_points).map_err(std::io::Error::from)?;
+            std::fs::write(wav_path.with_extension("json"), json)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every track on the disc can be played, without decoding
+    /// them: each hps must be on the disc, its header and chain of blocks must
+    /// parse, and every frame must refer to a coefficient pair that exists.
+    pub fn scan(&mut self) -> ScanReport {
+        let tracks = self.tracks();
+        let problems = tracks
+            .iter()
+            .filter_map(|track| self.check(track).err().map(|e| (track.clone(), e)))
+            .collect();
+
+        ScanReport {
+            tracks: tracks.len(),
+            problems,
+        }
+    }
+
+    fn check(&mut self, track: &DiscTrack) -> Result<()> {
+        if track.offset.saturating_add(track.length as u64) > self.disc.size() {
+            return Err(InvalidHps("The file extends past the end of the disc".to_string()));
+        }
+
+        // This is the only thing that makes decoding fail once an hps parses
+        let hps = self.read_hps(track)?;
+        let frames = hps.blocks.iter().flat_map(|block| &block.frames);
+        match frames
+            .map(|frame| frame.header >> 4)
+            .find(|&index| index >= COEFFICIENT_PAIRS_PER_CHANNEL)
+        {
+            Some(index) => Err(HpsDecode(format!("Invalid coefficient index {index}"))),
+            None => Ok(()),
+        }
+    }
+
+    fn read_hps(&mut self, track: &DiscTrack) -> Result<Hps> {
+        let hps_bytes = copy_bytes(&mut self.disc, track.offset, track.length)?;
+        hps_bytes.try_into().map_err(|e| InvalidHps(format!("{e:?}")))
+    }
+}
+
+/// The result of scanning the music on one ISO, which is saved so that the
+/// same ISO isn't scanned again every time jukebox starts
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ScanSummary {
+    /// `IsoIdentity` of the ISO that was scanned
+    iso: String,
+
+    /// Number of tracks that were checked
+    pub(crate) tracks: usize,
+
+    /// Tracks that can't be played, and why
+    pub(crate) problems: Vec<ScanProblem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ScanProblem {
+    pub(crate) path: String,
+    pub(crate) offset: u64,
+    pub(crate) error: String,
+}
+
+impl ScanSummary {
+    fn new(iso: &IsoIdentity, report: &ScanReport) -> Self {
+        Self {
+            iso: iso.as_str().to_string(),
+            tracks: report.tracks,
+            problems: report
+                .problems
+                .iter()
+                .map(|(track, e)| ScanProblem {
+                    path: track.path.clone(),
+                    offset: track.offset,
+                    error: e.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Loads the summary saved at `path`, if it's for the ISO `iso`
+    fn load(path: &Path, iso: &IsoIdentity) -> Option<Self> {
+        if !path.is_file() {
+            return None;
+        }
+
+        match std::fs::read_to_string(path).map(|json| serde_json::from_str::<Self>(&json)) {
+            Ok(Ok(summary)) => Some(summary).filter(|summary| summary.iso == iso.as_str()),
+            Ok(Err(e)) => {
+                tracing::warn!(target: Log::Jukebox, error = ?e, "Invalid scan cache at {}", path.display());
+                None
+            },
+            Err(e) => {
+                tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to read scan cache at {}", path.display());
+                None
+            },
+        }
+    }
+
+    fn save(&self, path: &Path) {
+        let saved = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(path, serde_json::to_string(self)?));
+        if let Err(e) = saved {
+            tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to save scan cache to {}", path.display());
+        }
+    }
+}
+
+/// Scans the music on the disc at `iso_path` (see `DiscMusic::scan`) and
+/// reports any tracks that can't be played, so that users with modified ISOs
+/// find out when jukebox starts rather than in the middle of a match.
+///
+/// If `cache_path` has the result of scanning this same ISO (`iso`), that
+/// result is reported instead of scanning it again. Otherwise the new result is
+/// saved there, replacing the result for any other ISO.
+pub(crate) fn scan_disc_music(iso_path: &str, iso: &IsoIdentity, cache_path: Option<&Path>) -> Result<ScanSummary> {
+    let summary = match cache_path.and_then(|path| ScanSummary::load(path, iso)) {
+        Some(summary) => summary,
+        None => {
+            let summary = ScanSummary::new(iso, &DiscMusic::open(iso_path)?.scan());
+            if let Some(path) = cache_path {
+                summary.save(path);
+            }
+            summary
+        },
+    };
+
+    for problem in &summary.problems {
+        tracing::warn!(
+            target: Log::Jukebox,
+            "{} at 0x{:x} can't be played: {}",
+            problem.path,
+            problem.offset,
+            problem.error
+        );
+    }
+
+    match summary.problems.len() {
+        0 => tracing::info!(target: Log::Jukebox, "Scanned {} songs on the ISO. All of them can be played.", summary.tracks),
+        count => {
+            tracing::warn!(target: Log::Jukebox, "Scanned {} songs on the ISO. {count} of them can't be played.", summary.tracks);
+            Dolphin::add_osd_message(
+                Color::Yellow,
+                OSDDuration::VeryLong,
+                format!("\n{count} of the songs in your ISO have invalid music data. They will not play."),
+            );
+        },
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::music_override::{open_override, read_loop_points};
+
+    use super::*;
+
+    #[test]
+    fn lists_the_music_on_the_disc() {
+        let music = DiscMusic::open("../disc/test-data/tiny-disc.rvz").unwrap();
+        let paths: Vec<String> = music.tracks().into_iter().map(|track| track.path).collect();
+        assert_eq!(paths, ["audio/broken.hps", "audio/izumi.hps", "audio/menu01.hps"]);
+
+        let track = music.find("MENU01.hps").unwrap();
+        assert_eq!(
+            (track.path.as_str(), track.offset, track.length),
+            ("audio/menu01.hps", 0x13F00, 0x500)
+        );
+        assert!(music.find("mutecity.hps").is_none());
+    }
+
+    #[test]
+    fn reads_the_format_of_tracks() {
+        let mut music = DiscMusic::open("../disc/test-data/tiny-disc.iso").unwrap();
+
+        let menu = music.find("menu01.hps").unwrap();
+        let info = music.info(&menu).unwrap();
+        assert_eq!((info.sample_rate, info.channels), (32000, 2));
+        assert!(info.loop_start.is_some_and(|start| start > 0 && start < info.frames));
+
+        let izumi = music.find("izumi.hps").unwrap();
+        assert_eq!(music.info(&izumi).unwrap().loop_start, None);
+
+        let broken = music.find("broken.hps").unwrap();
+        assert!(matches!(music.info(&broken), Err(InvalidHps(_))));
+    }
+
+    #[test]
+    fn finds_tracks_that_cant_be_played() {
+        let report = DiscMusic::open("../disc/test-data/tiny-disc.ciso").unwrap().scan();
+        assert_eq!(report.tracks, 3);
+        assert_eq!(report.problems.len(), 1);
+
+        let (track, e) = &report.problems[0];
+        assert_eq!(track.path, "audio/broken.hps");
+        assert!(matches!(e, InvalidHps(_)));
+    }
+
+    #[test]
+    fn only_scans_each_iso_once() {
+        let folder = tempfile::tempdir().unwrap();
+        let cache_path = folder.path().join("Jukebox").join("disc-scan.json");
+        let iso = IsoIdentity::for_tests("GALE01-2-57058000-1");
+
+        let summary = scan_disc_music("../disc/test-data/tiny-disc.ciso", &iso, Some(&cache_path)).unwrap();
+        assert_eq!(summary.tracks, 3);
+        assert_eq!(summary.problems.len(), 1);
+        assert_eq!(summary.problems[0].path, "audio/broken.hps");
+
+        // The saved result is used as long as the ISO is the same one, even
+        // though the path no longer leads to it
+        let cached = scan_disc_music("missing.iso", &iso, Some(&cache_path)).unwrap();
+        assert_eq!(cached, summary);
+
+        // A different ISO is scanned, and its result replaces the old one
+        let other_iso = IsoIdentity::for_tests("GALE01-2-57058000-2");
+        assert!(scan_disc_music("missing.iso", &other_iso, Some(&cache_path)).is_err());
+        let rescanned = scan_disc_music("../disc/test-data/tiny-disc.iso", &other_iso, Some(&cache_path)).unwrap();
+        assert_eq!((rescanned.tracks, rescanned.problems.len()), (3, 1));
+        assert!(scan_disc_music("missing.iso", &iso, Some(&cache_path)).is_err());
+    }
+
+    #[test]
+    fn exports_tracks_that_play_like_the_disc() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut music = DiscMusic::open("../disc/test-data/tiny-disc.iso").unwrap();
+
+        for name in ["menu01.hps", "izumi.hps"] {
+            let track = music.find(name).unwrap();
+            let info = music.info(&track).unwrap();
+            let wav = folder.path().join(name).with_extension("wav");
+            music.export_wav(&track, &wav).unwrap();
+
+            let song = decode_hps(&mut music.disc, track.offset, track.length).unwrap();
+            let exported: Vec<i16> = open_override(&wav).unwrap().take(song.samples().len()).collect();
+            assert_eq!(exported, song.samples());
+            assert_eq!(read_loop_points(&wav).map(|loop_points| loop_points.start), info.loop_start);
+        }
+    }
+}
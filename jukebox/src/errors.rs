@@ -1,3 +1,4 @@
+use slippi_disc::DiscError;
 use thiserror::Error;
 
 use crate::Message;
@@ -22,15 +23,79 @@ pub enum JukeboxError {
     #[error("Unable to play sound with rodio: {0}")]
     AudioPlayback(#[from] rodio::PlayError),
 
-    #[error("Failed to seek the ISO: {0}")]
-    IsoSeek(std::io::Error),
+    #[error("Unable to write captured audio: {0}")]
+    AudioCapture(#[from] hound::Error),
 
-    #[error("Failed to read the ISO: {0}")]
-    IsoRead(std::io::Error),
+    #[error("Unable to decode audio file: {0}")]
+    AudioDecode(#[from] rodio::decoder::DecoderError),
 
-    #[error("The provided game file is not supported")]
-    UnsupportedIso,
+    #[error("{0}")]
+    Disc(#[from] DiscError),
+
+    #[error("Failed to parse bytes into an Hps: {0}")]
+    InvalidHps(String),
+
+    #[error("Failed to decode hps into audio: {0}")]
+    HpsDecode(String),
+
+    #[error("{0} is not supported. Slippi Jukebox only plays music for NTSC Melee 1.02 (GALE01 revision 2).")]
+    UnsupportedGame(crate::DiscHeader),
 
     #[error("Unknown Jukebox Error")]
     Unknown,
 }
+
+/// Broad categories of `JukeboxError`, for reporting why jukebox isn't playing
+/// music without needing the details
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JukeboxErrorKind {
+    /// The ISO isn't a disc image that jukebox can read
+    UnsupportedIso,
+
+    /// The disc isn't a revision of Melee that jukebox supports
+    UnsupportedGame,
+
+    /// There's no audio device to play music on, or it couldn't be opened
+    AudioDevice,
+
+    /// Music couldn't be captured to a file
+    AudioCapture,
+
+    /// The disc image couldn't be read, or is corrupt
+    DiscRead,
+
+    /// A song's audio couldn't be decoded
+    InvalidMusic,
+
+    /// A file couldn't be opened or read
+    Io,
+
+    /// One of jukebox's threads couldn't be started
+    ThreadSpawn,
+
+    /// The player thread stopped unexpectedly
+    ThreadStopped,
+
+    Unknown,
+}
+
+impl JukeboxError {
+    /// Which category of error this is
+    pub fn kind(&self) -> JukeboxErrorKind {
+        use JukeboxError::*;
+
+        match self {
+            Disc(DiscError::UnsupportedIso) => JukeboxErrorKind::UnsupportedIso,
+            UnsupportedGame(_) => JukeboxErrorKind::UnsupportedGame,
+            AudioDevice(_) | AudioPlayback(_) => JukeboxErrorKind::AudioDevice,
+            AudioCapture(_) => JukeboxErrorKind::AudioCapture,
+            Disc(DiscError::GenericIO(_)) => JukeboxErrorKind::Io,
+            Disc(_) => JukeboxErrorKind::DiscRead,
+            InvalidHps(_) | HpsDecode(_) | AudioDecode(_) => JukeboxErrorKind::InvalidMusic,
+            GenericIO(_) => JukeboxErrorKind::Io,
+            ThreadSpawn(_) => JukeboxErrorKind::ThreadSpawn,
+            ChannelReceiverDisconnected(_) | ChannelSenderDisconnected(_) => JukeboxErrorKind::ThreadStopped,
+            Unknown => JukeboxErrorKind::Unknown,
+        }
+    }
+}
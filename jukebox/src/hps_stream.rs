@@ -0,0 +1,346 @@
+use std::io::{Read, Seek};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dolphin_integrations::Log;
+use rodio::Source;
+use slippi_disc::{DiscError, DiscReader};
+
+use crate::song_cache::{DecodedSong, SongCache};
+use crate::{JukeboxError::*, Result};
+
+/// Size of the hps file header, which is also where the first block starts
+const HPS_HEADER_SIZE: usize = 0x80;
+
+/// Offset of the channel information in the header, and the size of the
+/// information for each channel
+const HPS_CHANNEL_INFO_OFFSET: usize = 0x10;
+const HPS_CHANNEL_INFO_SIZE: usize = 0x38;
+
+/// Size of the header at the start of every block
+const HPS_BLOCK_HEADER_SIZE: usize = 0x20;
+
+/// Each DSP-ADPCM frame is a header byte followed by 14 four bit samples
+const FRAME_SIZE: usize = 8;
+const COEFFICIENT_PAIRS_PER_CHANNEL: usize = 8;
+
+type Coefficients = [(i16, i16); COEFFICIENT_PAIRS_PER_CHANNEL];
+
+/// Plays an hps file by reading and decoding one block at a time from the disc
+/// as the audio is needed, following the chain of blocks (and looping back when
+/// the last block points to an earlier one) the same way the game does. Only
+/// the current block is ever held in memory. Reading blocks means reading the
+/// disc, so jukebox plays streams through a `BufferedSource` rather than on the
+/// audio device's thread.
+///
+/// If a song cache is provided, the first pass through the song is recorded and
+/// added to it once complete, so that playing the song again doesn't need to
+/// decode anything. Recording stops if the song would not fit in the cache.
+pub(crate) struct HpsStream {
+    disc: Arc<Mutex<DiscReader>>,
+    hps_offset: u64,
+    hps_length: usize,
+    sample_rate: u32,
+    coefficients: [Coefficients; 2],
+
+    /// Interleaved samples of the block that is playing
+    block: Vec<i16>,
+    position: usize,
+    next_block_offset: Option<u32>,
+
+    /// Offsets of the blocks played so far, along with the sample frame that
+    /// each one starts at. Used to find where the song loops back to.
+    played_blocks: Vec<(u32, u64)>,
+    played_frames: u64,
+
+    cache: Option<Arc<Mutex<SongCache>>>,
+    recording: Option<Vec<i16>>,
+}
+
+impl HpsStream {
+    /// Starts streaming the hps file at `hps_offset` on the disc. The header
+    /// and first block are read right away so that broken files are caught
+    /// before playback starts.
+    pub(crate) fn new(
+        disc: Arc<Mutex<DiscReader>>,
+        hps_offset: u64,
+        hps_length: usize,
+        cache: Option<Arc<Mutex<SongCache>>>,
+    ) -> Result<Self> {
+        let mut header = [0; HPS_HEADER_SIZE];
+        read_bytes(&disc, hps_offset, &mut header)?;
+
+        if &header[..8] != b" HALPST\0" {
+            return Err(InvalidHps("Invalid magic number".to_string()));
+        }
+
+        let sample_rate = u32::from_be_bytes(header[0x8..0xC].try_into().unwrap());
+        let channel_count = u32::from_be_bytes(header[0xC..0x10].try_into().unwrap());
+        if channel_count != 2 {
+            return Err(InvalidHps(format!("Unsupported channel count: {channel_count}")));
+        }
+
+        // Each channel's coefficients start 0x10 bytes into its information
+        let coefficients = [0, 1].map(|channel| {
+            let start = HPS_CHANNEL_INFO_OFFSET + channel * HPS_CHANNEL_INFO_SIZE + 0x10;
+            std::array::from_fn(|pair| {
+                let bytes = &header[start + pair * 4..start + pair * 4 + 4];
+                (
+                    i16::from_be_bytes([bytes[0], bytes[1]]),
+                    i16::from_be_bytes([bytes[2], bytes[3]]),
+                )
+            })
+        });
+
+        let mut stream = Self {
+            disc,
+            hps_offset,
+            hps_length,
+            sample_rate,
+            coefficients,
+            block: Vec::new(),
+            position: 0,
+            next_block_offset: Some(HPS_HEADER_SIZE as u32),
+            played_blocks: Vec::new(),
+            played_frames: 0,
+            recording: cache.as_ref().map(|_| Vec::new()),
+            cache,
+        };
+
+        stream.load_next_block()?;
+        Ok(stream)
+    }
+
+    /// Reads and decodes the next block in the chain. Once there are no more
+    /// blocks (or the song loops), the recorded first pass is added to the
+    /// cache.
+    fn load_next_block(&mut self) -> Result<bool> {
+        let Some(offset) = self.next_block_offset else {
+            self.finish_recording(None);
+            return Ok(false);
+        };
+
+        match self.played_blocks.iter().find(|(played, _)| *played == offset) {
+            Some(&(_, loop_start)) => self.finish_recording(Some(loop_start)),
+            None => self.played_blocks.push((offset, self.played_frames)),
+        }
+
+        let (samples, next_block_offset) = self.read_block(offset)?;
+
+        // Blocks point past the end of the file (usually to 0xFFFFFFFF) when
+        // the song doesn't loop
+        self.next_block_offset =
+            Some(next_block_offset).filter(|&next| next as usize >= HPS_HEADER_SIZE && (next as usize) < self.hps_length);
+        self.played_frames += samples.len() as u64 / 2;
+        self.position = 0;
+
+        if let Some(recording) = &mut self.recording {
+            recording.extend_from_slice(&samples);
+
+            let budget = self.cache.as_ref().map_or(0, |cache| cache.lock().unwrap().budget());
+            if std::mem::size_of_val(recording.as_slice()) > budget {
+                self.recording = None;
+            }
+        }
+
+        self.block = samples;
+        Ok(true)
+    }
+
+    /// Reads the block at `offset` in the hps file and decodes its samples.
+    /// Returns the interleaved samples, and the offset of the next block.
+    fn read_block(&self, offset: u32) -> Result<(Vec<i16>, u32)> {
+        let block_offset = self.hps_offset + offset as u64;
+
+        let mut header = [0; HPS_BLOCK_HEADER_SIZE];
+        read_bytes(&self.disc, block_offset, &mut header)?;
+
+        // Data length (u32), address of the last byte (u32), next block offset
+        // (u32), then the initial decoder state for each channel (8 bytes each)
+        let dsp_data_length = u32::from_be_bytes(header[0x0..0x4].try_into().unwrap()) as usize;
+        let next_block_offset = u32::from_be_bytes(header[0x8..0xC].try_into().unwrap());
+        if offset as usize + HPS_BLOCK_HEADER_SIZE + dsp_data_length > self.hps_length {
+            return Err(InvalidHps(format!("Block at 0x{offset:x} is larger than the file")));
+        }
+
+        let mut data = vec![0; dsp_data_length / FRAME_SIZE * FRAME_SIZE];
+        read_bytes(&self.disc, block_offset + HPS_BLOCK_HEADER_SIZE as u64, &mut data)?;
+
+        // The first half of the frames are for the left channel, and the other
+        // half are for the right
+        let (left, right) = data.split_at(data.len() / FRAME_SIZE / 2 * FRAME_SIZE);
+        let history = |channel: usize| {
+            let state = &header[0xC + channel * 8..0xC + channel * 8 + 8];
+            (
+                i16::from_be_bytes([state[2], state[3]]),
+                i16::from_be_bytes([state[4], state[5]]),
+            )
+        };
+
+        let left = decode_frames(left, history(0), &self.coefficients[0])?;
+        let right = decode_frames(right, history(1), &self.coefficients[1])?;
+        let samples = left.into_iter().zip(right).flat_map(|(l, r)| [l, r]).collect();
+
+        Ok((samples, next_block_offset))
+    }
+
+    /// Adds the recorded first pass through the song to the cache
+    fn finish_recording(&mut self, loop_start: Option<u64>) {
+        let (Some(recording), Some(cache)) = (self.recording.take(), &self.cache) else {
+            return;
+        };
+
+        let song = DecodedSong::from_samples(recording.into(), 2, self.sample_rate, loop_start);
+        cache.lock().unwrap().insert(self.hps_offset, Arc::new(song));
+    }
+}
+
+impl Iterator for HpsStream {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        while self.position >= self.block.len() {
+            match self.load_next_block() {
+                Ok(true) => {},
+                Ok(false) => return None,
+                Err(e) => {
+                    tracing::error!(target: Log::Jukebox, error = ?e, "Failed to stream hps block. Stopping song.");
+                    self.recording = None;
+                    self.next_block_offset = None;
+                    return None;
+                },
+            }
+        }
+
+        let sample = self.block[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for HpsStream {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Decodes DSP-ADPCM `frames` for a single channel, starting from the given
+/// decoder history
+fn decode_frames(frames: &[u8], (mut hist1, mut hist2): (i16, i16), coefficients: &Coefficients) -> Result<Vec<i16>> {
+    let mut samples = Vec::with_capacity(frames.len() / FRAME_SIZE * 14);
+
+    for frame in frames.chunks_exact(FRAME_SIZE) {
+        let scale = 1 << (frame[0] & 0xF);
+        let Some(&(coef1, coef2)) = coefficients.get((frame[0] >> 4) as usize) else {
+            return Err(HpsDecode(format!("Invalid coefficient index: {}", frame[0] >> 4)));
+        };
+
+        for &byte in &frame[1..] {
+            // Samples are signed four bit values, high nibble first
+            for nibble in [(byte as i8) >> 4, ((byte << 4) as i8) >> 4] {
+                let sample = ((((nibble as i32 * scale) << 11)
+                    + 1024
+                    + (coef1 as i32 * hist1 as i32 + coef2 as i32 * hist2 as i32))
+                    >> 11)
+                    .clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+                hist2 = hist1;
+                hist1 = sample;
+                samples.push(sample);
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+/// Fills `buf` with the bytes at `offset` on the disc
+fn read_bytes(disc: &Mutex<DiscReader>, offset: u64, buf: &mut [u8]) -> Result<()> {
+    let mut disc = disc.lock().unwrap();
+    disc.seek(std::io::SeekFrom::Start(offset)).map_err(DiscError::IsoSeek)?;
+    disc.read_exact(buf).map_err(DiscError::IsoRead)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use hps_decode::Hps;
+    use slippi_disc::copy_bytes;
+
+    use super::*;
+
+    const MENU01: (u64, usize) = (0x13F00, 0x500);
+    const IZUMI: (u64, usize) = (0x15000, 0x320);
+
+    fn open(image: &str) -> Arc<Mutex<DiscReader>> {
+        Arc::new(Mutex::new(DiscReader::open(image).unwrap()))
+    }
+
+    fn decode(disc: &Arc<Mutex<DiscReader>>, (offset, length): (u64, usize)) -> hps_decode::decoded_hps::DecodedHps {
+        let bytes = copy_bytes(&mut *disc.lock().unwrap(), offset, length).unwrap();
+        Hps::try_from(bytes).unwrap().decode().unwrap()
+    }
+
+    #[test]
+    fn streams_the_same_audio_as_decoding_the_whole_file() {
+        for image in ["../disc/test-data/tiny-disc.iso", "../disc/test-data/tiny-disc.rvz"] {
+            let disc = open(image);
+
+            // Long enough to loop back a few times
+            let (offset, length) = MENU01;
+            let stream = HpsStream::new(disc.clone(), offset, length, None).unwrap();
+            assert_eq!(stream.sample_rate(), decode(&disc, MENU01).sample_rate());
+            assert!(stream.take(5000).eq(decode(&disc, MENU01).take(5000)), "{image}");
+
+            let (offset, length) = IZUMI;
+            let stream = HpsStream::new(disc.clone(), offset, length, None).unwrap();
+            assert!(stream.eq(decode(&disc, IZUMI)), "{image}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_files_before_playing() {
+        let disc = open("../disc/test-data/tiny-disc.iso");
+        let stream = HpsStream::new(disc, 0x16000, 0x100, None);
+        assert!(matches!(stream, Err(InvalidHps(_))));
+    }
+
+    #[test]
+    fn records_the_first_pass_into_the_cache() {
+        let disc = open("../disc/test-data/tiny-disc.iso");
+        let cache = Arc::new(Mutex::new(SongCache::new(1024 * 1024)));
+
+        for (offset, length) in [MENU01, IZUMI] {
+            let stream = HpsStream::new(disc.clone(), offset, length, Some(cache.clone())).unwrap();
+            let played: Vec<i16> = stream.take(5000).collect();
+            assert!(cache.lock().unwrap().contains(offset));
+
+            // The cached song plays exactly like the stream did
+            let song = cache.lock().unwrap().get(offset).unwrap();
+            assert!(song.source().take(5000).eq(played));
+        }
+    }
+
+    #[test]
+    fn doesnt_record_songs_that_dont_fit_in_the_cache() {
+        let disc = open("../disc/test-data/tiny-disc.iso");
+        let cache = Arc::new(Mutex::new(SongCache::new(0x100)));
+
+        let (offset, length) = MENU01;
+        let stream = HpsStream::new(disc, offset, length, Some(cache.clone())).unwrap();
+        assert_eq!(stream.take(5000).count(), 5000);
+        assert!(!cache.lock().unwrap().contains(offset));
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::UNIX_EPOCH;
+
+use slippi_disc::DiscHeader;
+
+/// Identifies one particular disc image, so that what jukebox remembers about
+/// the music on it (like how loud each song is) isn't used for a different or
+/// modified image. Modded images usually keep the header of the game they're
+/// based on, so the size and modification time of the file are included too,
+/// and any change to the file makes it a different image.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct IsoIdentity(String);
+
+impl IsoIdentity {
+    /// Identifies the image at `iso_path`, which has the provided `header`
+    pub(crate) fn read(iso_path: &str, header: &DiscHeader) -> Self {
+        let metadata = std::fs::metadata(iso_path).ok();
+        let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+        let modified = metadata
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_nanos());
+
+        Self(format!("{}-{}-{size:x}-{modified}", header.id(), header.revision))
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// An identity for tests that don't need a real image
+    #[cfg(test)]
+    pub(crate) fn for_tests(identity: &str) -> Self {
+        Self(identity.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    use slippi_disc::DiscReader;
+
+    use super::*;
+
+    #[test]
+    fn changes_along_with_the_image() {
+        let folder = tempfile::tempdir().unwrap();
+        let iso_path = folder.path().join("melee.iso");
+        std::fs::copy("../disc/test-data/tiny-disc.iso", &iso_path).unwrap();
+        let iso_path = iso_path.to_str().unwrap();
+        let header = DiscHeader::read(&mut DiscReader::open(iso_path).unwrap()).unwrap();
+
+        let identity = IsoIdentity::read(iso_path, &header);
+        assert!(identity.as_str().starts_with("GALE01-2-"));
+        assert_eq!(IsoIdentity::read(iso_path, &header), identity);
+
+        let file = File::options().write(true).open(iso_path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        assert_ne!(IsoIdentity::read(iso_path, &header), identity);
+    }
+}
@@ -1,36 +1,105 @@
-use std::convert::TryInto;
 use std::fmt::Debug;
-use std::fs::File;
-use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError, Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use dolphin_integrations::{Color, Dolphin, Duration as OSDDuration, Log};
-use hps_decode::Hps;
-use rodio::{OutputStream, Sink};
+use rodio::Source;
+pub use slippi_disc::DiscHeader;
+use slippi_disc::{DiscError, DiscReader, FileSystemTable};
 
 use crate::Message::*;
 
+mod config;
+pub use config::{
+    AudioBackend, Config, DEFAULT_CROSSFADE, DEFAULT_FADE_OUT, DEFAULT_SONG_CACHE_SIZE, DEFAULT_TARGET_LOUDNESS,
+    SUGGESTED_PREDECODE_SONGS,
+};
+
 mod errors;
 use JukeboxError::*;
-pub use errors::JukeboxError;
+pub use errors::{JukeboxError, JukeboxErrorKind};
+
+mod buffered_source;
+use buffered_source::BufferedSource;
+
+mod disc_music;
+use disc_music::scan_disc_music;
+pub use disc_music::{DiscMusic, DiscTrack, ScanReport, TrackInfo};
+
+mod hps_stream;
+use hps_stream::HpsStream;
+
+mod iso_identity;
+use iso_identity::IsoIdentity;
+
+mod looping_source;
 
-mod disc;
-use disc::{IsoKind, get_iso_kind};
+mod loudness;
+use loudness::{LoudnessAnalyzer, LoudnessCache, Track, normalization_gain};
 
-mod utils;
-use utils::copy_bytes_from_file;
+mod mixer;
+use mixer::{MIXER_SAMPLE_RATE, Mixer, MixerSource, PlaybackProgress, Song};
+
+mod output;
+use output::{AudioOutput, MirrorBuffer, output_device_names};
+
+mod music_override;
+use music_override::{find_override, open_override};
+
+mod status;
+pub use status::{JukeboxStatus, PlaybackState};
+
+mod song_cache;
+use song_cache::{SongCache, predecode_songs};
 
 pub(crate) type Result<T> = std::result::Result<T, JukeboxError>;
 
 /// By default Slippi Jukebox plays music slightly louder than vanilla melee
 /// does. This reduces the overall music volume output to 80%. Not totally sure
-/// if that's the correct amount, but it sounds about right.
+/// if that's the correct amount, but it sounds about right. Songs that are
+/// loudness normalized don't need it.
 const VOLUME_REDUCTION_MULTIPLIER: f32 = 0.8;
 
+/// How often the player checks that the audio output is still working
+const OUTPUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long dropping jukebox waits for the player thread to stop. Jukebox is
+/// dropped on Dolphin's thread, so a player thread that is stuck (e.g. opening
+/// an audio device) is left to finish on its own rather than stalling the
+/// emulator.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Game ID and maker code of NTSC Melee
+const MELEE_NTSC_ID: &str = "GALE01";
+
+/// Revisions of NTSC Melee that jukebox supports. Where the game keeps its
+/// music, and the hooks that tell jukebox what to play, are specific to 1.02.
+const SUPPORTED_REVISIONS: [u8; 1] = [2];
+
 #[derive(Debug)]
 pub enum Message {
     StartSong(u64, usize),
+    PlayFile(String),
     StopMusic,
     SetVolume(VolumeControl, u8),
+    /// Fade out duration when stopping, and crossfade duration when switching
+    /// songs
+    SetFadeDurations(Duration, Duration),
+    /// Name of the audio device to play music on, or `None` for the system's
+    /// default device
+    SetOutputDevice(Option<String>),
+    EmulationPaused,
+    EmulationResumed,
+    /// Speed that the emulator is running at, where 1.0 is full speed
+    SetEmulationSpeed(f32),
+    SetChannelMode(ChannelMode),
+    /// From -1.0 (left speaker only) to 1.0 (right speaker only)
+    SetBalance(f32),
+    SetLoudnessNormalization(bool),
+    /// Volume of the mirror output, from 0 to 100
+    SetMirrorVolume(u8),
     JukeboxDropped,
 }
 
@@ -41,46 +110,134 @@ pub enum VolumeControl {
     DolphinMusic,
 }
 
+/// Melee's Stereo/Mono sound setting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelMode {
+    #[default]
+    Stereo,
+    Mono,
+}
+
 #[derive(Debug)]
 pub struct Jukebox {
     tx: Sender<Message>,
+    thread: Option<JoinHandle<()>>,
+    /// Why the player thread stopped, if it stopped because of an error
+    thread_error: Arc<Mutex<Option<JukeboxErrorKind>>>,
+    status: Arc<Mutex<JukeboxStatus>>,
+    progress: Arc<PlaybackProgress>,
 }
 
 impl Jukebox {
     /// Returns an instance of Slippi Jukebox. Playback can be controlled by
     /// calling the instance's public methods.
-    pub fn new(iso_path: String, initial_dolphin_system_volume: u8, initial_dolphin_music_volume: u8) -> Result<Self> {
+    pub fn new(config: Config) -> Result<Self> {
         tracing::info!(target: Log::Jukebox, "Initializing Slippi Jukebox");
 
-        // Make sure the provided ISO is supported
-        if let IsoKind::Unknown = get_iso_kind(&mut File::open(&iso_path)?)? {
+        // Make sure the provided ISO is a disc image we can read, and that the
+        // game on it is one that jukebox knows how to play music for
+        let header = match DiscReader::open(&config.iso_path).and_then(|mut disc| DiscHeader::read(&mut disc)) {
+            Ok(header) => header,
+            Err(e @ DiscError::UnsupportedIso) => {
+                Dolphin::add_osd_message(
+                    Color::Red,
+                    OSDDuration::VeryLong,
+                    "\nYour ISO is not supported by Slippi Jukebox. Music will not play.",
+                );
+                return Err(e.into());
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        if !Self::supports_game(&header) {
+            tracing::error!(target: Log::Jukebox, "Not starting Slippi Jukebox for {header}");
             Dolphin::add_osd_message(
                 Color::Red,
                 OSDDuration::VeryLong,
-                "\nYour ISO is not supported by Slippi Jukebox. Music will not play.",
+                format!("\nSlippi Jukebox only supports NTSC Melee 1.02, but your ISO is {header}. Music will not play."),
             );
-            return Err(UnsupportedIso);
+            return Err(UnsupportedGame(header));
         }
 
+        // What jukebox remembers about the ISO's music is tied to this exact
+        // image, so that it isn't used once the image is changed or replaced
+        let iso = IsoIdentity::read(&config.iso_path, &header);
+
         // This channel allows the main thread to send messages to the
         // SlippiJukebox player thread
         let (tx, rx) = channel::<Message>();
 
+        // Decoded songs are shared with the pre-decoding thread. Only the
+        // player thread holds on to the cache, so pre-decoding stops once
+        // jukebox does.
+        let cache = Arc::new(Mutex::new(SongCache::new(config.song_cache_size)));
+        let predecode_cache = Arc::downgrade(&cache);
+        let iso_path = config.iso_path.clone();
+        let scan_iso_path = config.iso_path.clone();
+        let scan_iso = iso.clone();
+        let scan_cache_path = config.scan_cache_path.clone();
+        let songs = config.predecode_songs.clone();
+
+        // The player thread keeps the status up to date, and the mixer keeps
+        // track of how far into the song it is
+        let status = Arc::new(Mutex::new(JukeboxStatus::default()));
+        let player_status = status.clone();
+        let thread_error = Arc::new(Mutex::new(None));
+        let player_thread_error = thread_error.clone();
+        let mixer = Mixer::new();
+        let progress = mixer.0.progress();
+
         // Spawn the thread that will handle loading music and playing it back
-        std::thread::Builder::new()
+        let thread = std::thread::Builder::new()
             .name("SlippiJukebox".to_string())
             .spawn(move || {
-                if let Err(e) = Self::start(rx, iso_path, initial_dolphin_system_volume, initial_dolphin_music_volume) {
+                if let Err(e) = Self::start(rx, config, iso, cache, mixer, player_status.clone()) {
                     tracing::error!(
                         target: Log::Jukebox,
                         error = ?e,
                         "SlippiJukebox thread encountered an error: {e}"
                     );
+
+                    *player_thread_error.lock().unwrap() = Some(e.kind());
+
+                    let mut status = player_status.lock().unwrap();
+                    status.state = PlaybackState::Error;
+                    status.error = Some(e.to_string());
                 }
             })
             .map_err(ThreadSpawn)?;
 
-        Ok(Self { tx })
+        // Check the disc for songs that can't be played, so that users are
+        // warned about them now instead of when they come up
+        std::thread::Builder::new()
+            .name("SlippiJukeboxScan".to_string())
+            .spawn(move || {
+                if let Err(e) = scan_disc_music(&scan_iso_path, &scan_iso, scan_cache_path.as_deref()) {
+                    tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to scan the ISO's music: {e}");
+                }
+            })
+            .map_err(ThreadSpawn)?;
+
+        // Decode the songs that are most likely to be played in the background
+        // so that they start right away
+        if !songs.is_empty() {
+            std::thread::Builder::new()
+                .name("SlippiJukeboxPredecode".to_string())
+                .spawn(move || {
+                    if let Err(e) = predecode_songs(&iso_path, &songs, predecode_cache) {
+                        tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to pre-decode songs: {e}");
+                    }
+                })
+                .map_err(ThreadSpawn)?;
+        }
+
+        Ok(Self {
+            tx,
+            thread: Some(thread),
+            thread_error,
+            status,
+            progress,
+        })
     }
 
     /// This can be thought of as jukebox's "main" function.
@@ -88,66 +245,221 @@ impl Jukebox {
     /// thread. The message handlers control music playback.
     fn start(
         rx: Receiver<Message>,
-        iso_path: String,
-        initial_dolphin_system_volume: u8,
-        initial_dolphin_music_volume: u8,
+        config: Config,
+        iso: IsoIdentity,
+        cache: Arc<Mutex<SongCache>>,
+        (mixer, mixer_source): (Mixer, MixerSource),
+        status: Arc<Mutex<JukeboxStatus>>,
     ) -> Result<()> {
-        let (_stream, stream_handle) = OutputStream::try_default()?;
-        let sink = Sink::try_new(&stream_handle)?;
+        // The mixer outlives any one output, so that music keeps playing from
+        // the same spot when the output has to be reopened
+        let mixer_source = Arc::new(Mutex::new(mixer_source));
+        let mut output_device = config.output_device.clone();
+        let mut output = Some(AudioOutput::open(
+            &config.audio_backend,
+            output_device.as_deref(),
+            mixer_source.clone(),
+        )?);
+
+        // Everything the main output plays is copied to the mirror output, if
+        // there is one. Like the main output, it's reopened if it goes away.
+        let mirror = config.mirror_output_device.clone().map(|device_name| {
+            let buffer = Arc::new(MirrorBuffer::new(config.initial_mirror_volume as f32 / 100.0));
+            mixer_source.lock().unwrap().set_mirror(Some(buffer.clone()));
+            (device_name, buffer)
+        });
+        let open_mirror = |(device_name, buffer): &(String, Arc<MirrorBuffer>)| {
+            AudioOutput::open_mirror(device_name, buffer.clone(), mixer_source.clone())
+        };
+        let mut mirror_output = mirror.as_ref().and_then(|mirror| {
+            open_mirror(mirror)
+                .inspect_err(|e| tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to open mirror output {}", mirror.0))
+                .ok()
+        });
+
+        let mut disc = DiscReader::open(&config.iso_path)?;
+        let disc_size = disc.size();
 
-        let mut iso = File::open(&iso_path)?;
-        let get_real_offset = disc::create_offset_locator_fn(&mut iso)?;
+        // Songs can still be played by offset without the FST, so a disc with
+        // a broken one shouldn't stop jukebox from running
+        let fst = FileSystemTable::read(&mut disc).unwrap_or_else(|e| {
+            tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to read the ISO's file system table");
+            FileSystemTable::default()
+        });
+
+        // Songs read from the disc while they play, so the disc is shared with
+        // whichever songs are currently in the mixer
+        let disc = Arc::new(Mutex::new(disc));
 
         let mut melee_music_volume = 1.0;
-        let mut dolphin_system_volume = (initial_dolphin_system_volume as f32 / 100.0).clamp(0.0, 1.0);
-        let mut dolphin_music_volume = (initial_dolphin_music_volume as f32 / 100.0).clamp(0.0, 1.0);
+        let mut dolphin_system_volume = (config.initial_dolphin_system_volume as f32 / 100.0).clamp(0.0, 1.0);
+        let mut dolphin_music_volume = (config.initial_dolphin_music_volume as f32 / 100.0).clamp(0.0, 1.0);
+
+        let mut fade_out = config.fade_out;
+        let mut crossfade = config.crossfade;
+
+        // Songs are measured in the background the first time they play, and
+        // brought to the target loudness from then on
+        let mut normalize_loudness = config.normalize_loudness;
+        let loudness_cache = Arc::new(Mutex::new(LoudnessCache::load(config.loudness_cache_path.clone(), iso)));
+        let loudness_analyzer = LoudnessAnalyzer::start(config.iso_path.clone(), loudness_cache.clone())?;
 
-        sink.set_volume(melee_music_volume * dolphin_system_volume * dolphin_music_volume * VOLUME_REDUCTION_MULTIPLIER);
+        // The volume controls apply to everything in the mixer, and the
+        // current song's gain is applied to just that song
+        let mut music_volume = melee_music_volume * dolphin_system_volume * dolphin_music_volume;
+        let mut song_gain = VOLUME_REDUCTION_MULTIPLIER;
+        mixer.set_volume(music_volume);
+        mixer.set_balance(config.balance);
+        status.lock().unwrap().volume = music_volume * song_gain;
+
+        let mut last_output_check = Instant::now();
 
         loop {
-            match rx.recv()? {
+            // Outputs are checked on a timer rather than only while no
+            // messages arrive, as messages can keep arriving for as long as
+            // a match goes on (e.g. volume changes)
+            if last_output_check.elapsed() >= OUTPUT_CHECK_INTERVAL {
+                last_output_check = Instant::now();
+
+                // Move to the new default device when the old one goes away
+                // (e.g. a headset is unplugged)
+                reopen_if_needed(&mut output, "audio output", || {
+                    AudioOutput::open(&config.audio_backend, output_device.as_deref(), mixer_source.clone())
+                });
+
+                if let Some(mirror) = &mirror {
+                    // Nothing reaches the mirror from the main output while
+                    // it's closed, so the mirror keeps the music going itself
+                    mirror.1.set_main_output_open(output.is_some());
+                    reopen_if_needed(&mut mirror_output, "mirror output", || open_mirror(mirror));
+                }
+            }
+
+            // Files requested by name are played the same way as songs
+            // requested by the game, once we know where they are
+            let timeout = OUTPUT_CHECK_INTERVAL.saturating_sub(last_output_check.elapsed());
+            let message = match rx.recv_timeout(timeout) {
+                Ok(PlayFile(name)) => match fst.get(&name) {
+                    Some(file) => StartSong(file.offset, file.length),
+                    None => {
+                        tracing::warn!(target: Log::Jukebox, "{name} was not found in the ISO. Cannot play song.");
+                        continue;
+                    },
+                },
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Err(RecvError.into()),
+            };
+
+            match message {
                 StartSong(hps_offset, hps_length) => {
-                    // Stop the currently playing song
-                    sink.stop();
+                    let hps_path = fst.path_at(hps_offset);
+                    match hps_path {
+                        Some(path) => tracing::info!(target: Log::Jukebox, "Playing {path}"),
+                        None => tracing::info!(target: Log::Jukebox, "Playing unknown file at 0x{hps_offset:0x?}"),
+                    }
+
+                    {
+                        let mut status = status.lock().unwrap();
+                        status.state = PlaybackState::Loading;
+                        status.hps_offset = Some(hps_offset);
+                        status.file_name = hps_path.map(String::from);
+                        status.error = None;
+                    }
 
-                    // Get the _real_ offset of the hps file on the iso
-                    let real_hps_offset = match get_real_offset(hps_offset) {
-                        Some(offset) => offset,
-                        None => {
+                    // Songs that can't be played come out with the reason why
+                    let song: std::result::Result<(Song, Track), String> = 'song: {
+                        // Play the user's replacement for this song if they
+                        // have one, otherwise fall back to the music on the disc
+                        let override_path = config
+                            .music_folder
+                            .as_deref()
+                            .zip(hps_path)
+                            .and_then(|(music_folder, hps_path)| find_override(music_folder, hps_path));
+                        if let Some(override_path) = override_path {
+                            match open_override(&override_path) {
+                                Ok(audio) => {
+                                    tracing::info!(target: Log::Jukebox, "Playing override {}", override_path.display());
+                                    let song = buffer_song(audio);
+                                    break 'song song.map(|song| (Box::new(song) as Song, Track::Override(override_path)));
+                                },
+                                Err(e) => tracing::warn!(
+                                    target: Log::Jukebox,
+                                    error = ?e,
+                                    "Failed to open {}. Playing the ISO's music instead.",
+                                    override_path.display()
+                                ),
+                            }
+                        }
+
+                        // Make sure the hps file is actually on the disc
+                        if hps_offset.saturating_add(hps_length as u64) > disc_size {
                             tracing::warn!(
                                 target: Log::Jukebox,
                                 "0x{hps_offset:0x?} has no corresponding offset in the ISO. Cannot play song."
                             );
-                            continue;
-                        },
-                    };
+                            break 'song Err(format!("0x{hps_offset:x} is not on the disc"));
+                        }
 
-                    // Parse the bytes as an Hps
-                    let hps: Hps = match copy_bytes_from_file(&mut iso, real_hps_offset, hps_length)?.try_into() {
-                        Ok(hps) => hps,
-                        Err(e) => {
-                            tracing::error!(target: Log::Jukebox, error = ?e, "Failed to parse bytes into an Hps. Cannot play song.");
-                            continue;
-                        },
-                    };
+                        // Songs that were played recently (or pre-decoded)
+                        // don't need to be decoded again. Anything else is
+                        // streamed from the disc as it plays (decoding ahead
+                        // of the audio device on its own thread), and cached
+                        // once it has played through.
+                        let track = Track::Disc {
+                            offset: hps_offset,
+                            length: hps_length,
+                        };
+                        let cached_song = cache.lock().unwrap().get(hps_offset);
+                        if let Some(song) = cached_song {
+                            break 'song Ok((Box::new(song.source()), track));
+                        }
 
-                    // Decode the Hps into audio
-                    let audio = match hps.decode() {
-                        Ok(audio) => audio,
-                        Err(e) => {
-                            tracing::error!(target: Log::Jukebox, error = ?e, "Failed to decode hps into audio. Cannot play song.");
-                            Dolphin::add_osd_message(
-                                Color::Red,
-                                OSDDuration::Normal,
-                                "Invalid music data found in ISO. This music will not play.",
-                            );
-                            continue;
-                        },
+                        match HpsStream::new(disc.clone(), hps_offset, hps_length, Some(cache.clone())) {
+                            Ok(stream) => buffer_song(stream).map(|song| (Box::new(song) as Song, track)),
+                            Err(e @ InvalidHps(_)) => {
+                                tracing::error!(target: Log::Jukebox, error = ?e, "Failed to parse bytes into an Hps. Cannot play song.");
+                                Err(e.to_string())
+                            },
+                            Err(e @ HpsDecode(_)) => {
+                                tracing::error!(target: Log::Jukebox, error = ?e, "Failed to decode hps into audio. Cannot play song.");
+                                Dolphin::add_osd_message(
+                                    Color::Red,
+                                    OSDDuration::Normal,
+                                    "Invalid music data found in ISO. This music will not play.",
+                                );
+                                Err(e.to_string())
+                            },
+                            Err(e) => return Err(e),
+                        }
                     };
 
-                    // Play the song
-                    sink.append(audio);
-                    sink.play();
+                    // Switch to the new song, or stop the current one if the
+                    // new one can't be played
+                    let mut status = status.lock().unwrap();
+                    match song {
+                        Ok((song, track)) => {
+                            song_gain = VOLUME_REDUCTION_MULTIPLIER;
+                            if normalize_loudness {
+                                // Silent songs are left alone
+                                let measured = loudness_cache.lock().unwrap().get(&track);
+                                match measured {
+                                    Some(Some(loudness)) => song_gain = normalization_gain(loudness, config.target_loudness),
+                                    Some(None) => {},
+                                    None => loudness_analyzer.analyze(track),
+                                }
+                            }
+
+                            mixer.play(Box::new(song.amplify(song_gain)), crossfade);
+                            status.state = PlaybackState::Playing;
+                            status.volume = music_volume * song_gain;
+                        },
+                        Err(error) => {
+                            mixer.stop(fade_out);
+                            status.state = PlaybackState::Error;
+                            status.error = Some(error);
+                        },
+                    }
                 },
                 SetVolume(control, volume) => {
                     use VolumeControl::*;
@@ -158,19 +470,63 @@ impl Jukebox {
                         DolphinMusic => dolphin_music_volume = (volume as f32 / 100.0).clamp(0.0, 1.0),
                     };
 
-                    sink.set_volume(
-                        melee_music_volume * dolphin_system_volume * dolphin_music_volume * VOLUME_REDUCTION_MULTIPLIER,
-                    );
+                    music_volume = melee_music_volume * dolphin_system_volume * dolphin_music_volume;
+                    mixer.set_volume(music_volume);
+                    status.lock().unwrap().volume = music_volume * song_gain;
+                },
+                SetFadeDurations(new_fade_out, new_crossfade) => {
+                    fade_out = new_fade_out;
+                    crossfade = new_crossfade;
                 },
-                StopMusic => sink.stop(),
+                // Headless backends don't have devices to switch between
+                SetOutputDevice(device_name) if config.audio_backend != AudioBackend::Device => {
+                    output_device = device_name;
+                },
+                SetOutputDevice(device_name) => {
+                    drop(output.take());
+                    output = match AudioOutput::open(&config.audio_backend, device_name.as_deref(), mixer_source.clone()) {
+                        Ok(new_output) => Some(new_output),
+                        Err(e) => {
+                            tracing::error!(target: Log::Jukebox, error = ?e, "Failed to switch audio output");
+                            None
+                        },
+                    };
+                    output_device = device_name;
+                },
+                EmulationPaused => {
+                    mixer.set_paused(true);
+                    status.lock().unwrap().paused = true;
+                },
+                EmulationResumed => {
+                    mixer.set_paused(false);
+                    status.lock().unwrap().paused = false;
+                },
+                SetEmulationSpeed(speed) if config.follow_emulation_speed => mixer.set_speed(speed),
+                SetEmulationSpeed(_) => {},
+                SetChannelMode(channel_mode) => mixer.set_channel_mode(channel_mode),
+                SetBalance(balance) => mixer.set_balance(balance),
+                SetLoudnessNormalization(enabled) => normalize_loudness = enabled,
+                SetMirrorVolume(volume) => {
+                    if let Some((_, buffer)) = &mirror {
+                        buffer.set_volume(volume as f32 / 100.0);
+                    }
+                },
+                StopMusic => {
+                    mixer.stop(fade_out);
+
+                    let mut status = status.lock().unwrap();
+                    status.state = PlaybackState::Idle;
+                    status.hps_offset = None;
+                    status.file_name = None;
+                },
+                PlayFile(_) => unreachable!(),
                 JukeboxDropped => return Ok(()),
             }
         }
     }
 
     /// Loads the music file in the iso at offset `hps_offset` with a length of
-    /// `hps_length`, decodes it into audio, and plays it back using the default
-    /// audio device
+    /// `hps_length`, decodes it into audio, and plays it back
     pub fn start_song(&mut self, hps_offset: u64, hps_length: usize) {
         tracing::info!(
             target: Log::Jukebox,
@@ -179,12 +535,127 @@ impl Jukebox {
         let _ = self.tx.send(StartSong(hps_offset, hps_length));
     }
 
+    /// Loads the music file in the iso named `name` (e.g. `menu01.hps` or
+    /// `audio/menu01.hps`), decodes it into audio, and plays it back
+    pub fn play_file(&mut self, name: &str) {
+        tracing::info!(target: Log::Jukebox, "Play file: {name}");
+        let _ = self.tx.send(PlayFile(name.to_string()));
+    }
+
     /// Stops any currently playing music
     pub fn stop_music(&mut self) {
         tracing::info!(target: Log::Jukebox, "Stop music");
         let _ = self.tx.send(StopMusic);
     }
 
+    /// Sets how long music takes to fade out when it's stopped, and how long
+    /// songs overlap for when switching between them. Zero durations switch
+    /// instantly.
+    pub fn set_fade_durations(&mut self, fade_out: Duration, crossfade: Duration) {
+        tracing::info!(target: Log::Jukebox, "Change fade durations. Fade out: {fade_out:?}, Crossfade: {crossfade:?}");
+        let _ = self.tx.send(SetFadeDurations(fade_out, crossfade));
+    }
+
+    /// Pauses music along with the emulator, including between frames while
+    /// frame advancing
+    pub fn emulation_paused(&mut self) {
+        tracing::info!(target: Log::Jukebox, "Emulation paused");
+        let _ = self.tx.send(EmulationPaused);
+    }
+
+    /// Resumes music from where it was paused
+    pub fn emulation_resumed(&mut self) {
+        tracing::info!(target: Log::Jukebox, "Emulation resumed");
+        let _ = self.tx.send(EmulationResumed);
+    }
+
+    /// Plays music at `speed` (where 1.0 is full speed) so that it stays in
+    /// step with the game when emulation is running slower or faster than
+    /// normal. This does nothing if `Config::follow_emulation_speed` is off.
+    pub fn set_emulation_speed(&mut self, speed: f32) {
+        tracing::debug!(target: Log::Jukebox, "Emulation speed: {speed}");
+        let _ = self.tx.send(SetEmulationSpeed(speed));
+    }
+
+    /// Plays music in stereo or mono, following Melee's sound setting
+    pub fn set_channel_mode(&mut self, channel_mode: ChannelMode) {
+        tracing::info!(target: Log::Jukebox, "Change channel mode: {channel_mode:?}");
+        let _ = self.tx.send(SetChannelMode(channel_mode));
+    }
+
+    /// Shifts music toward the left (-1.0) or right (1.0) speaker. At 0.0 both
+    /// speakers play at full volume.
+    pub fn set_balance(&mut self, balance: f32) {
+        tracing::info!(target: Log::Jukebox, "Change balance: {balance}");
+        let _ = self.tx.send(SetBalance(balance));
+    }
+
+    /// Turns loudness normalization (see `Config::normalize_loudness`) on or
+    /// off. This takes effect from the next song.
+    pub fn set_loudness_normalization(&mut self, enabled: bool) {
+        tracing::info!(target: Log::Jukebox, "Loudness normalization: {enabled}");
+        let _ = self.tx.send(SetLoudnessNormalization(enabled));
+    }
+
+    /// Sets the volume of the mirror output (see
+    /// `Config::mirror_output_device`), from 0 to 100. The other volume
+    /// controls apply to the mirror as well.
+    pub fn set_mirror_volume(&mut self, volume: u8) {
+        tracing::info!(target: Log::Jukebox, "Change mirror volume: {volume}");
+        let _ = self.tx.send(SetMirrorVolume(volume));
+    }
+
+    /// Returns `true` while the player thread is running. If it isn't, no
+    /// music will play and `thread_error` says why.
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Returns why the player thread stopped, or `None` if it's still running
+    pub fn thread_error(&self) -> Option<JukeboxErrorKind> {
+        match self.is_running() {
+            true => None,
+            // The thread also stops cleanly once jukebox is dropped, but while
+            // jukebox is around it only stops without an error if it panicked
+            false => Some(self.thread_error.lock().unwrap().unwrap_or(JukeboxErrorKind::ThreadStopped)),
+        }
+    }
+
+    /// Returns a snapshot of what jukebox is currently playing
+    pub fn status(&self) -> JukeboxStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        status.sample_rate = MIXER_SAMPLE_RATE;
+
+        // Songs that don't loop end on their own
+        match (status.state, self.progress.is_playing()) {
+            (PlaybackState::Playing, true) => status.elapsed_samples = self.progress.elapsed_frames(),
+            (PlaybackState::Playing, false) => status.state = PlaybackState::Idle,
+            _ => {},
+        }
+
+        status
+    }
+
+    /// Returns `true` if jukebox can play music for the game on the disc with
+    /// this header
+    pub fn supports_game(header: &DiscHeader) -> bool {
+        header.id() == MELEE_NTSC_ID && SUPPORTED_REVISIONS.contains(&header.revision)
+    }
+
+    /// Returns the names of the audio output devices that music can be played
+    /// on
+    pub fn output_devices() -> Vec<String> {
+        output_device_names()
+    }
+
+    /// Switches music to the audio output device named `device_name`, or to
+    /// the system's default device if it's `None`. The current song carries on
+    /// from where it was.
+    pub fn set_output_device(&mut self, device_name: Option<&str>) {
+        tracing::info!(target: Log::Jukebox, "Change output device: {device_name:?}");
+        let _ = self.tx.send(SetOutputDevice(device_name.map(String::from)));
+    }
+
     // Update the volume for any of Jukebox's volume controls
     pub fn set_volume(&mut self, volume_control: VolumeControl, volume: u8) {
         tracing::info!(target: Log::Jukebox, "Change {volume_control:?} volume: {volume}");
@@ -192,6 +663,45 @@ impl Jukebox {
     }
 }
 
+/// Opens `output` again with `open` if it has gone away, or hasn't been opened
+/// yet. If it can't be opened, it's tried again the next time this is called.
+fn reopen_if_needed(output: &mut Option<AudioOutput>, name: &str, open: impl FnOnce() -> Result<AudioOutput>) {
+    if output.as_ref().is_some_and(|output| !output.needs_reopen()) {
+        return;
+    }
+
+    // The old output has to go first so that two outputs aren't playing the
+    // same audio at once
+    let was_open = output.take().is_some();
+    *output = match open() {
+        Ok(new_output) => Some(new_output),
+        Err(e) => {
+            if was_open {
+                tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to reopen {name}");
+            }
+            None
+        },
+    };
+}
+
+/// Starts decoding `song` ahead of playback (see `BufferedSource`). If that
+/// fails, only this song can't be played, so the reason comes back as a song
+/// error rather than stopping the player thread.
+fn buffer_song<S>(song: S) -> std::result::Result<BufferedSource, String>
+where
+    S: Source<Item = i16> + Send + 'static,
+{
+    BufferedSource::new(song).map_err(|e| {
+        tracing::error!(target: Log::Jukebox, error = ?e, "Failed to start decoding song. Cannot play song.");
+        Dolphin::add_osd_message(
+            Color::Red,
+            OSDDuration::Normal,
+            "Slippi Jukebox failed to start playing a song. This music will not play.",
+        );
+        e.to_string()
+    })
+}
+
 impl Drop for Jukebox {
     fn drop(&mut self) {
         tracing::info!(target: Log::Jukebox, "Dropping Slippi Jukebox");
@@ -201,5 +711,357 @@ impl Drop for Jukebox {
                 "Failed to notify child thread that Jukebox is dropping: {e}"
             );
         }
+
+        // Wait for the audio output to close, so that captured audio has been
+        // written out by the time jukebox is gone, but not for too long
+        if let Some(thread) = self.thread.take() {
+            let started = Instant::now();
+            while !thread.is_finished() && started.elapsed() < STOP_TIMEOUT {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+
+            match thread.is_finished() {
+                true => {
+                    let _ = thread.join();
+                },
+                false => tracing::warn!(
+                    target: Log::Jukebox,
+                    "SlippiJukebox thread didn't stop within {STOP_TIMEOUT:?}, leaving it to stop on its own"
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::thread::sleep;
+
+    use super::*;
+
+    /// 100ms of stereo audio at the rate that jukebox mixes at
+    const TENTH_OF_A_SECOND: usize = 48000 / 10 * 2;
+
+    fn config(audio_backend: AudioBackend) -> Config {
+        let mut config = Config::new(
+            "../disc/test-data/tiny-disc.iso".to_string(),
+            Some(PathBuf::from("test-data/music")),
+            100,
+            100,
+        );
+        config.audio_backend = audio_backend;
+        config.fade_out = Duration::ZERO;
+        config
+    }
+
+    fn read_capture(path: &Path) -> Vec<i16> {
+        let mut reader = hound::WavReader::open(path).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 48000);
+        reader.samples().map(|sample| sample.unwrap()).collect()
+    }
+
+    fn is_silent(samples: &[i16]) -> bool {
+        samples.iter().all(|&sample| sample == 0)
+    }
+
+    /// Number of samples written to the capture at `path` so far. Samples are
+    /// written out in batches, so this lags a little behind what has played.
+    fn captured_samples(path: &Path) -> usize {
+        let size = std::fs::metadata(path).map_or(0, |metadata| metadata.len() as usize);
+        size.saturating_sub(44) / 2
+    }
+
+    /// Waits for `condition` to be true, failing the test if it takes longer
+    /// than a few seconds. Jukebox does its work on its own threads, so tests
+    /// wait for it to get somewhere rather than for a fixed amount of time.
+    fn wait_until(description: &str, mut condition: impl FnMut() -> bool) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "Timed out waiting for {description}"
+            );
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn captures_songs_until_they_are_stopped() {
+        let capture = tempfile::tempdir().unwrap();
+        let wav = capture.path().join("jukebox.wav");
+
+        let mut jukebox = Jukebox::new(config(AudioBackend::Capture(wav.clone()))).unwrap();
+        jukebox.play_file("menu01.hps");
+        wait_until("the song to play", || jukebox.status().elapsed_samples >= 48000 / 5);
+        jukebox.stop_music();
+        wait_until("the song to stop", || jukebox.status().state == PlaybackState::Idle);
+        let stopped_at = captured_samples(&wav);
+        wait_until("silence to be captured", || {
+            captured_samples(&wav) >= stopped_at + TENTH_OF_A_SECOND * 3
+        });
+        drop(jukebox);
+
+        let samples = read_capture(&wav);
+        assert!(!is_silent(&samples));
+        assert!(is_silent(&samples[samples.len() - TENTH_OF_A_SECOND..]));
+    }
+
+    #[test]
+    fn captures_volume_changes() {
+        let capture = tempfile::tempdir().unwrap();
+        let wav = capture.path().join("jukebox.wav");
+
+        // `izumi.hps` is replaced by `izumi.wav` in the music folder
+        let mut jukebox = Jukebox::new(config(AudioBackend::Capture(wav.clone()))).unwrap();
+        jukebox.play_file("izumi.hps");
+        wait_until("the song to play", || jukebox.status().elapsed_samples >= 48000 / 20);
+        jukebox.set_volume(VolumeControl::Melee, 0);
+        wait_until("the volume to change", || jukebox.status().volume == 0.0);
+        let muted_at = captured_samples(&wav);
+        wait_until("silence to be captured", || {
+            captured_samples(&wav) >= muted_at + TENTH_OF_A_SECOND * 3
+        });
+        drop(jukebox);
+
+        let samples = read_capture(&wav);
+        assert!(!is_silent(&samples));
+        assert!(is_silent(&samples[samples.len() - TENTH_OF_A_SECOND..]));
+    }
+
+    #[test]
+    fn captures_silence_without_music() {
+        let capture = tempfile::tempdir().unwrap();
+        let wav = capture.path().join("jukebox.wav");
+
+        let mut jukebox = Jukebox::new(config(AudioBackend::Capture(wav.clone()))).unwrap();
+        jukebox.play_file("mutecity.hps");
+        jukebox.start_song(0x16000, 0x100);
+        wait_until("the broken song to fail", || jukebox.status().state == PlaybackState::Error);
+        wait_until("a while to be captured", || captured_samples(&wav) >= TENTH_OF_A_SECOND * 2);
+        drop(jukebox);
+
+        let samples = read_capture(&wav);
+        assert!(samples.len() >= TENTH_OF_A_SECOND);
+        assert!(is_silent(&samples));
+    }
+
+    #[test]
+    fn reports_what_is_playing() {
+        let mut jukebox = Jukebox::new(config(AudioBackend::Null)).unwrap();
+        assert_eq!(jukebox.status().state, PlaybackState::Idle);
+
+        jukebox.play_file("menu01.hps");
+        wait_until("the song to play", || jukebox.status().elapsed_samples > 0);
+        let status = jukebox.status();
+        assert_eq!(status.state, PlaybackState::Playing);
+        assert_eq!(status.hps_offset, Some(0x13F00));
+        assert_eq!(status.file_name.as_deref(), Some("audio/menu01.hps"));
+        assert!(status.elapsed_samples > 0);
+        assert_eq!(status.sample_rate, 48000);
+        assert!((status.volume - VOLUME_REDUCTION_MULTIPLIER).abs() < 0.001);
+
+        jukebox.set_volume(VolumeControl::DolphinMusic, 50);
+        jukebox.start_song(0x16000, 0x100);
+        wait_until("the broken song to fail", || jukebox.status().state == PlaybackState::Error);
+        let status = jukebox.status();
+        assert_eq!(status.file_name.as_deref(), Some("audio/broken.hps"));
+        assert!(status.error.is_some());
+        assert!((status.volume - VOLUME_REDUCTION_MULTIPLIER / 2.0).abs() < 0.001);
+
+        jukebox.play_file("menu01.hps");
+        jukebox.stop_music();
+        wait_until("the song to stop", || jukebox.status().state == PlaybackState::Idle);
+        let status = jukebox.status();
+        assert_eq!(status.hps_offset, None);
+    }
+
+    #[test]
+    fn reports_songs_that_end_as_idle() {
+        let mut config = config(AudioBackend::Null);
+        config.music_folder = Some(PathBuf::from("test-data/missing"));
+        let mut jukebox = Jukebox::new(config).unwrap();
+
+        // `izumi.hps` doesn't loop, and is only a few milliseconds long
+        jukebox.play_file("izumi.hps");
+        wait_until("the song to end", || {
+            let status = jukebox.status();
+            status.file_name.is_some() && status.state == PlaybackState::Idle
+        });
+        assert_eq!(jukebox.status().hps_offset, Some(0x15000));
+    }
+
+    #[test]
+    fn pauses_along_with_the_emulator() {
+        let mut jukebox = Jukebox::new(config(AudioBackend::Null)).unwrap();
+        jukebox.play_file("menu01.hps");
+        wait_until("the song to play", || jukebox.status().elapsed_samples > 0);
+
+        // The mixer's own tests check that paused songs stay exactly where
+        // they are. Here it's enough that the song stops moving.
+        jukebox.emulation_paused();
+        wait_until("the emulator to pause", || jukebox.status().paused);
+        let mut last_elapsed = 0;
+        wait_until("the song to stop moving", || {
+            let elapsed = jukebox.status().elapsed_samples;
+            std::mem::replace(&mut last_elapsed, elapsed) == elapsed
+        });
+
+        jukebox.emulation_resumed();
+        jukebox.set_emulation_speed(0.5);
+        wait_until("the emulator to resume", || !jukebox.status().paused);
+        wait_until("the song to move again", || jukebox.status().elapsed_samples > last_elapsed);
+    }
+
+    #[test]
+    fn normalizes_songs_once_they_are_measured() {
+        let folder = tempfile::tempdir().unwrap();
+        let music_folder = folder.path().join("Music");
+        std::fs::create_dir(&music_folder).unwrap();
+
+        // Two seconds of a 1kHz sine at -23 dBFS, which measures -23 LUFS
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = hound::WavWriter::create(music_folder.join("izumi.wav"), spec).unwrap();
+        for frame in 0..96000 {
+            let sample = 0.0708 * i16::MAX as f64 * (std::f64::consts::TAU * frame as f64 / 48.0).sin();
+            wav.write_sample(sample as i16).unwrap();
+            wav.write_sample(sample as i16).unwrap();
+        }
+        wav.finalize().unwrap();
+
+        let mut config = config(AudioBackend::Null);
+        config.music_folder = Some(music_folder);
+        config.normalize_loudness = true;
+        config.loudness_cache_path = Some(folder.path().join("Jukebox").join("loudness.json"));
+        let mut jukebox = Jukebox::new(config).unwrap();
+
+        // The first time the song plays it hasn't been measured yet
+        jukebox.play_file("izumi.hps");
+        wait_until("the song to play", || jukebox.status().file_name.is_some());
+        assert!((jukebox.status().volume - VOLUME_REDUCTION_MULTIPLIER).abs() < 0.001);
+        wait_until("the song to be measured", || {
+            folder.path().join("Jukebox").join("loudness.json").is_file()
+        });
+
+        // It's 5 LU quieter than the target
+        jukebox.play_file("izumi.hps");
+        wait_until("the song to be normalized", || (jukebox.status().volume - 1.778).abs() < 0.01);
+
+        jukebox.set_loudness_normalization(false);
+        jukebox.play_file("izumi.hps");
+        wait_until("normalization to turn off", || {
+            (jukebox.status().volume - VOLUME_REDUCTION_MULTIPLIER).abs() < 0.001
+        });
+    }
+
+    #[test]
+    fn only_supports_ntsc_melee_1_02() {
+        let header = |id: &str, revision: u8, name: &str| DiscHeader {
+            game_id: id[..4].to_string(),
+            maker_code: id[4..].to_string(),
+            disc_number: 0,
+            revision,
+            internal_name: name.to_string(),
+        };
+
+        assert!(Jukebox::supports_game(&header("GALE01", 2, "Super Smash Bros Melee")));
+
+        assert!(!Jukebox::supports_game(&header("GALE01", 0, "Super Smash Bros Melee")));
+        assert!(!Jukebox::supports_game(&header("GALE01", 1, "Super Smash Bros Melee")));
+        assert!(!Jukebox::supports_game(&header("GALP01", 2, "Super Smash Bros Melee")));
+        assert!(!Jukebox::supports_game(&header("GALJ01", 2, "Dairantou Smash Brothers DX")));
+        assert!(!Jukebox::supports_game(&header("GMSE01", 0, "Super Mario Sunshine")));
+    }
+
+    #[test]
+    fn only_starts_for_supported_games() {
+        let folder = tempfile::tempdir().unwrap();
+        let iso_path = folder.path().join("melee-1.00.iso");
+
+        // Melee 1.00 is the same disc with a different revision
+        let mut iso = std::fs::read("../disc/test-data/tiny-disc.iso").unwrap();
+        iso[7] = 0;
+        std::fs::write(&iso_path, iso).unwrap();
+
+        let mut revision_0 = config(AudioBackend::Null);
+        revision_0.iso_path = iso_path.to_string_lossy().into_owned();
+        match Jukebox::new(revision_0) {
+            Err(UnsupportedGame(header)) => assert_eq!((header.id().as_str(), header.revision), ("GALE01", 0)),
+            other => panic!("expected UnsupportedGame, got {other:?}"),
+        }
+
+        let mut not_a_disc = config(AudioBackend::Null);
+        not_a_disc.iso_path = "../disc/test-data/misow.bin".to_string();
+        assert_eq!(Jukebox::new(not_a_disc).unwrap_err().kind(), JukeboxErrorKind::UnsupportedIso);
+    }
+
+    #[test]
+    fn keeps_playing_when_the_mirror_device_is_missing() {
+        let mut config = config(AudioBackend::Null);
+        config.mirror_output_device = Some("Missing Virtual Cable".to_string());
+        let mut jukebox = Jukebox::new(config).unwrap();
+
+        jukebox.play_file("menu01.hps");
+        jukebox.set_mirror_volume(50);
+        wait_until("the song to play", || jukebox.status().elapsed_samples > 0);
+        assert_eq!(jukebox.status().state, PlaybackState::Playing);
+    }
+
+    #[test]
+    fn reports_why_the_player_thread_stopped() {
+        // Once a song plays, the output has opened and jukebox is up and running
+        let mut jukebox = Jukebox::new(config(AudioBackend::Null)).unwrap();
+        jukebox.play_file("menu01.hps");
+        wait_until("the song to play", || jukebox.status().elapsed_samples > 0);
+        assert!(jukebox.is_running());
+        assert_eq!(jukebox.thread_error(), None);
+
+        // Captures can't be written to a folder that doesn't exist
+        let capture = PathBuf::from("test-data/missing/jukebox.wav");
+        let jukebox = Jukebox::new(config(AudioBackend::Capture(capture))).unwrap();
+        wait_until("the player thread to stop", || !jukebox.is_running());
+        assert_eq!(jukebox.thread_error(), Some(JukeboxErrorKind::AudioCapture));
+        assert_eq!(jukebox.status().state, PlaybackState::Error);
+    }
+
+    #[test]
+    fn plays_without_an_audio_device() {
+        let mut jukebox = Jukebox::new(config(AudioBackend::Null)).unwrap();
+        jukebox.play_file("menu01.hps");
+        jukebox.set_fade_durations(Duration::from_millis(50), Duration::from_millis(50));
+        jukebox.set_output_device(Some("Speakers"));
+        jukebox.play_file("izumi.hps");
+        wait_until("the song to play", || {
+            jukebox.status().file_name.as_deref() == Some("audio/izumi.hps")
+        });
+        jukebox.stop_music();
+        drop(jukebox);
+    }
+
+    #[test]
+    fn doesnt_wait_forever_for_a_stuck_player_thread() {
+        // A player thread that never gets to its messages
+        let (tx, _rx) = channel();
+        let (unstick, stuck) = channel::<()>();
+        let jukebox = Jukebox {
+            tx,
+            thread: Some(std::thread::spawn(move || {
+                let _ = stuck.recv();
+            })),
+            thread_error: Arc::new(Mutex::new(None)),
+            status: Arc::new(Mutex::new(JukeboxStatus::default())),
+            progress: Mixer::new().0.progress(),
+        };
+
+        let started = Instant::now();
+        drop(jukebox);
+        assert!(started.elapsed() < STOP_TIMEOUT * 2);
+        drop(unstick);
     }
 }
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+/// Where a song loops, in sample frames (one sample per channel)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct LoopPoints {
+    #[serde(rename = "loop_start")]
+    pub start: u64,
+    /// When this is `None` the loop ends at the end of the song
+    #[serde(rename = "loop_length", skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+}
+
+/// Fully decoded audio that plays from the start, and then repeats the section
+/// between its loop points forever. This is what `hps.decode()` does for songs
+/// on the disc, so songs from other sources behave the same way.
+#[derive(Debug, Clone)]
+pub(crate) struct LoopingSource {
+    samples: Arc<[i16]>,
+    channels: u16,
+    sample_rate: u32,
+    position: usize,
+    loop_start: usize,
+    loop_end: usize,
+}
+
+impl LoopingSource {
+    /// Creates a source over interleaved `samples`. Without loop points (or
+    /// with ones that don't make sense for this audio) the whole song loops.
+    pub(crate) fn new(samples: Arc<[i16]>, channels: u16, sample_rate: u32, loop_points: Option<LoopPoints>) -> Self {
+        let channels = channels.max(1);
+        let frame_count = (samples.len() / channels as usize) as u64;
+
+        let (start, end) = match loop_points {
+            Some(LoopPoints { start, length }) => {
+                let end = length.map_or(frame_count, |length| start.saturating_add(length));
+                match start < end && end <= frame_count {
+                    true => (start, end),
+                    false => {
+                        tracing::warn!(
+                            target: dolphin_integrations::Log::Jukebox,
+                            "Ignoring loop points {loop_points:?} for a song that is {frame_count} samples long"
+                        );
+                        (0, frame_count)
+                    },
+                }
+            },
+            None => (0, frame_count),
+        };
+
+        Self {
+            samples,
+            channels,
+            sample_rate,
+            position: 0,
+            loop_start: start as usize * channels as usize,
+            loop_end: end as usize * channels as usize,
+        }
+    }
+
+    /// Creates a source over interleaved `samples` that plays once and then
+    /// ends, for songs that don't loop
+    pub(crate) fn once(samples: Arc<[i16]>, channels: u16, sample_rate: u32) -> Self {
+        Self {
+            channels: channels.max(1),
+            sample_rate,
+            position: 0,
+            loop_start: samples.len(),
+            loop_end: samples.len(),
+            samples,
+        }
+    }
+}
+
+impl Iterator for LoopingSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.position >= self.loop_end {
+            // Songs that don't loop (and empty songs) end here
+            if self.loop_start == self.loop_end {
+                return None;
+            }
+            self.position = self.loop_start;
+        }
+
+        let sample = self.samples[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for LoopingSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plays_the_intro_once_and_then_loops() {
+        let samples: Arc<[i16]> = (0..10).collect();
+        let loop_points = LoopPoints {
+            start: 2,
+            length: Some(2),
+        };
+        let source = LoopingSource::new(samples, 2, 8000, Some(loop_points));
+
+        let played: Vec<i16> = source.take(14).collect();
+        assert_eq!(played, [0, 1, 2, 3, 4, 5, 6, 7, 4, 5, 6, 7, 4, 5]);
+    }
+
+    #[test]
+    fn loops_the_whole_song_without_valid_loop_points() {
+        let samples: Arc<[i16]> = (0..4).collect();
+        let expected = [0, 1, 2, 3, 0, 1, 2, 3];
+
+        let source = LoopingSource::new(samples.clone(), 1, 8000, None);
+        assert_eq!(source.take(8).collect::<Vec<_>>(), expected);
+
+        let past_the_end = LoopPoints {
+            start: 3,
+            length: Some(2),
+        };
+        let source = LoopingSource::new(samples, 1, 8000, Some(past_the_end));
+        assert_eq!(source.take(8).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn ends_songs_that_dont_loop() {
+        let samples: Arc<[i16]> = (0..4).collect();
+        let source = LoopingSource::once(samples, 2, 8000);
+        assert_eq!(source.collect::<Vec<_>>(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn ends_empty_songs() {
+        let mut source = LoopingSource::new(Arc::new([]), 2, 8000, None);
+        assert_eq!(source.next(), None);
+    }
+}
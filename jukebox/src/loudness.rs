@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex};
+
+use dolphin_integrations::Log;
+use rodio::{Decoder, Source};
+use slippi_disc::DiscReader;
+
+use crate::iso_identity::IsoIdentity;
+use crate::song_cache::decode_hps;
+use crate::{JukeboxError::*, Result};
+
+/// Blocks quieter than this (in LUFS) are left out of the measurement
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks more than this many LU quieter than the rest of the song are left out
+/// of the measurement, so that quiet intros and breaks don't drag it down
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Limits on how much a song's volume is changed to reach the target loudness,
+/// in dB. Very quiet songs would otherwise be boosted until they clip.
+const MIN_GAIN_DB: f64 = -24.0;
+const MAX_GAIN_DB: f64 = 12.0;
+
+/// Measures the integrated loudness of interleaved `samples` in LUFS, following
+/// EBU R128 (ITU-R BS.1770). Every channel is weighted the same, which is right
+/// for mono and stereo music. Returns `None` if the audio is silent or shorter
+/// than a single 400ms block.
+pub(crate) fn integrated_loudness(samples: &[i16], channels: u16, sample_rate: u32) -> Option<f64> {
+    let channels = channels as usize;
+    let segment_frames = (sample_rate / 10) as usize;
+    if channels == 0 || segment_frames == 0 {
+        return None;
+    }
+
+    // Mean square of the K-weighted audio in each 100ms segment, summed over
+    // the channels
+    let mut filters = vec![KWeighting::new(sample_rate); channels];
+    let segments: Vec<f64> = samples
+        .chunks_exact(segment_frames * channels)
+        .map(|segment| {
+            let mut sum = 0.0;
+            for frame in segment.chunks_exact(channels) {
+                for (&sample, filter) in frame.iter().zip(filters.iter_mut()) {
+                    sum += filter.process(sample as f64 / 32768.0).powi(2);
+                }
+            }
+            sum / segment_frames as f64
+        })
+        .collect();
+
+    // Blocks are 400ms long and overlap by 75%
+    let blocks: Vec<f64> = segments.windows(4).map(|window| window.iter().sum::<f64>() / 4.0).collect();
+
+    let absolute_power = gated_mean(&blocks, ABSOLUTE_GATE)?;
+    let relative_gate = (loudness(absolute_power) + RELATIVE_GATE).max(ABSOLUTE_GATE);
+    gated_mean(&blocks, relative_gate).map(loudness)
+}
+
+/// Converts a mean square power to LUFS
+fn loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// Mean power of the blocks that are louder than `gate` (in LUFS)
+fn gated_mean(blocks: &[f64], gate: f64) -> Option<f64> {
+    let gated: Vec<f64> = blocks.iter().copied().filter(|&power| loudness(power) > gate).collect();
+    match gated.is_empty() {
+        true => None,
+        false => Some(gated.iter().sum::<f64>() / gated.len() as f64),
+    }
+}
+
+/// Volume multiplier that brings a song at `loudness` to `target` (both in
+/// LUFS)
+pub(crate) fn normalization_gain(loudness: f64, target: f64) -> f32 {
+    let gain_db = (target - loudness).clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+    10f64.powf(gain_db / 20.0) as f32
+}
+
+/// The filter that R128 applies before measuring, which roughly matches how
+/// loud people hear different frequencies: a high shelf that boosts everything
+/// above ~1.5kHz, followed by a high pass that cuts out low rumble
+#[derive(Debug, Clone)]
+struct KWeighting([Biquad; 2]);
+
+impl KWeighting {
+    /// The filters are specified at 48kHz, so they're designed from the analog
+    /// prototype for other sample rates
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+        Self([shelf, high_pass])
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.0.iter_mut().fold(sample, |sample, filter| filter.process(sample))
+    }
+}
+
+/// A second order IIR filter (transposed direct form II)
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, state: [0.0; 2] }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        let output = self.b[0] * sample + self.state[0];
+        self.state[0] = self.b[1] * sample - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * sample - self.a[1] * output;
+        output
+    }
+}
+
+/// A song that can have its loudness measured
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Track {
+    /// The hps file at this offset on the disc, with this length
+    Disc { offset: u64, length: usize },
+
+    /// A user's replacement for a song (see `find_override`)
+    Override(PathBuf),
+}
+
+impl Track {
+    /// Identifies the track in the loudness cache. Disc tracks include the
+    /// image they're on, as modded images can have different songs at the same
+    /// offset. Overrides include their size and modification time, so that
+    /// replacing the file measures it again.
+    fn key(&self, iso: &IsoIdentity) -> String {
+        match self {
+            Self::Disc { offset, length } => format!("{}{offset:x}:{length:x}", disc_key_prefix(iso)),
+            Self::Override(path) => {
+                let metadata = std::fs::metadata(path).ok();
+                let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+                let modified = metadata
+                    .and_then(|metadata| metadata.modified().ok())
+                    .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |modified| modified.as_secs());
+                format!("file:{}:{size}:{modified}", path.display())
+            },
+        }
+    }
+}
+
+/// Start of the keys of every track on the disc image `iso`
+fn disc_key_prefix(iso: &IsoIdentity) -> String {
+    format!("disc:{}:", iso.as_str())
+}
+
+/// Loudness of every track that has been measured, saved to a json file so that
+/// tracks only need to be measured once. Tracks that are silent are kept as
+/// `None`.
+#[derive(Debug, Default)]
+pub(crate) struct LoudnessCache {
+    path: Option<PathBuf>,
+    iso: IsoIdentity,
+    tracks: HashMap<String, Option<f64>>,
+}
+
+impl LoudnessCache {
+    /// Loads the cache from `path`, for tracks on the disc image `iso`. The
+    /// cache starts out empty if the file doesn't exist yet or can't be read,
+    /// and is only kept in memory if `path` is `None`.
+    ///
+    /// Measurements of tracks on any other image (including an earlier version
+    /// of this one) are dropped, and are gone from the file once it's next
+    /// saved. Overrides are kept, as they don't depend on the image.
+    pub(crate) fn load(path: Option<PathBuf>, iso: IsoIdentity) -> Self {
+        let tracks = path
+            .as_ref()
+            .filter(|path| path.is_file())
+            .and_then(
+                |path| match std::fs::read_to_string(path).map(|json| serde_json::from_str(&json)) {
+                    Ok(Ok(tracks)) => Some(tracks),
+                    Ok(Err(e)) => {
+                        tracing::warn!(target: Log::Jukebox, error = ?e, "Invalid loudness cache at {}", path.display());
+                        None
+                    },
+                    Err(e) => {
+                        tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to read loudness cache at {}", path.display());
+                        None
+                    },
+                },
+            )
+            .unwrap_or_default();
+
+        let mut cache = Self { path, iso, tracks };
+        let prefix = disc_key_prefix(&cache.iso);
+        cache
+            .tracks
+            .retain(|key, _| !key.starts_with("disc:") || key.starts_with(&prefix));
+        cache
+    }
+
+    /// Gets the loudness of `track` in LUFS. The outer `Option` is `None` if
+    /// the track hasn't been measured, and the inner one if it's silent.
+    pub(crate) fn get(&self, track: &Track) -> Option<Option<f64>> {
+        self.tracks.get(&track.key(&self.iso)).copied()
+    }
+
+    /// Records the loudness of `track` and saves the cache
+    pub(crate) fn insert(&mut self, track: &Track, loudness: Option<f64>) {
+        self.tracks.insert(track.key(&self.iso), loudness);
+
+        if let Some(path) = &self.path {
+            let saved = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(path, serde_json::to_string(&self.tracks)?));
+            if let Err(e) = saved {
+                tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to save loudness cache to {}", path.display());
+            }
+        }
+    }
+}
+
+/// Measures the loudness of tracks on a background thread, one at a time, and
+/// records them in a `LoudnessCache`. The thread stops once the analyzer is
+/// dropped.
+#[derive(Debug)]
+pub(crate) struct LoudnessAnalyzer {
+    tx: Sender<Track>,
+}
+
+impl LoudnessAnalyzer {
+    /// Starts the thread that measures tracks. Disc tracks are read from the
+    /// disc at `iso_path`.
+    pub(crate) fn start(iso_path: String, cache: Arc<Mutex<LoudnessCache>>) -> Result<Self> {
+        let (tx, rx) = channel::<Track>();
+
+        std::thread::Builder::new()
+            .name("SlippiJukeboxLoudness".to_string())
+            .spawn(move || {
+                let mut disc = None;
+
+                for track in rx {
+                    // The same track can be queued more than once if it's
+                    // played again before it has been measured
+                    if cache.lock().unwrap().get(&track).is_some() {
+                        continue;
+                    }
+
+                    match measure(&track, &iso_path, &mut disc) {
+                        Ok(loudness) => {
+                            tracing::info!(target: Log::Jukebox, "Measured loudness of {track:?}: {loudness:?} LUFS");
+                            cache.lock().unwrap().insert(&track, loudness);
+                        },
+                        Err(e) => {
+                            tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to measure loudness of {track:?}")
+                        },
+                    }
+                }
+            })
+            .map_err(ThreadSpawn)?;
+
+        Ok(Self { tx })
+    }
+
+    /// Queues `track` to be measured
+    pub(crate) fn analyze(&self, track: Track) {
+        let _ = self.tx.send(track);
+    }
+}
+
+/// Decodes all of `track` and measures its loudness. The disc is only opened
+/// once a disc track needs it.
+fn measure(track: &Track, iso_path: &str, disc: &mut Option<DiscReader>) -> Result<Option<f64>> {
+    match track {
+        Track::Disc { offset, length } => {
+            let disc = match disc {
+                Some(disc) => disc,
+                None => disc.insert(DiscReader::open(iso_path)?),
+            };
+            let song = decode_hps(disc, *offset, *length)?;
+            Ok(integrated_loudness(song.samples(), song.channels(), song.sample_rate()))
+        },
+        Track::Override(path) => {
+            let decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+            let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+            let samples: Vec<i16> = decoder.collect();
+            Ok(integrated_loudness(&samples, channels, sample_rate))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// A 1kHz sine wave in every channel at `level` dBFS
+    fn sine(level: f64, seconds: f64, channels: u16, sample_rate: u32) -> Vec<i16> {
+        let amplitude = 10f64.powf(level / 20.0) * i16::MAX as f64;
+        let frames = (seconds * sample_rate as f64) as usize;
+        (0..frames)
+            .flat_map(|frame| {
+                let sample = amplitude * (2.0 * PI * 1000.0 * frame as f64 / sample_rate as f64).sin();
+                std::iter::repeat_n(sample as i16, channels as usize)
+            })
+            .collect()
+    }
+
+    fn write_wav(path: &std::path::Path, samples: &[i16], sample_rate: u32) {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        samples.iter().for_each(|&sample| writer.write_sample(sample).unwrap());
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn measures_the_loudness_of_a_sine_wave() {
+        // EBU Tech 3341: a stereo 1kHz sine at -23 dBFS measures -23 LUFS
+        let loudness = integrated_loudness(&sine(-23.0, 5.0, 2, 48000), 2, 48000).unwrap();
+        assert!((loudness - -23.0).abs() < 0.1, "{loudness}");
+
+        // Songs on the disc are 32kHz
+        let loudness = integrated_loudness(&sine(-20.0, 5.0, 2, 32000), 2, 32000).unwrap();
+        assert!((loudness - -20.0).abs() < 0.1, "{loudness}");
+    }
+
+    #[test]
+    fn leaves_silence_out_of_the_measurement() {
+        let mut samples = sine(-23.0, 5.0, 2, 48000);
+        samples.extend(std::iter::repeat_n(0, 48000 * 2 * 10));
+        // The few blocks that straddle the end of the sine still count
+        let loudness = integrated_loudness(&samples, 2, 48000).unwrap();
+        assert!((loudness - -23.0).abs() < 0.2, "{loudness}");
+
+        assert_eq!(integrated_loudness(&vec![0; 48000 * 2], 2, 48000), None);
+        assert_eq!(integrated_loudness(&sine(-23.0, 0.3, 2, 48000), 2, 48000), None);
+    }
+
+    #[test]
+    fn limits_the_normalization_gain() {
+        assert!((normalization_gain(-23.0, -18.0) - 1.778).abs() < 0.001);
+        assert!((normalization_gain(-13.0, -18.0) - 0.562).abs() < 0.001);
+        assert!((normalization_gain(-60.0, -18.0) - 3.981).abs() < 0.001);
+    }
+
+    #[test]
+    fn saves_measurements_to_disk() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("Jukebox").join("loudness.json");
+        let track = Track::Disc {
+            offset: 0x13F00,
+            length: 0x500,
+        };
+        let silent_track = Track::Disc {
+            offset: 0x15000,
+            length: 0x320,
+        };
+
+        let iso = IsoIdentity::for_tests("GALE01-2-57058000-1");
+        let mut cache = LoudnessCache::load(Some(path.clone()), iso.clone());
+        assert_eq!(cache.get(&track), None);
+        cache.insert(&track, Some(-20.5));
+        cache.insert(&silent_track, None);
+
+        let cache = LoudnessCache::load(Some(path), iso);
+        assert_eq!(cache.get(&track), Some(Some(-20.5)));
+        assert_eq!(cache.get(&silent_track), Some(None));
+    }
+
+    #[test]
+    fn forgets_disc_tracks_once_the_iso_changes() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("loudness.json");
+        let track = Track::Disc {
+            offset: 0x13F00,
+            length: 0x500,
+        };
+        let wav = folder.path().join("izumi.wav");
+        write_wav(&wav, &sine(-23.0, 0.5, 2, 44100), 44100);
+        let override_track = Track::Override(wav);
+
+        let mut cache = LoudnessCache::load(Some(path.clone()), IsoIdentity::for_tests("GALE01-2-57058000-1"));
+        cache.insert(&track, Some(-20.5));
+        cache.insert(&override_track, Some(-23.0));
+
+        // A modded image can have a different song at the same offset
+        let mut cache = LoudnessCache::load(Some(path.clone()), IsoIdentity::for_tests("GALE01-2-57058000-2"));
+        assert_eq!(cache.get(&track), None);
+        assert_eq!(cache.get(&override_track), Some(Some(-23.0)));
+
+        // The old image's measurements are gone from the file once it's saved
+        cache.insert(&track, Some(-18.0));
+        let cache = LoudnessCache::load(Some(path), IsoIdentity::for_tests("GALE01-2-57058000-1"));
+        assert_eq!(cache.get(&track), None);
+        assert_eq!(cache.get(&override_track), Some(Some(-23.0)));
+    }
+
+    #[test]
+    fn measures_tracks_in_the_background() {
+        let folder = tempfile::tempdir().unwrap();
+        let wav = folder.path().join("izumi.wav");
+        write_wav(&wav, &sine(-23.0, 2.0, 2, 44100), 44100);
+
+        let cache = Arc::new(Mutex::new(LoudnessCache::default()));
+        let analyzer = LoudnessAnalyzer::start("../disc/test-data/tiny-disc.iso".to_string(), cache.clone()).unwrap();
+        let tracks = [
+            Track::Disc {
+                offset: 0x13F00,
+                length: 0x500,
+            },
+            Track::Override(wav),
+        ];
+        tracks.iter().for_each(|track| analyzer.analyze(track.clone()));
+
+        let started = Instant::now();
+        while tracks.iter().any(|track| cache.lock().unwrap().get(track).is_none()) {
+            assert!(started.elapsed() < Duration::from_secs(5), "tracks were not measured");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let loudness = cache.lock().unwrap().get(&tracks[1]).flatten().unwrap();
+        assert!((loudness - -23.0).abs() < 0.1, "{loudness}");
+    }
+}
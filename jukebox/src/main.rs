@@ -0,0 +1,156 @@
+//! Inspects, plays and exports the music on a Melee disc image without running
+//! the emulator. Music is read and played the same way it is in Dolphin, so
+//! this is handy for checking ISOs and music mods.
+
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use slippi_jukebox::{AudioBackend, Config, DiscMusic, Jukebox, PlaybackState};
+
+const USAGE: &str = "\
+Usage:
+  slippi-jukebox list <iso>
+      Lists every hps file on the disc with its offset, length, format and
+      loop point
+  slippi-jukebox play <iso> <track> [--music-folder <folder>]
+      Plays a track (e.g. menu01.hps) until it ends or Ctrl+C is pressed.
+      Overrides in the music folder are played in place of the disc's music.
+  slippi-jukebox export <iso> <folder> [track...]
+      Writes tracks (or every track) to WAV files in the folder, along with
+      json files with the loop points of tracks that loop";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["list", iso_path] => list(iso_path),
+        ["play", iso_path, track] => play(iso_path, track, None),
+        ["play", iso_path, track, "--music-folder", music_folder] => play(iso_path, track, Some(Path::new(music_folder))),
+        ["export", iso_path, folder, tracks @ ..] => export(iso_path, Path::new(folder), tracks),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+fn list(iso_path: &str) -> Result<()> {
+    let mut music = DiscMusic::open(iso_path)?;
+
+    let header = music.header()?;
+    match Jukebox::supports_game(&header) {
+        true => println!("{header}\n"),
+        false => println!("{header} (not supported by Slippi Jukebox)\n"),
+    }
+
+    println!(
+        "{:<24} {:>10} {:>10} {:>7} {:>8} {:>10} {:>10}",
+        "Path", "Offset", "Length", "Rate", "Channels", "Duration", "Loop"
+    );
+    for track in music.tracks() {
+        let offset = format!("0x{:X}", track.offset);
+        let length = format!("0x{:X}", track.length);
+        print!("{:<24} {offset:>10} {length:>10} ", track.path);
+
+        match music.info(&track) {
+            Ok(info) => {
+                let duration = format_frames(info.frames, info.sample_rate);
+                let loop_start = match info.loop_start {
+                    Some(start) => format_frames(start, info.sample_rate),
+                    None => "-".to_string(),
+                };
+                println!("{:>7} {:>8} {duration:>10} {loop_start:>10}", info.sample_rate, info.channels);
+            },
+            Err(e) => println!("{e}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn play(iso_path: &str, name: &str, music_folder: Option<&Path>) -> Result<()> {
+    let track = DiscMusic::open(iso_path)?
+        .find(name)
+        .ok_or_else(|| format!("{name} is not on the disc"))?;
+
+    // Without a music folder, only the disc's music is played
+    let music_folder = music_folder.map(Path::to_path_buf);
+    let mut config = Config::new(iso_path.to_string(), music_folder, 100, 100);
+    config.audio_backend = AudioBackend::Device;
+
+    let mut jukebox = Jukebox::new(config)?;
+    jukebox.play_file(&track.path);
+
+    // Songs that loop play until the process is stopped
+    let mut started = false;
+    loop {
+        std::thread::sleep(Duration::from_millis(100));
+        let status = jukebox.status();
+
+        match status.state {
+            PlaybackState::Loading => {},
+            PlaybackState::Playing => {
+                started = true;
+                let elapsed = format_frames(status.elapsed_samples, status.sample_rate);
+                eprint!("\rPlaying {} {elapsed}", track.path);
+            },
+            PlaybackState::Idle if started => {
+                eprintln!();
+                return Ok(());
+            },
+            PlaybackState::Idle => {},
+            PlaybackState::Error => return Err(status.error.unwrap_or_default().into()),
+        }
+    }
+}
+
+fn export(iso_path: &str, folder: &Path, names: &[&str]) -> Result<()> {
+    let mut music = DiscMusic::open(iso_path)?;
+
+    let tracks = match names.is_empty() {
+        true => music.tracks(),
+        false => names
+            .iter()
+            .map(|name| music.find(name).ok_or_else(|| format!("{name} is not on the disc")))
+            .collect::<std::result::Result<_, _>>()?,
+    };
+
+    std::fs::create_dir_all(folder)?;
+
+    let mut failed = 0;
+    for track in &tracks {
+        let file_name = Path::new(&track.path).file_stem().unwrap_or_default();
+        let wav_path = folder.join(file_name).with_extension("wav");
+
+        match music.export_wav(track, &wav_path) {
+            Ok(()) => println!("{} -> {}", track.path, wav_path.display()),
+            Err(e) => {
+                eprintln!("{}: {e}", track.path);
+                failed += 1;
+            },
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{failed} of {} tracks could not be exported", tracks.len()).into()),
+    }
+}
+
+/// Formats a number of sample frames as minutes and seconds
+fn format_frames(frames: u64, sample_rate: u32) -> String {
+    let millis = frames * 1000 / sample_rate.max(1) as u64;
+    format!("{}:{:02}.{:03}", millis / 60000, millis / 1000 % 60, millis % 1000)
+}
@@ -0,0 +1,610 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
+use std::time::Duration;
+
+use rodio::Source;
+use rodio::source::UniformSourceIterator;
+
+use crate::ChannelMode;
+use crate::output::MirrorBuffer;
+
+/// Format that everything is mixed in. Songs with a different number of
+/// channels or sample rate are converted to this as they play.
+pub(crate) const MIXER_CHANNELS: u16 = 2;
+pub(crate) const MIXER_SAMPLE_RATE: u32 = 48000;
+
+/// Lowest and highest speeds that music can be played at
+const MIN_SPEED: f32 = 0.1;
+const MAX_SPEED: f32 = 4.0;
+
+/// One sample for each channel
+type Frame = [f32; MIXER_CHANNELS as usize];
+
+/// Any song that jukebox can play
+pub(crate) type Song = Box<dyn Source<Item = i16> + Send>;
+
+enum MixerCommand {
+    Play(Song, Duration, u64),
+    Stop(Duration),
+    SetVolume(f32),
+    SetPaused(bool),
+    SetSpeed(f32),
+    SetChannelMode(ChannelMode),
+    SetBalance(f32),
+}
+
+/// How far along the mixer is with the song that was played last. This is
+/// shared by the mixer and its source, and can be read from any thread.
+#[derive(Debug, Default)]
+pub(crate) struct PlaybackProgress {
+    /// Id of the last song passed to `Mixer::play`
+    latest_song: AtomicU64,
+    /// Id of the last song that ended on its own
+    finished_song: AtomicU64,
+    /// Sample frames of the latest song that have been mixed so far
+    elapsed_frames: AtomicU64,
+}
+
+impl PlaybackProgress {
+    /// Sample frames of the latest song that have played, at the mixer's
+    /// sample rate
+    pub(crate) fn elapsed_frames(&self) -> u64 {
+        self.elapsed_frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns `false` once the latest song has reached its end. Songs that
+    /// loop never do.
+    pub(crate) fn is_playing(&self) -> bool {
+        self.latest_song.load(Ordering::Relaxed) != self.finished_song.load(Ordering::Relaxed)
+    }
+}
+
+/// Controls the audio of a `MixerSource`, which is playing on the audio
+/// device. The mixer has room for two songs at a time: the song that is
+/// playing, and the previous song while it fades out.
+#[derive(Debug)]
+pub(crate) struct Mixer {
+    tx: Sender<MixerCommand>,
+    progress: Arc<PlaybackProgress>,
+}
+
+impl Mixer {
+    /// Creates a mixer along with the source that outputs its audio. The source
+    /// plays silence when there is no music, and ends once the mixer is
+    /// dropped.
+    pub(crate) fn new() -> (Self, MixerSource) {
+        let (tx, rx) = channel();
+        let progress = Arc::new(PlaybackProgress::default());
+        let source = MixerSource {
+            rx,
+            progress: progress.clone(),
+            current: None,
+            outgoing: None,
+            volume: 1.0,
+            paused: false,
+            channel_mode: ChannelMode::Stereo,
+            balance: 0.0,
+            speed: 1.0,
+            resampler: None,
+            resampler_phase: 0.0,
+            frame: [0.0; MIXER_CHANNELS as usize],
+            channel: 0,
+            mirror: None,
+        };
+
+        (Self { tx, progress }, source)
+    }
+
+    /// Starts playing `song`. The song that was playing fades out while the
+    /// new one fades in over `crossfade`, or is cut off if it's zero.
+    pub(crate) fn play(&self, song: Song, crossfade: Duration) {
+        let id = self.progress.latest_song.fetch_add(1, Ordering::Relaxed) + 1;
+        let _ = self.tx.send(MixerCommand::Play(song, crossfade, id));
+    }
+
+    /// Fades out the song that is playing over `fade_out`, or cuts it off if
+    /// it's zero
+    pub(crate) fn stop(&self, fade_out: Duration) {
+        let _ = self.tx.send(MixerCommand::Stop(fade_out));
+    }
+
+    /// Sets the volume of everything the mixer plays, from 0.0 to 1.0
+    pub(crate) fn set_volume(&self, volume: f32) {
+        let _ = self.tx.send(MixerCommand::SetVolume(volume));
+    }
+
+    /// Pauses or resumes everything the mixer plays. Songs pick up exactly
+    /// where they were when resumed.
+    pub(crate) fn set_paused(&self, paused: bool) {
+        let _ = self.tx.send(MixerCommand::SetPaused(paused));
+    }
+
+    /// Plays everything faster or slower than normal, where 1.0 is normal
+    /// speed. The pitch changes along with the speed, like a record would.
+    pub(crate) fn set_speed(&self, speed: f32) {
+        let _ = self.tx.send(MixerCommand::SetSpeed(speed));
+    }
+
+    /// Sets whether music plays in stereo, or is mixed down to mono
+    pub(crate) fn set_channel_mode(&self, channel_mode: ChannelMode) {
+        let _ = self.tx.send(MixerCommand::SetChannelMode(channel_mode));
+    }
+
+    /// Shifts music toward the left (-1.0) or right (1.0) speaker. At 0.0,
+    /// both speakers are at full volume.
+    pub(crate) fn set_balance(&self, balance: f32) {
+        let _ = self.tx.send(MixerCommand::SetBalance(balance));
+    }
+
+    /// Returns the progress of the song that was played last
+    pub(crate) fn progress(&self) -> Arc<PlaybackProgress> {
+        self.progress.clone()
+    }
+}
+
+/// A song in the mixer, along with how loud it currently is
+struct Deck {
+    id: u64,
+    samples: UniformSourceIterator<Song, f32>,
+    gain: f32,
+    target_gain: f32,
+    /// How much the gain moves toward the target every sample frame
+    gain_step: f32,
+}
+
+impl Deck {
+    fn new(id: u64, song: Song, gain: f32) -> Self {
+        Self {
+            id,
+            samples: UniformSourceIterator::new(song, MIXER_CHANNELS, MIXER_SAMPLE_RATE),
+            gain,
+            target_gain: gain,
+            gain_step: 0.0,
+        }
+    }
+
+    /// Moves the gain to `target_gain` linearly over `duration`
+    fn ramp_to(&mut self, target_gain: f32, duration: Duration) {
+        let frames = duration.as_secs_f32() * MIXER_SAMPLE_RATE as f32;
+        self.target_gain = target_gain;
+        self.gain_step = match frames >= 1.0 {
+            true => (target_gain - self.gain).abs() / frames,
+            false => f32::INFINITY,
+        };
+    }
+
+    /// Takes the samples of the song's next sample frame
+    fn next_frame(&mut self) -> Option<Frame> {
+        Some([self.samples.next()?, self.samples.next()?])
+    }
+
+    /// Advances the gain ramp by one sample frame
+    fn step_gain(&mut self) {
+        self.gain = match self.gain < self.target_gain {
+            true => (self.gain + self.gain_step).min(self.target_gain),
+            false => (self.gain - self.gain_step).max(self.target_gain),
+        };
+    }
+
+    fn is_silent(&self) -> bool {
+        self.gain <= 0.0 && self.target_gain <= 0.0
+    }
+}
+
+/// The audio output of a `Mixer`
+pub(crate) struct MixerSource {
+    rx: Receiver<MixerCommand>,
+    progress: Arc<PlaybackProgress>,
+    current: Option<Deck>,
+    outgoing: Option<Deck>,
+    volume: f32,
+    paused: bool,
+    speed: f32,
+    channel_mode: ChannelMode,
+    balance: f32,
+
+    /// The two frames of the mix that are being interpolated between, when
+    /// not playing at normal speed, and how far between them the output is
+    resampler: Option<(Frame, Frame)>,
+    resampler_phase: f32,
+
+    /// The frame being output, and the channel of the next sample. Commands
+    /// are only applied between frames.
+    frame: Frame,
+    channel: u16,
+
+    /// Where everything the mixer outputs is copied to, if it is being
+    /// mirrored to a second output
+    mirror: Option<Arc<MirrorBuffer>>,
+}
+
+impl MixerSource {
+    /// Copies everything that is taken from the mixer with `take_chunk` into
+    /// `mirror`, or stops copying it if `None`
+    pub(crate) fn set_mirror(&mut self, mirror: Option<Arc<MirrorBuffer>>) {
+        self.mirror = mirror;
+    }
+
+    /// Appends the next `size` samples of the mix to `buffer`, and copies
+    /// them to the mirror if there is one. `size` should be a whole number of
+    /// frames.
+    pub(crate) fn take_chunk(&mut self, buffer: &mut Vec<f32>, size: usize) {
+        let start = buffer.len();
+        buffer.extend(self.by_ref().take(size));
+
+        if let Some(mirror) = &self.mirror {
+            mirror.push(&buffer[start..]);
+        }
+    }
+
+    fn apply(&mut self, command: MixerCommand) {
+        match command {
+            MixerCommand::Play(song, crossfade, id) => {
+                let mut deck = Deck::new(id, song, 1.0);
+                self.progress.elapsed_frames.store(0, Ordering::Relaxed);
+
+                if crossfade.is_zero() {
+                    self.outgoing = None;
+                } else if let Some(mut current) = self.current.take() {
+                    current.ramp_to(0.0, crossfade);
+                    self.outgoing = Some(current);
+
+                    deck.gain = 0.0;
+                    deck.ramp_to(1.0, crossfade);
+                }
+
+                self.current = Some(deck);
+            },
+            MixerCommand::Stop(fade_out) => {
+                if fade_out.is_zero() {
+                    self.current = None;
+                    self.outgoing = None;
+                } else if let Some(mut current) = self.current.take() {
+                    current.ramp_to(0.0, fade_out);
+                    self.outgoing = Some(current);
+                }
+            },
+            MixerCommand::SetVolume(volume) => self.volume = volume,
+            MixerCommand::SetPaused(paused) => self.paused = paused,
+            MixerCommand::SetChannelMode(channel_mode) => self.channel_mode = channel_mode,
+            MixerCommand::SetBalance(balance) => {
+                self.balance = match balance.is_finite() {
+                    true => balance.clamp(-1.0, 1.0),
+                    false => 0.0,
+                };
+            },
+            MixerCommand::SetSpeed(speed) => {
+                self.speed = match speed.is_finite() {
+                    true => speed.clamp(MIN_SPEED, MAX_SPEED),
+                    false => 1.0,
+                };
+
+                // Going back to normal speed drops the frames that were held
+                // back for interpolating
+                if self.speed == 1.0 {
+                    self.resampler = None;
+                    self.resampler_phase = 0.0;
+                }
+            },
+        }
+    }
+}
+
+impl MixerSource {
+    /// Mixes the next sample frame of every song at normal speed. Songs that
+    /// end are removed, and the mixer plays silence when there is nothing left.
+    fn mix_frame(&mut self) -> Frame {
+        for deck in [&mut self.current, &mut self.outgoing].into_iter().flatten() {
+            deck.step_gain();
+        }
+
+        if self.outgoing.as_ref().is_some_and(Deck::is_silent) {
+            self.outgoing = None;
+        }
+
+        let mut frame = [0.0; MIXER_CHANNELS as usize];
+
+        if let Some(deck) = &mut self.current {
+            match deck.next_frame() {
+                Some(samples) => {
+                    add_frame(&mut frame, samples, deck.gain);
+                    self.progress.elapsed_frames.fetch_add(1, Ordering::Relaxed);
+                },
+                None => {
+                    self.progress.finished_song.store(deck.id, Ordering::Relaxed);
+                    self.current = None;
+                },
+            }
+        }
+
+        if let Some(deck) = &mut self.outgoing {
+            match deck.next_frame() {
+                Some(samples) => add_frame(&mut frame, samples, deck.gain),
+                None => self.outgoing = None,
+            }
+        }
+
+        let [left, right] = match self.channel_mode {
+            ChannelMode::Stereo => frame,
+            ChannelMode::Mono => [(frame[0] + frame[1]) / 2.0; 2],
+        };
+
+        // The side that the balance is shifted toward stays at full volume,
+        // and the other side gets quieter
+        let left = left * (1.0 - self.balance).min(1.0);
+        let right = right * (1.0 + self.balance).min(1.0);
+
+        [left, right].map(|sample| (sample * self.volume).clamp(-1.0, 1.0))
+    }
+
+    /// Mixes the next sample frame at the current speed, by stepping through
+    /// the mix faster or slower than normal and interpolating between frames
+    fn resample_frame(&mut self) -> Frame {
+        let (mut previous, mut next) = match self.resampler.take() {
+            Some(frames) => frames,
+            None => (self.mix_frame(), self.mix_frame()),
+        };
+
+        let phase = self.resampler_phase;
+        let frame = std::array::from_fn(|channel| previous[channel] + (next[channel] - previous[channel]) * phase);
+
+        self.resampler_phase += self.speed;
+        while self.resampler_phase >= 1.0 {
+            previous = next;
+            next = self.mix_frame();
+            self.resampler_phase -= 1.0;
+        }
+
+        self.resampler = Some((previous, next));
+        frame
+    }
+}
+
+/// Adds the samples of a song's frame into the mix at `gain`
+fn add_frame(frame: &mut Frame, samples: Frame, gain: f32) {
+    for (mixed, sample) in frame.iter_mut().zip(samples) {
+        *mixed += sample * gain;
+    }
+}
+
+impl Iterator for MixerSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            loop {
+                match self.rx.try_recv() {
+                    Ok(command) => self.apply(command),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return None,
+                }
+            }
+
+            // Songs don't move forward at all while the game is paused
+            self.frame = match (self.paused, self.speed == 1.0) {
+                (true, _) => [0.0; MIXER_CHANNELS as usize],
+                (false, true) => self.mix_frame(),
+                (false, false) => self.resample_frame(),
+            };
+        }
+
+        let sample = self.frame[self.channel as usize];
+        self.channel = (self.channel + 1) % MIXER_CHANNELS;
+        Some(sample)
+    }
+}
+
+impl Source for MixerSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        MIXER_CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        MIXER_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    /// A song that holds the same sample for `frames` sample frames
+    fn song(sample: i16, frames: usize) -> Song {
+        Box::new(SamplesBuffer::new(
+            MIXER_CHANNELS,
+            MIXER_SAMPLE_RATE,
+            vec![sample; frames * 2],
+        ))
+    }
+
+    /// Takes `frames` sample frames from the mixer, and returns the left
+    /// channel of each
+    fn take_frames(source: &mut MixerSource, frames: usize) -> Vec<f32> {
+        source.take(frames * 2).step_by(2).collect()
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 0.01, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn plays_silence_until_the_mixer_is_dropped() {
+        let (mixer, mut source) = Mixer::new();
+        assert!(take_frames(&mut source, 100).iter().all(|&sample| sample == 0.0));
+
+        mixer.play(song(16384, 10), Duration::ZERO);
+        assert!(take_frames(&mut source, 10).iter().all(|&sample| sample > 0.0));
+        assert!(take_frames(&mut source, 10).iter().all(|&sample| sample == 0.0));
+
+        drop(mixer);
+        assert_eq!(source.next(), None);
+    }
+
+    #[test]
+    fn cuts_between_songs_without_a_crossfade() {
+        let (mixer, mut source) = Mixer::new();
+        mixer.play(song(16384, 1000), Duration::ZERO);
+        assert_close(take_frames(&mut source, 1)[0], 0.5);
+
+        mixer.play(song(-8192, 1000), Duration::ZERO);
+        assert_close(take_frames(&mut source, 1)[0], -0.25);
+
+        mixer.stop(Duration::ZERO);
+        assert_eq!(take_frames(&mut source, 1)[0], 0.0);
+    }
+
+    #[test]
+    fn fades_out_when_stopping() {
+        let (mixer, mut source) = Mixer::new();
+        mixer.play(song(16384, 10000), Duration::ZERO);
+        take_frames(&mut source, 100);
+
+        // 10ms is 480 sample frames
+        mixer.stop(Duration::from_millis(10));
+        let fade = take_frames(&mut source, 480);
+        assert!(fade.windows(2).all(|pair| pair[1] < pair[0]));
+        assert_close(fade[240], 0.25);
+
+        assert!(take_frames(&mut source, 100).iter().all(|&sample| sample == 0.0));
+        assert!(source.outgoing.is_none());
+    }
+
+    #[test]
+    fn crossfades_between_songs() {
+        let (mixer, mut source) = Mixer::new();
+        mixer.play(song(16384, 10000), Duration::ZERO);
+        take_frames(&mut source, 100);
+
+        mixer.play(song(8192, 10000), Duration::from_millis(10));
+        let crossfade = take_frames(&mut source, 480);
+        assert_close(crossfade[0], 0.5);
+        assert_close(crossfade[240], 0.375);
+        assert_close(crossfade[479], 0.25);
+
+        // The old song is gone once it has faded out
+        assert_close(take_frames(&mut source, 1)[0], 0.25);
+        assert!(source.outgoing.is_none());
+    }
+
+    #[test]
+    fn tracks_the_progress_of_the_latest_song() {
+        let (mixer, mut source) = Mixer::new();
+        let progress = mixer.progress();
+        assert!(!progress.is_playing());
+
+        mixer.play(song(16384, 100), Duration::ZERO);
+        assert!(progress.is_playing());
+        take_frames(&mut source, 60);
+        assert_eq!(progress.elapsed_frames(), 60);
+
+        // Starting another song restarts the count
+        mixer.play(song(16384, 100), Duration::from_millis(10));
+        take_frames(&mut source, 20);
+        assert_eq!(progress.elapsed_frames(), 20);
+
+        take_frames(&mut source, 100);
+        assert_eq!(progress.elapsed_frames(), 100);
+        assert!(!progress.is_playing());
+    }
+
+    #[test]
+    fn holds_songs_in_place_while_paused() {
+        let (mixer, mut source) = Mixer::new();
+        let progress = mixer.progress();
+        mixer.play(
+            Box::new(SamplesBuffer::new(2, MIXER_SAMPLE_RATE, (0..200).collect::<Vec<i16>>())),
+            Duration::ZERO,
+        );
+        take_frames(&mut source, 10);
+
+        mixer.set_paused(true);
+        assert!(take_frames(&mut source, 100).iter().all(|&sample| sample == 0.0));
+        assert_eq!(progress.elapsed_frames(), 10);
+
+        mixer.set_paused(false);
+        assert_close(take_frames(&mut source, 1)[0] * 32768.0, 20.0);
+    }
+
+    #[test]
+    fn changes_speed_by_resampling() {
+        // The left channel counts up by one every frame
+        let samples: Vec<i16> = (0..2000).flat_map(|frame| [frame * 10, 0]).collect();
+        let (mixer, mut source) = Mixer::new();
+        let progress = mixer.progress();
+        mixer.play(Box::new(SamplesBuffer::new(2, MIXER_SAMPLE_RATE, samples)), Duration::ZERO);
+
+        // Half speed plays every frame twice, interpolating in between
+        mixer.set_speed(0.5);
+        let played: Vec<f32> = take_frames(&mut source, 8)
+            .iter()
+            .map(|sample| sample * 32768.0 / 10.0)
+            .collect();
+        for (played, expected) in played.into_iter().zip([0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 3.5]) {
+            assert_close(played, expected);
+        }
+
+        // Double speed skips every other frame
+        mixer.set_speed(2.0);
+        take_frames(&mut source, 100);
+        let frames_before = progress.elapsed_frames();
+        take_frames(&mut source, 100);
+        assert_eq!(progress.elapsed_frames() - frames_before, 200);
+
+        // Speeds are kept within reason
+        mixer.set_speed(100.0);
+        take_frames(&mut source, 1);
+        assert_eq!(source.speed, MAX_SPEED);
+        mixer.set_speed(f32::NAN);
+        take_frames(&mut source, 1);
+        assert_eq!(source.speed, 1.0);
+    }
+
+    #[test]
+    fn mixes_down_to_mono() {
+        let (mixer, mut source) = Mixer::new();
+        mixer.set_channel_mode(ChannelMode::Mono);
+        mixer.play(
+            Box::new(SamplesBuffer::new(2, MIXER_SAMPLE_RATE, vec![16384, 0])),
+            Duration::ZERO,
+        );
+
+        let frame: Vec<f32> = source.by_ref().take(2).collect();
+        assert_close(frame[0], 0.25);
+        assert_close(frame[1], 0.25);
+    }
+
+    #[test]
+    fn shifts_the_balance_between_speakers() {
+        let (mixer, mut source) = Mixer::new();
+        mixer.play(song(16384, 1000), Duration::ZERO);
+
+        mixer.set_balance(0.5);
+        let frame: Vec<f32> = source.by_ref().take(2).collect();
+        assert_close(frame[0], 0.25);
+        assert_close(frame[1], 0.5);
+
+        mixer.set_balance(-1.0);
+        let frame: Vec<f32> = source.by_ref().take(2).collect();
+        assert_close(frame[0], 0.5);
+        assert_close(frame[1], 0.0);
+    }
+
+    #[test]
+    fn applies_the_volume_to_everything() {
+        let (mixer, mut source) = Mixer::new();
+        mixer.set_volume(0.5);
+        mixer.play(song(16384, 1000), Duration::ZERO);
+        assert_close(take_frames(&mut source, 1)[0], 0.25);
+    }
+}
@@ -0,0 +1,359 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use dolphin_integrations::Log;
+use lewton::inside_ogg::OggStreamReader;
+use rodio::{Decoder, Source};
+
+use crate::Result;
+use crate::looping_source::LoopPoints;
+
+/// Extensions of the audio files that can replace a song, in order of
+/// preference when more than one exists
+const OVERRIDE_EXTENSIONS: [&str; 4] = ["ogg", "flac", "wav", "mp3"];
+
+/// Finds a user provided audio file in `music_folder` that should be played in
+/// place of the hps file at `hps_path` on the disc. Overrides are named after
+/// the hps file, so `audio/izumi.hps` is replaced by `izumi.ogg`, `izumi.flac`,
+/// `izumi.wav` or `izumi.mp3`.
+pub(crate) fn find_override(music_folder: &Path, hps_path: &str) -> Option<PathBuf> {
+    let file_name = hps_path.rsplit('/').next()?;
+    let song_name = Path::new(file_name).file_stem()?;
+
+    OVERRIDE_EXTENSIONS
+        .iter()
+        .map(|extension| music_folder.join(song_name).with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+/// Opens the override at `path` so that it can be played back. The song loops
+/// according to its loop points (see `read_loop_points`), or from the start if
+/// it has none. Only opening the file and probing its format happen here, and
+/// the audio is decoded as it plays.
+pub(crate) fn open_override(path: &Path) -> Result<OverrideStream> {
+    let decoder = open_decoder(path)?;
+    let channels = decoder.channels().max(1);
+
+    // A loop has to have something in it, and has to fit in a position
+    let loop_points = read_loop_points(path).filter(|loop_points| loop_points.length != Some(0));
+    let (loop_start, loop_end) = match loop_points.map(|loop_points| loop_sample_range(loop_points, channels)) {
+        Some(Some(range)) => range,
+        Some(None) => {
+            tracing::warn!(target: Log::Jukebox, "Ignoring loop points of {}, which are too large", path.display());
+            (0, None)
+        },
+        None => (0, None),
+    };
+
+    Ok(OverrideStream {
+        path: path.to_path_buf(),
+        channels,
+        sample_rate: decoder.sample_rate(),
+        decoder,
+        position: 0,
+        loop_start,
+        loop_end,
+        next_loop: None,
+    })
+}
+
+/// Converts `loop_points` (in sample frames) into the interleaved samples that
+/// the loop starts and ends at, or `None` if they don't fit in a `u64`
+fn loop_sample_range(loop_points: LoopPoints, channels: u16) -> Option<(u64, Option<u64>)> {
+    let loop_start = loop_points.start.checked_mul(channels as u64)?;
+    let loop_end = match loop_points.length {
+        Some(length) => Some(length.checked_mul(channels as u64)?.checked_add(loop_start)?),
+        None => None,
+    };
+
+    Some((loop_start, loop_end))
+}
+
+fn open_decoder(path: &Path) -> Result<Decoder<BufReader<File>>> {
+    Ok(Decoder::new(BufReader::new(File::open(path)?))?)
+}
+
+/// Plays an override while decoding it, the same way `HpsStream` plays songs on
+/// the disc, so the song never has to be held in memory.
+///
+/// Most formats can't seek, so looping means decoding the file again from the
+/// start up to the loop start. That's done by a second decoder a sample at a
+/// time alongside playback, so that it's already at the loop start by the time
+/// the song gets to its loop end, and looping costs the same as playing.
+pub(crate) struct OverrideStream {
+    path: PathBuf,
+    decoder: Decoder<BufReader<File>>,
+    channels: u16,
+    sample_rate: u32,
+
+    /// Positions in interleaved samples, rather than sample frames
+    position: u64,
+    loop_start: u64,
+    loop_end: Option<u64>,
+
+    /// The decoder that the next loop plays from, and how many samples it has
+    /// skipped toward the loop start so far
+    next_loop: Option<(Decoder<BufReader<File>>, u64)>,
+}
+
+impl OverrideStream {
+    /// Moves the decoder for the next loop one sample closer to the loop start,
+    /// opening it first if needed
+    fn prepare_next_loop(&mut self) {
+        if self.next_loop.is_none() {
+            match open_decoder(&self.path) {
+                Ok(decoder) => self.next_loop = Some((decoder, 0)),
+                Err(e) => {
+                    tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to reopen {}", self.path.display());
+                    return;
+                },
+            }
+        }
+
+        if let Some((decoder, skipped)) = &mut self.next_loop {
+            if *skipped < self.loop_start && decoder.next().is_some() {
+                *skipped += 1;
+            }
+        }
+    }
+
+    /// Continues from the loop start, with the decoder that has been skipping
+    /// ahead to it if that's ready, and otherwise by decoding up to it now
+    fn restart(&mut self) -> Result<()> {
+        let decoder = match self.next_loop.take() {
+            Some((decoder, skipped)) if skipped == self.loop_start => decoder,
+            _ => {
+                let mut decoder = open_decoder(&self.path)?;
+                if self.loop_start > 0 {
+                    decoder.nth(self.loop_start as usize - 1);
+                }
+                decoder
+            },
+        };
+
+        self.decoder = decoder;
+        self.position = self.loop_start;
+        Ok(())
+    }
+}
+
+impl Iterator for OverrideStream {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        // Restarting always leads to a sample unless the file has become
+        // empty, so this only tries twice rather than restarting forever
+        for _ in 0..2 {
+            let at_loop_end = self.loop_end.is_some_and(|end| self.position >= end);
+            if !at_loop_end {
+                if let Some(sample) = self.decoder.next() {
+                    self.position += 1;
+                    self.prepare_next_loop();
+                    return Some(sample);
+                }
+
+                // The file ended before the loop started, so the loop points
+                // don't fit this song and the whole song loops instead
+                if self.position <= self.loop_start && self.loop_start > 0 {
+                    tracing::warn!(
+                        target: Log::Jukebox,
+                        "Ignoring loop points of {}, which is only {} samples long",
+                        self.path.display(),
+                        self.position / self.channels as u64
+                    );
+                    self.loop_start = 0;
+                    self.loop_end = None;
+                }
+
+                if self.position == 0 {
+                    return None;
+                }
+            }
+
+            if let Err(e) = self.restart() {
+                tracing::error!(target: Log::Jukebox, error = ?e, "Failed to loop {}. Stopping song.", self.path.display());
+                return None;
+            }
+        }
+
+        None
+    }
+}
+
+impl Source for OverrideStream {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Reads the loop points of the override at `path`. A json file next to it
+/// with the same name (e.g. `izumi.json`) takes priority, and otherwise the
+/// `LOOPSTART` and `LOOPLENGTH` tags of ogg and flac files are used.
+///
+/// The json file looks like `{ "loop_start": 44100, "loop_length": 88200 }`,
+/// where both values are in samples and `loop_length` is optional.
+pub(crate) fn read_loop_points(path: &Path) -> Option<LoopPoints> {
+    let sidecar = path.with_extension("json");
+    if sidecar.is_file() {
+        match std::fs::read_to_string(&sidecar).map(|json| serde_json::from_str(&json)) {
+            Ok(Ok(loop_points)) => return Some(loop_points),
+            Ok(Err(e)) => tracing::warn!(target: Log::Jukebox, error = ?e, "Invalid loop points in {}", sidecar.display()),
+            Err(e) => tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to read {}", sidecar.display()),
+        }
+    }
+
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "ogg" => {
+            let reader = OggStreamReader::new(BufReader::new(File::open(path).ok()?)).ok()?;
+            let tags = reader.comment_hdr.comment_list.iter();
+            loop_points_from_tags(tags.map(|(key, value)| (key.as_str(), value.as_str())))
+        },
+        "flac" => {
+            let reader = claxon::FlacReader::open(path).ok()?;
+            loop_points_from_tags(reader.tags())
+        },
+        _ => None,
+    }
+}
+
+/// Finds the `LOOPSTART` and `LOOPLENGTH` tags in a file's vorbis comments.
+/// Tag names aren't case sensitive.
+fn loop_points_from_tags<'a>(tags: impl Iterator<Item = (&'a str, &'a str)>) -> Option<LoopPoints> {
+    let (mut start, mut length) = (None, None);
+
+    for (key, value) in tags {
+        if key.eq_ignore_ascii_case("LOOPSTART") {
+            start = value.trim().parse().ok();
+        } else if key.eq_ignore_ascii_case("LOOPLENGTH") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    Some(LoopPoints { start: start?, length })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_overrides_by_song_name() {
+        let music_folder = Path::new("test-data/music");
+
+        let expected = Some(music_folder.join("izumi.wav"));
+        assert_eq!(find_override(music_folder, "audio/izumi.hps"), expected);
+        assert_eq!(find_override(music_folder, "izumi.hps"), expected);
+
+        assert_eq!(find_override(music_folder, "audio/menu01.hps"), None);
+        assert_eq!(find_override(Path::new("test-data/missing"), "audio/izumi.hps"), None);
+    }
+
+    #[test]
+    fn opens_overrides() {
+        let audio = open_override(Path::new("test-data/music/izumi.wav")).unwrap();
+        assert_eq!(audio.sample_rate(), 8000);
+        assert_eq!(audio.channels(), 2);
+
+        assert!(open_override(Path::new("../disc/test-data/tiny-disc.iso")).is_err());
+        assert!(open_override(Path::new("test-data/music/mutecity.ogg")).is_err());
+    }
+
+    #[test]
+    fn reads_loop_points_from_tags_and_sidecar_files() {
+        let flac = read_loop_points(Path::new("test-data/music/hyrule.flac"));
+        let expected = LoopPoints {
+            start: 200,
+            length: Some(600),
+        };
+        assert_eq!(flac, Some(expected));
+
+        let wav = read_loop_points(Path::new("test-data/music/izumi.wav"));
+        let expected = LoopPoints {
+            start: 100,
+            length: Some(500),
+        };
+        assert_eq!(wav, Some(expected));
+    }
+
+    #[test]
+    fn needs_a_loop_start_tag() {
+        let tags = [("LOOPLENGTH", "100"), ("TITLE", "Fountain of Dreams")];
+        assert_eq!(loop_points_from_tags(tags.into_iter()), None);
+
+        let tags = [("loopstart", " 42 ")];
+        let expected = LoopPoints { start: 42, length: None };
+        assert_eq!(loop_points_from_tags(tags.into_iter()), Some(expected));
+    }
+
+    #[test]
+    fn loops_overrides_seamlessly() {
+        let samples: Vec<i16> = Decoder::new(File::open("test-data/music/hyrule.flac").unwrap())
+            .unwrap()
+            .collect();
+        assert_eq!(samples.len(), 2000);
+
+        // Intro, then the loop (frames 200..800) over and over
+        let audio = open_override(Path::new("test-data/music/hyrule.flac")).unwrap();
+        let played: Vec<i16> = audio.take(1600 + 1200 * 2).collect();
+        assert_eq!(played[..1600], samples[..1600]);
+        assert_eq!(played[1600..2800], samples[400..1600]);
+        assert_eq!(played[2800..], samples[400..1600]);
+    }
+
+    #[test]
+    fn loops_the_whole_override_when_its_loop_points_dont_fit() {
+        let folder = tempfile::tempdir().unwrap();
+        let flac = folder.path().join("hyrule.flac");
+        std::fs::copy("test-data/music/hyrule.flac", &flac).unwrap();
+        std::fs::write(flac.with_extension("json"), r#"{ "loop_start": 5000 }"#).unwrap();
+
+        let samples: Vec<i16> = Decoder::new(File::open(&flac).unwrap()).unwrap().collect();
+        let audio = open_override(&flac).unwrap();
+        let played: Vec<i16> = audio.take(samples.len() * 3).collect();
+        assert!(played.chunks(samples.len()).all(|pass| pass == samples));
+    }
+
+    #[test]
+    fn skips_to_the_loop_start_while_the_song_plays() {
+        let mut audio = open_override(Path::new("test-data/music/hyrule.flac")).unwrap();
+        audio.by_ref().take(400).for_each(drop);
+
+        // By the time the song reaches its loop start, so has the next loop
+        assert!(matches!(audio.next_loop, Some((_, 400))));
+    }
+
+    #[test]
+    fn ignores_loop_points_that_are_too_large() {
+        let folder = tempfile::tempdir().unwrap();
+        let flac = folder.path().join("hyrule.flac");
+        std::fs::copy("test-data/music/hyrule.flac", &flac).unwrap();
+
+        let samples: Vec<i16> = Decoder::new(File::open(&flac).unwrap()).unwrap().collect();
+        for json in [
+            r#"{ "loop_start": 18446744073709551615 }"#,
+            r#"{ "loop_start": 200, "loop_length": 9223372036854775807 }"#,
+        ] {
+            std::fs::write(flac.with_extension("json"), json).unwrap();
+            let audio = open_override(&flac).unwrap();
+            assert_eq!((audio.loop_start, audio.loop_end), (0, None), "{json}");
+
+            let played: Vec<i16> = audio.take(samples.len() * 2).collect();
+            assert!(played.chunks(samples.len()).all(|pass| pass == samples), "{json}");
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use dolphin_integrations::Log;
+use rodio::Source;
+use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use rodio::cpal::{self, FromSample, SizedSample};
+use rodio::source::UniformSourceIterator;
+
+use crate::mixer::{MIXER_CHANNELS, MIXER_SAMPLE_RATE, MixerSource};
+use crate::{AudioBackend, JukeboxError::*, Result};
+
+/// Number of samples taken from the mixer at a time, so that it doesn't need to
+/// be locked for every sample
+const MIXER_CHUNK_SIZE: usize = 512;
+
+/// Most samples that a mirror output can fall behind the main output by (about
+/// 100ms of stereo audio). Older samples are dropped past this, so that a
+/// device that runs slightly slower doesn't drift further and further behind.
+const MIRROR_BUFFER_SIZE: usize = (MIXER_SAMPLE_RATE / 10 * 2) as usize;
+
+/// Returns the names of the audio output devices on this machine
+pub(crate) fn output_device_names() -> Vec<String> {
+    match cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+        Err(e) => {
+            tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to list audio output devices");
+            Vec::new()
+        },
+    }
+}
+
+/// Plays the audio of a mixer through one of the audio backends. The mixer is
+/// shared so that the output can be reopened (on another device, for example)
+/// and the music picks up exactly where it left off.
+pub(crate) enum AudioOutput {
+    Device(DeviceOutput),
+
+    // Only held on to so that it stops when the output is dropped
+    #[allow(dead_code)]
+    Headless(HeadlessOutput),
+}
+
+impl AudioOutput {
+    /// Starts playing `mixer` through `backend`. For the `Device` backend,
+    /// music plays on the device named `device_name`, or on the default device
+    /// if it's `None` or the device can't be found.
+    pub(crate) fn open(backend: &AudioBackend, device_name: Option<&str>, mixer: Arc<Mutex<MixerSource>>) -> Result<Self> {
+        match backend {
+            AudioBackend::Device => {
+                let mixer = SharedMixer {
+                    mixer,
+                    buffer: Vec::with_capacity(MIXER_CHUNK_SIZE),
+                    position: 0,
+                };
+                DeviceOutput::open(device_name, true, mixer).map(Self::Device)
+            },
+            AudioBackend::Null => HeadlessOutput::start(mixer, None).map(Self::Headless),
+            AudioBackend::Capture(path) => {
+                let spec = {
+                    let mixer = mixer.lock().unwrap();
+                    hound::WavSpec {
+                        channels: mixer.channels(),
+                        sample_rate: mixer.sample_rate(),
+                        bits_per_sample: 16,
+                        sample_format: hound::SampleFormat::Int,
+                    }
+                };
+                let writer = hound::WavWriter::create(path, spec)?;
+                tracing::info!(target: Log::Jukebox, "Capturing music to {}", path.display());
+                HeadlessOutput::start(mixer, Some(writer)).map(Self::Headless)
+            },
+        }
+    }
+
+    /// Starts playing the audio copied into `mirror` on the output device named
+    /// `device_name`. Unlike the main output this never falls back to the
+    /// default device, which would play the music twice. While the main output
+    /// is closed, the mirror takes its audio from `mixer` itself.
+    pub(crate) fn open_mirror(device_name: &str, mirror: Arc<MirrorBuffer>, mixer: Arc<Mutex<MixerSource>>) -> Result<Self> {
+        let source = MirrorSource {
+            mirror,
+            mixer,
+            chunk: Vec::with_capacity(MIXER_CHUNK_SIZE),
+            position: 0,
+        };
+        DeviceOutput::open(Some(device_name), false, source).map(Self::Device)
+    }
+
+    /// Returns `true` if the output should be reopened because its device
+    /// stopped working or changed. Headless outputs never need to be.
+    pub(crate) fn needs_reopen(&self) -> bool {
+        match self {
+            Self::Device(output) => output.needs_reopen(),
+            Self::Headless(_) => false,
+        }
+    }
+}
+
+/// Plays the audio of a mixer on an output device
+pub(crate) struct DeviceOutput {
+    _stream: cpal::Stream,
+    device_name: String,
+    /// Whether the output plays on whatever the system's default device is,
+    /// rather than on a device chosen by name
+    follows_default: bool,
+    failed: Arc<AtomicBool>,
+}
+
+impl DeviceOutput {
+    /// Starts playing `source` on the output device named `device_name`, or on
+    /// the default device if it's `None`. If the device can't be found, the
+    /// default device is used only when `fallback_to_default` is set.
+    fn open<S>(device_name: Option<&str>, fallback_to_default: bool, source: S) -> Result<Self>
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        let host = cpal::default_host();
+
+        let named_device = device_name.and_then(|name| {
+            let device = host
+                .output_devices()
+                .ok()?
+                .find(|device| device.name().is_ok_and(|device_name| device_name == name));
+            if device.is_none() && fallback_to_default {
+                tracing::warn!(target: Log::Jukebox, "Audio device {name} was not found. Using the default device.");
+            }
+            device
+        });
+
+        if device_name.is_some() && named_device.is_none() && !fallback_to_default {
+            return Err(rodio::StreamError::NoDevice.into());
+        }
+
+        let follows_default = named_device.is_none();
+        let device = match named_device {
+            Some(device) => device,
+            None => host.default_output_device().ok_or(rodio::StreamError::NoDevice)?,
+        };
+
+        let config = device.default_output_config().map_err(rodio::StreamError::from)?;
+        let failed = Arc::new(AtomicBool::new(false));
+
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => build_stream::<f32, _>(&device, &config.config(), source, failed.clone()),
+            cpal::SampleFormat::F64 => build_stream::<f64, _>(&device, &config.config(), source, failed.clone()),
+            cpal::SampleFormat::I8 => build_stream::<i8, _>(&device, &config.config(), source, failed.clone()),
+            cpal::SampleFormat::I16 => build_stream::<i16, _>(&device, &config.config(), source, failed.clone()),
+            cpal::SampleFormat::I32 => build_stream::<i32, _>(&device, &config.config(), source, failed.clone()),
+            cpal::SampleFormat::I64 => build_stream::<i64, _>(&device, &config.config(), source, failed.clone()),
+            cpal::SampleFormat::U8 => build_stream::<u8, _>(&device, &config.config(), source, failed.clone()),
+            cpal::SampleFormat::U16 => build_stream::<u16, _>(&device, &config.config(), source, failed.clone()),
+            cpal::SampleFormat::U32 => build_stream::<u32, _>(&device, &config.config(), source, failed.clone()),
+            cpal::SampleFormat::U64 => build_stream::<u64, _>(&device, &config.config(), source, failed.clone()),
+            _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+        }
+        .map_err(rodio::StreamError::from)?;
+
+        stream.play().map_err(rodio::StreamError::from)?;
+
+        let device_name = device.name().unwrap_or_default();
+        tracing::info!(target: Log::Jukebox, "Playing music on {device_name}");
+
+        Ok(Self {
+            _stream: stream,
+            device_name,
+            follows_default,
+            failed,
+        })
+    }
+
+    /// Returns `true` if the output has stopped working (e.g. its device was
+    /// unplugged), or if it's following the default device and the default
+    /// device has changed. Either way, it should be reopened.
+    fn needs_reopen(&self) -> bool {
+        if self.failed.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        self.follows_default
+            && cpal::default_host()
+                .default_output_device()
+                .and_then(|device| device.name().ok())
+                .is_some_and(|name| name != self.device_name)
+    }
+}
+
+/// Builds a stream on `device` that plays `source`, converting it to the
+/// device's sample format, channels and sample rate
+fn build_stream<T, S>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    source: S,
+    failed: Arc<AtomicBool>,
+) -> std::result::Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+    S: Source<Item = f32> + Send + 'static,
+{
+    let mut samples = UniformSourceIterator::<_, f32>::new(source, config.channels, config.sample_rate.0);
+
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            for sample in data.iter_mut() {
+                *sample = T::from_sample(samples.next().unwrap_or(0.0));
+            }
+        },
+        move |e| {
+            tracing::error!(target: Log::Jukebox, error = ?e, "Audio output stream failed");
+            failed.store(true, Ordering::Relaxed);
+        },
+        None,
+    )
+}
+
+/// Takes the audio of a mixer in real time without a device, optionally writing
+/// it to a WAV file
+pub(crate) struct HeadlessOutput {
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HeadlessOutput {
+    fn start(mixer: Arc<Mutex<MixerSource>>, mut writer: Option<hound::WavWriter<BufWriter<File>>>) -> Result<Self> {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+
+        let thread = std::thread::Builder::new()
+            .name("SlippiJukeboxOutput".to_string())
+            .spawn(move || {
+                let (channels, sample_rate) = {
+                    let mixer = mixer.lock().unwrap();
+                    (mixer.channels(), mixer.sample_rate())
+                };
+                let chunk_frames = (MIXER_CHUNK_SIZE / channels as usize) as f64;
+                let chunk_duration = Duration::from_secs_f64(chunk_frames / sample_rate as f64);
+
+                // Chunks are timed from when the output started so that small
+                // delays in waking up don't add up over time
+                let mut next_chunk = Instant::now();
+                while !thread_stopped.load(Ordering::Relaxed) {
+                    let mut samples = Vec::with_capacity(MIXER_CHUNK_SIZE);
+                    mixer.lock().unwrap().take_chunk(&mut samples, MIXER_CHUNK_SIZE);
+
+                    if let Some(wav) = &mut writer {
+                        let written = samples
+                            .iter()
+                            .try_for_each(|&sample| wav.write_sample((sample * i16::MAX as f32) as i16));
+                        if let Err(e) = written {
+                            tracing::error!(target: Log::Jukebox, error = ?e, "Failed to write captured audio. Stopping capture.");
+                            writer = None;
+                        }
+                    }
+
+                    next_chunk += chunk_duration;
+                    std::thread::sleep(next_chunk.saturating_duration_since(Instant::now()));
+                }
+
+                if let Some(Err(e)) = writer.map(hound::WavWriter::finalize) {
+                    tracing::error!(target: Log::Jukebox, error = ?e, "Failed to finish writing captured audio");
+                }
+            })
+            .map_err(ThreadSpawn)?;
+
+        Ok(Self {
+            stopped,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for HeadlessOutput {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Takes samples from a mixer that is shared with other outputs, a chunk at a
+/// time
+struct SharedMixer {
+    mixer: Arc<Mutex<MixerSource>>,
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl Iterator for SharedMixer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position >= self.buffer.len() {
+            self.buffer.clear();
+            self.mixer.lock().unwrap().take_chunk(&mut self.buffer, MIXER_CHUNK_SIZE);
+            self.position = 0;
+        }
+
+        let sample = self.buffer.get(self.position).copied();
+        self.position += 1;
+        sample
+    }
+}
+
+impl Source for SharedMixer {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.mixer.lock().unwrap().channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.mixer.lock().unwrap().sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Audio copied from the mixer on its way to a mirror output, along with the
+/// mirror's own volume. The mixer adds to it as the main output plays, and the
+/// mirror output takes from it.
+#[derive(Debug)]
+pub(crate) struct MirrorBuffer {
+    samples: Mutex<VecDeque<f32>>,
+    /// An `f32`, stored as its bits
+    volume: AtomicU32,
+    /// Whether the main output is taking audio from the mixer. When it isn't
+    /// (e.g. its device is missing), nothing is copied here, so the mirror
+    /// output has to take audio from the mixer itself.
+    main_output_open: AtomicBool,
+}
+
+impl MirrorBuffer {
+    pub(crate) fn new(volume: f32) -> Self {
+        Self {
+            samples: Mutex::new(VecDeque::with_capacity(MIRROR_BUFFER_SIZE)),
+            volume: AtomicU32::new(volume.to_bits()),
+            main_output_open: AtomicBool::new(true),
+        }
+    }
+
+    /// Tells the mirror whether the main output is open, and so whether the
+    /// mirror output needs to take audio from the mixer itself
+    pub(crate) fn set_main_output_open(&self, open: bool) {
+        self.main_output_open.store(open, Ordering::Relaxed);
+    }
+
+    /// Sets the volume of the mirror output, from 0.0 to 1.0. This is applied
+    /// on top of the mixer's volume.
+    pub(crate) fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+
+    /// Adds whole frames of mixed audio, dropping the oldest frames if the
+    /// mirror has fallen too far behind
+    pub(crate) fn push(&self, samples: &[f32]) {
+        let mut buffer = self.samples.lock().unwrap();
+        buffer.extend(samples);
+
+        let excess = buffer.len().saturating_sub(MIRROR_BUFFER_SIZE);
+        buffer.drain(..excess.next_multiple_of(MIXER_CHANNELS as usize));
+    }
+}
+
+/// Plays the audio copied into a `MirrorBuffer`. When the mirror has caught up
+/// with the main output it plays silence until more audio arrives.
+struct MirrorSource {
+    mirror: Arc<MirrorBuffer>,
+    /// Only taken from while the main output is closed
+    mixer: Arc<Mutex<MixerSource>>,
+    chunk: Vec<f32>,
+    position: usize,
+}
+
+impl Iterator for MirrorSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position >= self.chunk.len() {
+            // Taking audio from the mixer copies it into the mirror, the same
+            // as when the main output takes it
+            if !self.mirror.main_output_open.load(Ordering::Relaxed) {
+                self.mixer.lock().unwrap().take_chunk(&mut Vec::new(), MIXER_CHUNK_SIZE);
+            }
+
+            let volume = self.mirror.volume();
+            let mut buffer = self.mirror.samples.lock().unwrap();
+            let available = buffer.len().min(MIXER_CHUNK_SIZE);
+
+            self.chunk.clear();
+            self.chunk.extend(buffer.drain(..available).map(|sample| sample * volume));
+            if self.chunk.is_empty() {
+                self.chunk.resize(MIXER_CHANNELS as usize, 0.0);
+            }
+            self.position = 0;
+        }
+
+        let sample = self.chunk[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for MirrorSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        MIXER_CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        MIXER_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+    use crate::mixer::Mixer;
+
+    fn mirror_source(mirror: &Arc<MirrorBuffer>, mixer: MixerSource) -> MirrorSource {
+        MirrorSource {
+            mirror: mirror.clone(),
+            mixer: Arc::new(Mutex::new(mixer)),
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+
+    #[test]
+    fn mirrors_everything_taken_from_the_mixer() {
+        let (mixer, mut source) = Mixer::new();
+        let mirror = Arc::new(MirrorBuffer::new(0.5));
+        source.set_mirror(Some(mirror.clone()));
+
+        let song = SamplesBuffer::new(2, MIXER_SAMPLE_RATE, vec![16384i16; 1000]);
+        mixer.play(Box::new(song), Duration::ZERO);
+
+        let mut played = Vec::new();
+        source.take_chunk(&mut played, MIXER_CHUNK_SIZE);
+        assert!(played.iter().all(|&sample| (sample - 0.5).abs() < 0.01));
+
+        // The mirror plays the same audio at its own volume, and then silence
+        // once it has caught up
+        let mirrored: Vec<f32> = mirror_source(&mirror, source).take(MIXER_CHUNK_SIZE + 4).collect();
+        assert_eq!(
+            &mirrored[..MIXER_CHUNK_SIZE],
+            played.iter().map(|sample| sample * 0.5).collect::<Vec<_>>()
+        );
+        assert_eq!(&mirrored[MIXER_CHUNK_SIZE..], [0.0; 4]);
+    }
+
+    #[test]
+    fn drops_audio_when_the_mirror_falls_behind() {
+        let mirror = Arc::new(MirrorBuffer::new(1.0));
+        let samples: Vec<f32> = (0..MIRROR_BUFFER_SIZE + 6).map(|sample| sample as f32).collect();
+        mirror.push(&samples);
+
+        // The oldest frames are dropped, without mixing up the channels
+        let mut source = mirror_source(&mirror, Mixer::new().1);
+        assert_eq!(source.next(), Some(6.0));
+        assert_eq!(source.next(), Some(7.0));
+        assert_eq!(mirror.samples.lock().unwrap().len(), MIRROR_BUFFER_SIZE - MIXER_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn mirror_plays_on_its_own_while_the_main_output_is_closed() {
+        let (mixer, mut source) = Mixer::new();
+        let mirror = Arc::new(MirrorBuffer::new(1.0));
+        source.set_mirror(Some(mirror.clone()));
+
+        let song = SamplesBuffer::new(2, MIXER_SAMPLE_RATE, vec![16384i16; MIXER_CHUNK_SIZE * 4]);
+        mixer.play(Box::new(song), Duration::ZERO);
+
+        // Nothing takes from the mixer but the mirror
+        mirror.set_main_output_open(false);
+        let mirrored: Vec<f32> = mirror_source(&mirror, source).take(MIXER_CHUNK_SIZE * 2).collect();
+        assert!(mirrored.iter().all(|&sample| (sample - 0.5).abs() < 0.01));
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+
+use dolphin_integrations::Log;
+use hps_decode::Hps;
+use hps_decode::decoded_hps::DecodedHps;
+use slippi_disc::{DiscReader, FileSystemTable, copy_bytes};
+
+use crate::looping_source::{LoopPoints, LoopingSource};
+use crate::{JukeboxError::*, Result};
+
+/// Number of samples (per channel) in a DSP-ADPCM frame
+const SAMPLES_PER_FRAME: usize = 14;
+
+/// A song that has been fully decoded to PCM and can be played back any number
+/// of times without decoding it again.
+#[derive(Debug)]
+pub(crate) struct DecodedSong {
+    samples: Arc<[i16]>,
+    channels: u16,
+    sample_rate: u32,
+    /// Sample frame that the song loops back to once it ends, if it loops
+    loop_start: Option<u64>,
+}
+
+impl DecodedSong {
+    /// Keeps the decoded `audio` of an `hps` file
+    pub(crate) fn new(hps: &Hps, audio: DecodedHps) -> Self {
+        Self {
+            samples: audio.samples().into(),
+            channels: audio.channel_count as u16,
+            sample_rate: audio.sample_rate,
+            loop_start: hps_loop_start(hps),
+        }
+    }
+
+    /// Keeps interleaved `samples` that were decoded elsewhere
+    pub(crate) fn from_samples(samples: Arc<[i16]>, channels: u16, sample_rate: u32, loop_start: Option<u64>) -> Self {
+        Self {
+            samples,
+            channels,
+            sample_rate,
+            loop_start,
+        }
+    }
+
+    /// Interleaved samples of the whole song, without looping
+    pub(crate) fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub(crate) fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Sample frame that the song loops back to once it ends, if it loops
+    pub(crate) fn loop_start(&self) -> Option<u64> {
+        self.loop_start
+    }
+
+    /// Amount of memory used by the decoded samples, in bytes
+    pub(crate) fn size(&self) -> usize {
+        std::mem::size_of_val(&*self.samples)
+    }
+
+    /// Creates a source that plays the song from the start, looping the same
+    /// way the hps does
+    pub(crate) fn source(&self) -> LoopingSource {
+        let samples = self.samples.clone();
+        match self.loop_start {
+            Some(start) => {
+                let loop_points = LoopPoints { start, length: None };
+                LoopingSource::new(samples, self.channels, self.sample_rate, Some(loop_points))
+            },
+            None => LoopingSource::once(samples, self.channels, self.sample_rate),
+        }
+    }
+}
+
+/// Number of sample frames in the blocks of `hps` before `block_index`
+fn frames_before(hps: &Hps, block_index: usize) -> u64 {
+    // Hps files are always stereo, and the frames of each block are split
+    // evenly between the two channels
+    let frames: usize = hps.blocks[..block_index].iter().map(|block| block.frames.len()).sum();
+    (frames * SAMPLES_PER_FRAME / 2) as u64
+}
+
+/// Sample frame that `hps` loops back to once it ends, if it loops
+pub(crate) fn hps_loop_start(hps: &Hps) -> Option<u64> {
+    hps.loop_block_index.map(|index| frames_before(hps, index))
+}
+
+/// Number of sample frames in one pass through `hps`
+pub(crate) fn hps_frame_count(hps: &Hps) -> u64 {
+    frames_before(hps, hps.blocks.len())
+}
+
+/// Reads the hps file at `hps_offset` on the disc and decodes all of its audio
+pub(crate) fn decode_hps(disc: &mut DiscReader, hps_offset: u64, hps_length: usize) -> Result<DecodedSong> {
+    let hps_bytes = copy_bytes(disc, hps_offset, hps_length)?;
+    let hps: Hps = hps_bytes.try_into().map_err(|e| InvalidHps(format!("{e:?}")))?;
+    let audio = hps.decode().map_err(|e| HpsDecode(format!("{e:?}")))?;
+
+    Ok(DecodedSong::new(&hps, audio))
+}
+
+/// Decodes `songs` (by file name) from the disc at `iso_path` into the cache,
+/// one after the other. Songs that are already cached or aren't on the disc are
+/// skipped. This stops early if the cache is dropped, which happens when
+/// jukebox stops.
+pub(crate) fn predecode_songs(iso_path: &str, songs: &[String], cache: Weak<Mutex<SongCache>>) -> Result<()> {
+    let mut disc = DiscReader::open(iso_path)?;
+    let fst = FileSystemTable::read(&mut disc)?;
+
+    for name in songs {
+        let Some(file) = fst.get(name) else {
+            tracing::debug!(target: Log::Jukebox, "{name} is not on the disc. Skipping pre-decode.");
+            continue;
+        };
+
+        match cache.upgrade() {
+            Some(cache) if cache.lock().unwrap().contains(file.offset) => continue,
+            Some(_) => {},
+            None => return Ok(()),
+        }
+
+        match decode_hps(&mut disc, file.offset, file.length) {
+            Ok(song) => {
+                let Some(cache) = cache.upgrade() else {
+                    return Ok(());
+                };
+                cache.lock().unwrap().insert(file.offset, Arc::new(song));
+                tracing::info!(target: Log::Jukebox, "Pre-decoded {name}");
+            },
+            Err(e) => tracing::warn!(target: Log::Jukebox, error = ?e, "Failed to pre-decode {name}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Decoded songs keyed by the offset of their hps file on the disc. When adding
+/// a song would use more memory than the budget allows, the least recently
+/// played songs are dropped until it fits.
+#[derive(Debug)]
+pub(crate) struct SongCache {
+    budget: usize,
+    used: usize,
+    /// Least recently used songs are at the front
+    songs: VecDeque<(u64, Arc<DecodedSong>)>,
+}
+
+impl SongCache {
+    /// Creates an empty cache that holds at most `budget` bytes of samples
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            songs: VecDeque::new(),
+        }
+    }
+
+    /// Maximum amount of memory that the cached songs may use, in bytes
+    pub(crate) fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Gets the song decoded from the hps at `offset` and marks it as the most
+    /// recently used one
+    pub(crate) fn get(&mut self, offset: u64) -> Option<Arc<DecodedSong>> {
+        let index = self.songs.iter().position(|(song_offset, _)| *song_offset == offset)?;
+        let entry = self.songs.remove(index)?;
+        let song = entry.1.clone();
+        self.songs.push_back(entry);
+        Some(song)
+    }
+
+    /// Returns `true` if the song decoded from the hps at `offset` is cached.
+    /// Unlike `get`, this doesn't count as using the song.
+    pub(crate) fn contains(&self, offset: u64) -> bool {
+        self.songs.iter().any(|(song_offset, _)| *song_offset == offset)
+    }
+
+    /// Adds the song decoded from the hps at `offset`, evicting other songs as
+    /// needed. Songs that are larger than the whole budget are not cached.
+    pub(crate) fn insert(&mut self, offset: u64, song: Arc<DecodedSong>) {
+        if let Some(index) = self.songs.iter().position(|(song_offset, _)| *song_offset == offset) {
+            let (_, old_song) = self.songs.remove(index).unwrap();
+            self.used -= old_song.size();
+        }
+
+        if song.size() > self.budget {
+            return;
+        }
+
+        while self.used + song.size() > self.budget {
+            let Some((_, evicted)) = self.songs.pop_front() else {
+                break;
+            };
+            self.used -= evicted.size();
+        }
+
+        self.used += song.size();
+        self.songs.push_back((offset, song));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::Source;
+
+    use super::*;
+
+    fn song(sample_count: usize) -> Arc<DecodedSong> {
+        Arc::new(DecodedSong {
+            samples: vec![0; sample_count].into(),
+            channels: 2,
+            sample_rate: 32000,
+            loop_start: None,
+        })
+    }
+
+    fn read_hps(offset: u64, length: usize) -> Hps {
+        let mut disc = DiscReader::open("../disc/test-data/tiny-disc.iso").unwrap();
+        copy_bytes(&mut disc, offset, length).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn plays_the_same_audio_as_the_hps() {
+        // `menu01.hps` loops back to its second block, `izumi.hps` doesn't loop
+        for (offset, length, looping) in [(0x13F00, 0x500, true), (0x15000, 0x320, false)] {
+            let hps = read_hps(offset, length);
+            let song = DecodedSong::new(&hps, hps.decode().unwrap());
+            let audio = hps.decode().unwrap();
+
+            assert_eq!(song.source().channels(), audio.channels());
+            assert_eq!(song.source().sample_rate(), audio.sample_rate());
+            match looping {
+                true => assert!(song.source().take(1000).eq(audio.take(1000))),
+                false => assert!(song.source().eq(audio)),
+            }
+        }
+    }
+
+    #[test]
+    fn fails_to_decode_invalid_hps_files() {
+        let mut disc = DiscReader::open("../disc/test-data/tiny-disc.iso").unwrap();
+        assert!(matches!(decode_hps(&mut disc, 0x16000, 0x100), Err(InvalidHps(_))));
+    }
+
+    #[test]
+    fn predecodes_songs_into_the_cache() {
+        let cache = Arc::new(Mutex::new(SongCache::new(1024 * 1024)));
+        let songs = ["menu01.hps", "mutecity.hps", "broken.hps", "izumi.hps"].map(String::from);
+        predecode_songs("../disc/test-data/tiny-disc.rvz", &songs, Arc::downgrade(&cache)).unwrap();
+
+        let cache = cache.lock().unwrap();
+        assert_eq!(cache.songs.len(), 2);
+        assert!(cache.contains(0x13F00) && cache.contains(0x15000));
+    }
+
+    #[test]
+    fn stops_predecoding_once_the_cache_is_dropped() {
+        let cache = Arc::new(Mutex::new(SongCache::new(1024 * 1024)));
+        let weak_cache = Arc::downgrade(&cache);
+        drop(cache);
+
+        let songs = ["menu01.hps".to_string()];
+        assert!(predecode_songs("../disc/test-data/tiny-disc.iso", &songs, weak_cache).is_ok());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_songs() {
+        let mut cache = SongCache::new(300);
+        cache.insert(1, song(50));
+        cache.insert(2, song(50));
+        cache.insert(3, song(50));
+        assert_eq!(cache.used, 300);
+
+        // Using a song keeps it around longer than the others
+        assert!(cache.get(1).is_some());
+        cache.insert(4, song(50));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(1) && cache.contains(3) && cache.contains(4));
+
+        // Makes room for bigger songs by evicting as many songs as needed
+        cache.insert(5, song(100));
+        assert!(!cache.contains(3) && !cache.contains(1));
+        assert!(cache.contains(4) && cache.contains(5));
+        assert_eq!(cache.used, 300);
+    }
+
+    #[test]
+    fn doesnt_cache_songs_larger_than_the_budget() {
+        let mut cache = SongCache::new(300);
+        cache.insert(1, song(100));
+        cache.insert(2, song(151));
+
+        assert!(cache.contains(1));
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.used, 200);
+    }
+
+    #[test]
+    fn replaces_songs_at_the_same_offset() {
+        let mut cache = SongCache::new(300);
+        cache.insert(1, song(100));
+        cache.insert(1, song(50));
+
+        assert_eq!(cache.songs.len(), 1);
+        assert_eq!(cache.used, 100);
+    }
+}
@@ -0,0 +1,44 @@
+/// What jukebox is doing with the current song
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlaybackState {
+    /// Nothing is playing
+    #[default]
+    Idle,
+
+    /// A song was requested and is being loaded
+    Loading,
+
+    Playing,
+
+    /// The requested song couldn't be played (see `JukeboxStatus::error`)
+    Error,
+}
+
+/// A snapshot of what jukebox is playing, returned by `Jukebox::status`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JukeboxStatus {
+    pub state: PlaybackState,
+
+    /// Offset of the current song's hps file on the disc
+    pub hps_offset: Option<u64>,
+
+    /// Path of the current song on the disc (e.g. `audio/menu01.hps`), if the
+    /// disc has a file at its offset
+    pub file_name: Option<String>,
+
+    /// Sample frames of the current song that have played so far, at
+    /// `sample_rate`. This keeps counting when a song loops.
+    pub elapsed_samples: u64,
+    pub sample_rate: u32,
+
+    /// Volume that the current song plays at once all of the volume controls
+    /// and its loudness normalization are applied. This is above 1.0 when a
+    /// quiet song is boosted.
+    pub volume: f32,
+
+    /// Whether music is paused because the emulator is
+    pub paused: bool,
+
+    /// Why the current song couldn't be played, if it couldn't
+    pub error: Option<String>,
+}